@@ -0,0 +1,301 @@
+use std::{fs, thread};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tempfile::TempDir;
+
+use rskv::{BatchOp, Bitcask, BitcaskOptions, Encoding, KvsEngine};
+
+const KEYS: usize = 1000;
+
+/// Fill a store with `KEYS` values and reopen it, so all of them are in sealed log files.
+fn sealed_store(temp_dir: &TempDir, options: BitcaskOptions) -> Bitcask {
+    let store = Bitcask::open(temp_dir.path()).unwrap();
+    for key_id in 0..KEYS {
+        store
+            .set(format!("key{}", key_id), "x".repeat(100))
+            .unwrap();
+    }
+    drop(store);
+    Bitcask::open_with_options(temp_dir.path(), options).unwrap()
+}
+
+fn random_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_read");
+    let configs = [
+        ("buffered", BitcaskOptions::default()),
+        #[cfg(feature = "mmap")]
+        (
+            "mmap",
+            BitcaskOptions {
+                mmap_reads: true,
+                ..BitcaskOptions::default()
+            },
+        ),
+    ];
+
+    for (name, options) in configs {
+        let temp_dir = TempDir::new().unwrap();
+        let store = sealed_store(&temp_dir, options);
+        let mut rng = StdRng::seed_from_u64(0);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || format!("key{}", rng.gen_range(0..KEYS)),
+                |key| store.get(key).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Draws key ids from a Zipfian distribution with exponent 1: the `i`-th most popular
+/// key is read about `1 / i` times as often as the most popular one.
+struct Zipf {
+    /// Cumulative weights of the key ids.
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize) -> Self {
+        let cdf = (1..=n)
+            .scan(0.0, |sum, rank| {
+                *sum += 1.0 / rank as f64;
+                Some(*sum)
+            })
+            .collect();
+        Zipf { cdf }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        let x = rng.gen::<f64>() * self.cdf[self.cdf.len() - 1];
+        self.cdf.partition_point(|&sum| sum < x)
+    }
+}
+
+/// Skewed reads, with and without a value cache of about a quarter of the data.
+fn zipfian_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zipfian_read");
+    let configs = [
+        ("uncached", BitcaskOptions::default()),
+        (
+            "cached",
+            BitcaskOptions {
+                value_cache_bytes: Some(KEYS * 100 / 4),
+                ..BitcaskOptions::default()
+            },
+        ),
+    ];
+
+    let zipf = Zipf::new(KEYS);
+    for (name, options) in configs {
+        let temp_dir = TempDir::new().unwrap();
+        let store = sealed_store(&temp_dir, options);
+        let mut rng = StdRng::seed_from_u64(0);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || format!("key{}", zipf.sample(&mut rng)),
+                |key| store.get(key).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+        let stats = store.stats();
+        let reads = stats.cache_hits + stats.cache_misses;
+        if reads > 0 {
+            println!(
+                "{}: {:.1}% of {} reads hit the cache",
+                name,
+                100.0 * stats.cache_hits as f64 / reads as f64,
+                reads
+            );
+        }
+    }
+    group.finish();
+}
+
+fn large_key_reads(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = Bitcask::open(temp_dir.path()).unwrap();
+    let key = |key_id: usize| format!("{:01024}", key_id);
+    for key_id in 0..KEYS {
+        store.set(key(key_id), "x".repeat(100)).unwrap();
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    c.bench_function("large_key_get", |b| {
+        b.iter_batched(
+            || key(rng.gen_range(0..KEYS)),
+            |key| store.get(key).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn multi_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("multi_get");
+    let temp_dir = TempDir::new().unwrap();
+    let store = sealed_store(&temp_dir, BitcaskOptions::default());
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut keys = || -> Vec<String> {
+        (0..50)
+            .map(|_| format!("key{}", rng.gen_range(0..KEYS)))
+            .collect()
+    };
+
+    group.bench_function("get_loop", |b| {
+        b.iter_batched(
+            &mut keys,
+            |keys| {
+                keys.into_iter()
+                    .map(|key| store.get(key).unwrap())
+                    .collect::<Vec<_>>()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("get_many", |b| {
+        b.iter_batched(
+            &mut keys,
+            |keys| store.get_many(keys).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+/// `get` allocates a `String` per read, `get_into` reuses one buffer.
+fn read_into(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_into");
+    let temp_dir = TempDir::new().unwrap();
+    let store = sealed_store(&temp_dir, BitcaskOptions::default());
+    let keys: Vec<String> = (0..KEYS).map(|key_id| format!("key{}", key_id)).collect();
+
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            for key in &keys {
+                store.get(key.clone()).unwrap();
+            }
+        })
+    });
+    group.bench_function("get_into", |b| {
+        let mut buf = String::new();
+        b.iter(|| {
+            for key in &keys {
+                store.get_into(key, &mut buf).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bulk_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load");
+    let ops = || {
+        (0..KEYS)
+            .map(|key_id| BatchOp::Set {
+                key: format!("key{}", key_id),
+                value: "x".repeat(100),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    group.bench_function("set", |b| {
+        b.iter_batched(
+            || (TempDir::new().unwrap(), ops()),
+            |(temp_dir, ops)| {
+                let store = Bitcask::open(temp_dir.path()).unwrap();
+                for op in ops {
+                    if let BatchOp::Set { key, value } = op {
+                        store.set(key, value).unwrap();
+                    }
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("write_batch", |b| {
+        b.iter_batched(
+            || (TempDir::new().unwrap(), ops()),
+            |(temp_dir, ops)| {
+                let store = Bitcask::open(temp_dir.path()).unwrap();
+                store.write_batch(ops).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// Sets of distinct keys spread over threads, which all append to the one active log
+fn concurrent_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_writes");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for threads in [1, 2, 4, 8] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = Bitcask::open(temp_dir.path()).unwrap();
+        let value = "x".repeat(1000);
+        group.bench_function(format!("{}_threads", threads), |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for thread_id in 0..threads {
+                        let (store, value) = (store.clone(), &value);
+                        s.spawn(move || {
+                            for key_id in (thread_id..KEYS).step_by(threads) {
+                                store.set(format!("key{}", key_id), value.clone()).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    for (name, encoding) in [("json", Encoding::Json), ("bincode", Encoding::Bincode)] {
+        let options = BitcaskOptions {
+            encoding,
+            ..BitcaskOptions::default()
+        };
+        let data_dir = TempDir::new().unwrap();
+        let store = Bitcask::open_with_options(data_dir.path(), options.clone()).unwrap();
+        let ops = (0..10 * KEYS).map(|key_id| BatchOp::Set {
+            key: format!("key{}", key_id),
+            value: "x".repeat(100),
+        });
+        store.write_batch(ops.collect()).unwrap();
+        drop(store);
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                // a copy of the logs, as every open adds a log file
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    for entry in fs::read_dir(data_dir.path()).unwrap() {
+                        let path = entry.unwrap().path();
+                        fs::copy(&path, temp_dir.path().join(path.file_name().unwrap())).unwrap();
+                    }
+                    temp_dir
+                },
+                |temp_dir| Bitcask::open_with_options(temp_dir.path(), options.clone()).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    random_reads,
+    zipfian_reads,
+    large_key_reads,
+    multi_get,
+    read_into,
+    bulk_load,
+    concurrent_writes,
+    open
+);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+use std::{net::SocketAddr, thread};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tempfile::TempDir;
+
+use rskv::{
+    thread_pool::{DropJoinThreadPool, ThreadPool},
+    Bitcask, KvsClient, KvsServer, ServerHandle,
+};
+
+/// Connections opened by each client thread of the concurrent benchmark.
+const CONNECTIONS_PER_CLIENT: usize = 25;
+
+/// Client threads of the concurrent benchmark.
+const CLIENTS: usize = 8;
+
+/// A server accepting connections on `acceptors` threads.
+fn spawn_server(temp_dir: &TempDir, acceptors: usize) -> ServerHandle {
+    let engine = Bitcask::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, DropJoinThreadPool::new(4).unwrap()).acceptors(acceptors);
+    server.spawn("127.0.0.1:0").unwrap()
+}
+
+/// Open a connection, send a ping and close it, as a short-lived client does.
+fn ping(addr: SocketAddr) {
+    KvsClient::connect(addr).unwrap().ping().unwrap();
+}
+
+// Short-lived connections, opened one after another or by many clients at once
+fn accept(c: &mut Criterion) {
+    let mut group = c.benchmark_group("accept");
+    for acceptors in [1, 4] {
+        let temp_dir = TempDir::new().unwrap();
+        let handle = spawn_server(&temp_dir, acceptors);
+        let addr = handle.local_addr();
+        group.bench_function(format!("sequential/{}", acceptors), |b| {
+            b.iter(|| ping(addr))
+        });
+        group.bench_function(format!("concurrent/{}", acceptors), |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for _ in 0..CLIENTS {
+                        s.spawn(|| (0..CONNECTIONS_PER_CLIENT).for_each(|_| ping(addr)));
+                    }
+                })
+            })
+        });
+        handle.shutdown().unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, accept);
+criterion_main!(benches);
@@ -44,11 +44,7 @@ mod ex3 {
     use super::*;
 
     fn new_json_writer(path: impl AsRef<Path>) -> Result<BufWriter<File>> {
-        let f = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?;
+        let f = OpenOptions::new().create(true).append(true).open(&path)?;
 
         let writer = BufWriter::new(f);
         Ok(writer)
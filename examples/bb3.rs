@@ -56,7 +56,7 @@ mod trace {
             // note that this is intended to demonstrate `tracing`'s features, not idiomatic
             // error handling! in a library or application, you should consider returning
             // a dedicated `YakError`. libraries like snafu or thiserror make this easy.
-            return Err(io::Error::new(io::ErrorKind::Other, "shaving yak failed!").into());
+            return Err(io::Error::other("shaving yak failed!").into());
         } else {
             debug!("yak shaved successfully");
         }
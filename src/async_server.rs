@@ -0,0 +1,211 @@
+//! A server for [AsyncKvsEngine]s on the Tokio runtime, see [AsyncKvsServer].
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Deserializer;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    metrics::Metrics,
+    resp::{
+        AuthResponse, GetResponse, Hello, HelloResponse, InfoResponse, PingResponse,
+        RemoveResponse, Request, ScanResponse, SetResponse, TransactionResponse, PROTOCOL_VERSION,
+    },
+    AsyncKvsEngine, Command, KvsError, Result, ServerInfo,
+};
+
+/// The server of a key value store, serving each connection on a Tokio task.
+///
+/// It speaks the JSON protocol of [KvsServer](crate::KvsServer), so it is used with
+/// the same [KvsClient](crate::KvsClient). Transactions and scans are not supported.
+pub struct AsyncKvsServer<E: AsyncKvsEngine> {
+    engine: E,
+    metrics: Arc<Metrics>,
+    /// Number of connections being served.
+    active: Arc<AtomicUsize>,
+}
+
+impl<E: AsyncKvsEngine> AsyncKvsServer<E> {
+    /// Create an `AsyncKvsServer` with a given storage engine.
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            engine,
+            metrics: Arc::new(Metrics::default()),
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Serve connections on a certain ip address, forever.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr).await?).await
+    }
+
+    /// Serve the connections accepted by `listener`, forever.
+    ///
+    /// This allows binding to port `0` and reading the picked port before serving.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let engine = self.engine.clone();
+            let metrics = Arc::clone(&self.metrics);
+            let active = Arc::clone(&self.active);
+            tokio::spawn(async move {
+                active.fetch_add(1, Ordering::SeqCst);
+                debug!("Accepted connection {}", addr);
+                let res = handle_stream(engine, stream, addr, &metrics, &active).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                match res {
+                    Ok(()) => debug!("Connection {} closed", addr),
+                    Err(e) => error!("Error on serving client {}: {}", addr, e),
+                }
+            });
+        }
+    }
+}
+
+async fn handle_stream<E: AsyncKvsEngine>(
+    engine: E,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    metrics: &Metrics,
+    active: &AtomicUsize,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    // bytes received but not parsed yet
+    let mut buf = Vec::new();
+
+    let hello: Hello = match read_message(&mut stream, &mut buf).await? {
+        Some(hello) => hello,
+        None => return Ok(()),
+    };
+    if hello.version != PROTOCOL_VERSION {
+        warn!(
+            "Connection {} speaks protocol version {} (rskv {}), expected {} (rskv {})",
+            peer,
+            hello.version,
+            hello.crate_version,
+            PROTOCOL_VERSION,
+            env!("CARGO_PKG_VERSION")
+        );
+        let resp = HelloResponse::VersionMismatch(Hello::current());
+        return write_message(&mut stream, &resp).await;
+    }
+    let encoding = hello.value_encoding;
+    let hello = Hello {
+        value_encoding: encoding,
+        ..Hello::current()
+    };
+    write_message(&mut stream, &HelloResponse::Ok(hello)).await?;
+
+    while let Some(req) = read_message::<Request>(&mut stream, &mut buf).await? {
+        debug!("Receive request from {}: {:?}", peer, req);
+        match req {
+            Request::Get { key } => {
+                let start = Instant::now();
+                let res = engine.get(key).await;
+                metrics.record(Command::Get, start.elapsed(), res.as_ref().err());
+                let resp = match res {
+                    Ok(val) => GetResponse::Ok(val),
+                    Err(e) => GetResponse::Err((&e).into()),
+                };
+                write_message(&mut stream, &resp).await?;
+            }
+            Request::Set { key, value } => {
+                let start = Instant::now();
+                let res = match encoding.check(&value) {
+                    Ok(()) => engine.set(key, value).await,
+                    Err(e) => Err(e),
+                };
+                metrics.record(Command::Set, start.elapsed(), res.as_ref().err());
+                let resp = match res {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err((&e).into()),
+                };
+                write_message(&mut stream, &resp).await?;
+            }
+            Request::Rm { key } => {
+                let start = Instant::now();
+                let res = engine.rm(key).await;
+                metrics.record(Command::Rm, start.elapsed(), res.as_ref().err());
+                let resp = match res {
+                    Ok(()) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err((&e).into()),
+                };
+                write_message(&mut stream, &resp).await?;
+            }
+            Request::Info => {
+                let resp = InfoResponse::Ok(ServerInfo {
+                    active_connections: active.load(Ordering::SeqCst),
+                    ..metrics.errors.info()
+                });
+                write_message(&mut stream, &resp).await?;
+            }
+            Request::Ping => write_message(&mut stream, &PingResponse::Pong).await?,
+            // no token is required
+            Request::Auth { .. } => write_message(&mut stream, &AuthResponse::Ok(())).await?,
+            Request::Transaction { .. } => {
+                let e = KvsError::StringError(
+                    "Transactions are not supported by this server".to_owned(),
+                );
+                metrics.record(Command::Transaction, Duration::ZERO, Some(&e));
+                write_message(&mut stream, &TransactionResponse::Err((&e).into())).await?;
+            }
+            Request::Scan { .. } => {
+                let e = KvsError::StringError("Scans are not supported by this server".to_owned());
+                metrics.record(Command::Scan, Duration::ZERO, Some(&e));
+                write_message(&mut stream, &ScanResponse::Err((&e).into())).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the next JSON message from `stream`, or `None` if the client disconnected
+/// before sending one.
+///
+/// `buf` holds the bytes received after the previous message.
+async fn read_message<T: DeserializeOwned>(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+) -> Result<Option<T>> {
+    loop {
+        let mut messages = Deserializer::from_slice(buf).into_iter::<T>();
+        match messages.next() {
+            Some(Ok(msg)) => {
+                let len = messages.byte_offset();
+                buf.drain(..len);
+                return Ok(Some(msg));
+            }
+            // the message is still arriving
+            Some(Err(e)) if e.is_eof() => {}
+            Some(Err(e)) => return Err(e.into()),
+            // only whitespace so far
+            None => {}
+        }
+        if stream.read_buf(buf).await? == 0 {
+            if buf.iter().all(u8::is_ascii_whitespace) {
+                return Ok(None);
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+/// Send `msg` to `stream` as JSON.
+async fn write_message(stream: &mut TcpStream, msg: &(impl Serialize + ?Sized)) -> Result<()> {
+    stream.write_all(&serde_json::to_vec(msg)?).await?;
+    Ok(())
+}
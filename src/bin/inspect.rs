@@ -0,0 +1,75 @@
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use clap::Parser;
+use log::{error, info, LevelFilter};
+
+use rskv::{get_kvstore_data_dir, inspect_log, verify, KvsError, Result};
+
+/// Args for kvs-inspect
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct InspectArgs {
+    /// Data directory of the kvs engine, default is data/kvs
+    #[clap(value_parser)]
+    path: Option<PathBuf>,
+    /// Check the records and hint files instead of listing the records, and fail if
+    /// anything is corrupt or inconsistent
+    #[clap(long)]
+    verify: bool,
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    if let Err(e) = run() {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let cli = InspectArgs::parse();
+    let path = cli.path.unwrap_or_else(get_kvstore_data_dir);
+    if cli.verify {
+        return run_verify(&path);
+    }
+
+    // one record per line: fid, position, length and the command
+    for record in inspect_log(&path)? {
+        println!(
+            "{}\t{}\t{}\t{:?}",
+            record.fid, record.pos, record.len, record.cmd
+        );
+    }
+    Ok(())
+}
+
+fn run_verify(path: &Path) -> Result<()> {
+    let report = verify(path)?;
+    // one problem per line: corrupt records by fid, position and length, then the rest
+    for record in &report.corrupt_records {
+        println!("corrupt\t{}\t{}\t{}", record.fid, record.pos, record.len);
+    }
+    for inconsistency in &report.inconsistencies {
+        println!("inconsistent\t{:?}", inconsistency);
+    }
+    info!(
+        "Checked {} records in {} log files: {} corrupt records of {} bytes, {} inconsistencies",
+        report.valid_records + report.corrupt_records.len() as u64,
+        report.log_files,
+        report.corrupt_records.len(),
+        report.corrupt_bytes,
+        report.inconsistencies.len()
+    );
+    if report.is_clean() {
+        Ok(())
+    } else {
+        Err(KvsError::StringError(format!(
+            "{:?} failed verification",
+            path
+        )))
+    }
+}
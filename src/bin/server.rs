@@ -1,12 +1,18 @@
-use std::{env::current_dir, fs, net::SocketAddr, process::exit};
+use std::{
+    env::current_dir,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
-use clap::{arg_enum, Parser};
-use log::{error, info, warn, LevelFilter};
+use clap::Parser;
+use log::{error, info, LevelFilter};
 
 use rskv::{
-    get_kvstore_data_dir, get_sled_data_dir,
+    kvstore_data_dir, open_engine, reconcile_engine, sled_data_dir,
     thread_pool::{RayonThreadPool, ThreadPool},
-    Bitcask, KvsEngine, KvsServer, Result, SledKvsEngine,
+    EngineKind, KvsEngine, KvsError, KvsServer, LogNaming, Result,
 };
 
 /// Args for kvs-server
@@ -17,21 +23,18 @@ struct ServerArgs {
     /// Server listening address, default is 127.0.0.1:4000
     #[clap(long, value_parser)]
     addr: Option<SocketAddr>,
-    /// Engine type, default is kvs
-    #[clap(long, arg_enum, value_parser)]
-    engine: Option<Engine>,
-}
-
-arg_enum! {
-  /// Engine enum type
-  #[derive(Debug, Clone, PartialEq)]
-  enum Engine {
-      Kvs,
-      Sled,
-  }
+    /// Engine type, kvs or sled, default is kvs
+    #[clap(long, value_parser)]
+    engine: Option<EngineKind>,
+    /// Speak the Redis protocol, for redis-cli, instead of the JSON one of kvs-client
+    #[clap(long)]
+    resp: bool,
+    /// Directory of the engine file and of the data, default is the current directory
+    #[clap(long, value_parser)]
+    data_dir: Option<PathBuf>,
 }
 
-const DEFAULT_ENGINE: Engine = Engine::Kvs;
+const DEFAULT_ENGINE: EngineKind = EngineKind::Kvs;
 const DEFAULT_ADDR: &str = "127.0.0.1:4000";
 
 fn main() {
@@ -41,20 +44,34 @@ fn main() {
 
     let engine = cli.engine.unwrap_or(DEFAULT_ENGINE);
     let addr = cli.addr.unwrap_or(DEFAULT_ADDR.parse().unwrap());
+    let data_dir = match cli.data_dir.map_or_else(current_dir, Ok) {
+        Ok(data_dir) => data_dir,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    };
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {:?}", engine);
+    info!("Data directory: {:?}", data_dir);
     info!("Listening on {:?}", addr);
 
-    let res = current_engine().and_then(|cur_engine| {
-        if let Some(cur_engine) = cur_engine {
-            if engine != cur_engine {
-                error!("Wrong engine! Please modify the engine file in the root of project");
-                exit(1);
-            }
-        }
-        boot_engine(engine, addr)
-    });
+    let res =
+        reconcile_engine(&data_dir, &LogNaming::default()).and_then(
+            |cur_engine| match cur_engine {
+                Some(cur_engine) if cur_engine != engine => Err(KvsError::StringError(format!(
+            "Wrong engine: {:?} records {}, whose data is in {:?}. Start with --engine {}, \
+             or delete that data to start over with {}",
+            data_dir.join("engine"),
+            cur_engine,
+            data_path(cur_engine, &data_dir),
+            cur_engine.to_string().to_lowercase(),
+            engine
+        ))),
+                _ => boot_engine(engine, &data_dir, addr, cli.resp),
+            },
+        );
 
     if let Err(e) = res {
         error!("{}", e);
@@ -62,18 +79,21 @@ fn main() {
     }
 }
 
-fn boot_engine(engine: Engine, addr: SocketAddr) -> Result<()> {
+fn boot_engine(engine: EngineKind, data_dir: &Path, addr: SocketAddr, resp: bool) -> Result<()> {
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{:?}", engine))?;
+    fs::create_dir_all(data_dir)?;
+    fs::write(data_dir.join("engine"), engine.to_string())?;
 
     let pool = RayonThreadPool::new(num_cpus::get())?;
+    let path = data_path(engine, data_dir);
+    run_with_engine(open_engine(engine, &path)?, pool, addr, resp)
+}
+
+/// The directory of the data of `engine` under `data_dir`.
+fn data_path(engine: EngineKind, data_dir: &Path) -> PathBuf {
     match engine {
-        Engine::Kvs => run_with_engine(Bitcask::open(get_kvstore_data_dir())?, pool, addr),
-        Engine::Sled => run_with_engine(
-            SledKvsEngine::new(sled::open(get_sled_data_dir())?),
-            pool,
-            addr,
-        ),
+        EngineKind::Kvs => kvstore_data_dir(data_dir),
+        EngineKind::Sled => sled_data_dir(data_dir),
     }
 }
 
@@ -81,22 +101,12 @@ fn run_with_engine<E: KvsEngine, P: ThreadPool>(
     engine: E,
     pool: P,
     addr: SocketAddr,
+    resp: bool,
 ) -> Result<()> {
     let server = KvsServer::new(engine, pool);
-    server.run(addr)
-}
-
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
-    }
-
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
-        }
+    if resp {
+        server.run_resp(addr)
+    } else {
+        server.run(addr)
     }
 }
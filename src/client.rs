@@ -1,52 +1,233 @@
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
     io::{BufReader, BufWriter, Write},
-    net::{TcpStream, ToSocketAddrs},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
 };
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
 
+use log::warn;
 use serde::Deserialize;
 use serde_json::{de::IoRead, Deserializer};
 
+#[cfg(feature = "tls")]
+use crate::transport::TlsClientStream;
 use crate::{
-    resp::{GetResponse, RemoveResponse, Request, SetResponse},
-    KvsError, Result,
+    resp::{
+        AuthResponse, CommandResult, GetResponse, Hello, HelloResponse, InfoResponse, PingResponse,
+        RemoveResponse, Request, ScanResponse, Secret, SetResponse, TransactionResponse,
+        ValueEncoding,
+    },
+    transport::{SharedStream, Stream},
+    BatchOp, KvsError, Result, ServerInfo,
 };
 
+/// The connection of a [KvsClient], plain TCP or TLS.
+type Connection = SharedStream<Box<dyn Stream>>;
+
 /// Key value store client
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: Deserializer<IoRead<BufReader<Connection>>>,
+    writer: BufWriter<Connection>,
+    /// The encoding of values agreed on with the server.
+    encoding: ValueEncoding,
 }
 
 impl KvsClient {
     /// Client connect to cettain address
+    ///
+    /// The client and the server first exchange their protocol versions, and
+    /// connecting fails with [KvsError::VersionMismatch] if they differ.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+        Self::over(Box::new(TcpStream::connect(addr)?), ValueEncoding::Utf8)
+    }
+
+    /// Connect to the server at `addr` like [KvsClient::connect], making up to
+    /// `max_attempts` attempts while the connection fails, e.g. because the server is
+    /// not up yet.
+    ///
+    /// The wait after the n-th failed attempt is `base_backoff * 2^(n-1)`, randomly
+    /// shortened by up to half so that clients started together spread out. `addr` is
+    /// resolved again on every attempt. Errors other than connection errors, such as
+    /// [KvsError::VersionMismatch], are returned at once, and so is the last error once
+    /// all attempts failed. At least one attempt is made.
+    pub fn connect_with_retry<A: ToSocketAddrs>(
+        addr: A,
+        max_attempts: usize,
+        base_backoff: Duration,
+    ) -> Result<Self> {
+        let mut attempt = 1;
+        loop {
+            match Self::connect(&addr) {
+                Err(e) if attempt < max_attempts && is_connection_error(&e) => {
+                    let backoff = jitter(base_backoff.saturating_mul(1 << (attempt - 1).min(31)));
+                    warn!(
+                        "Unable to connect, attempt {} of {}, retrying in {:?}: {}",
+                        attempt, max_attempts, backoff, e
+                    );
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Connect to the server at `addr`, sending and receiving values in `encoding`.
+    ///
+    /// With [ValueEncoding::Base64], [KvsClient::set_bytes] stores values which are
+    /// not UTF-8, see [ValueEncoding::Base64] for how they are stored. Connecting fails
+    /// if the server does not agree on the encoding, e.g. a server built before it.
+    pub fn connect_with_encoding<A: ToSocketAddrs>(
+        addr: A,
+        encoding: ValueEncoding,
+    ) -> Result<Self> {
+        Self::over(Box::new(TcpStream::connect(addr)?), encoding)
+    }
+
+    /// Connect to a server started with [KvsServer::run_unix](crate::KvsServer::run_unix)
+    /// on the Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<Self> {
+        Self::over(Box::new(UnixStream::connect(path)?), ValueEncoding::Utf8)
+    }
+
+    /// Connect to a server started with [KvsServer::run_tls](crate::KvsServer::run_tls).
+    ///
+    /// The server's certificate is verified against `server_name`, e.g. `"localhost"`,
+    /// and the roots of trust of `client_config`.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        client_config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self> {
+        let name =
+            rustls::pki_types::ServerName::try_from(server_name.to_owned()).map_err(|e| {
+                KvsError::StringError(format!("Invalid server name {:?}: {}", server_name, e))
+            })?;
+        let conn = rustls::ClientConnection::new(client_config, name)?;
+        let stream = rustls::StreamOwned::new(conn, TcpStream::connect(addr)?);
+        Self::over(Box::new(TlsClientStream(stream)), ValueEncoding::Utf8)
+    }
+
+    /// Talk to the server over `stream`, starting with the handshake, which agrees on
+    /// `encoding`.
+    fn over(stream: Box<dyn Stream>, encoding: ValueEncoding) -> Result<Self> {
+        let stream = SharedStream::new(stream);
+        let mut client = KvsClient {
+            reader: Deserializer::from_reader(BufReader::new(stream.clone())),
+            writer: BufWriter::new(stream),
+            encoding,
+        };
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// Connect to a server requiring a token, see
+    /// [KvsServer::auth_token](crate::KvsServer::auth_token).
+    ///
+    /// Connecting fails with [KvsError::Unauthorized] if the token is wrong.
+    pub fn connect_with_auth<A: ToSocketAddrs>(addr: A, token: String) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.auth(token)?;
+        Ok(client)
+    }
+
+    fn auth(&mut self, token: String) -> Result<()> {
+        let req = Request::Auth {
+            token: Secret(token),
+        };
+        serde_json::to_writer(&mut self.writer, &req)?;
+        self.writer.flush()?;
+        match AuthResponse::deserialize(&mut self.reader)? {
+            AuthResponse::Ok(()) => Ok(()),
+            AuthResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        let hello = Hello {
+            value_encoding: self.encoding,
+            ..Hello::current()
+        };
+        serde_json::to_writer(&mut self.writer, &hello)?;
+        self.writer.flush()?;
+        match HelloResponse::deserialize(&mut self.reader)? {
+            HelloResponse::Ok(server) if server.value_encoding != self.encoding => {
+                Err(KvsError::StringError(format!(
+                    "The server (rskv {}) does not support {:?} values",
+                    server.crate_version, self.encoding
+                )))
+            }
+            HelloResponse::Ok(_) => Ok(()),
+            HelloResponse::VersionMismatch(server) => Err(KvsError::VersionMismatch {
+                client: hello.version,
+                server: server.version,
+                server_crate_version: server.crate_version,
+            }),
+        }
     }
 
     /// Get the value of a given key from the server.
+    ///
+    /// With [ValueEncoding::Base64], a value which is not UTF-8 fails with
+    /// [KvsError::Utf8], see [KvsClient::get_bytes].
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let encoding = self.encoding;
+        self.get_encoded(key)?
+            .map(|value| encoding.decode(value))
+            .transpose()
+    }
+
+    /// Get the value of a given key from the server, which may be any bytes with
+    /// [ValueEncoding::Base64].
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        let encoding = self.encoding;
+        self.get_encoded(key)?
+            .map(|value| encoding.decode_bytes(value))
+            .transpose()
+    }
+
+    /// Get the value of `key` as sent by the server.
+    fn get_encoded(&mut self, key: String) -> Result<Option<String>> {
         serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
         self.writer.flush()?;
         let resp = GetResponse::deserialize(&mut self.reader)?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(err) => Err(err.into()),
         }
     }
 
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let value = self.encoding.encode(value);
+        self.set_encoded(key, value)
+    }
+
+    /// Set the value of a string key in the server to any bytes.
+    ///
+    /// Values which are not UTF-8 require [ValueEncoding::Base64], and otherwise fail
+    /// with [KvsError::Utf8] without being sent.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        let value = self.encoding.encode_bytes(value)?;
+        self.set_encoded(key, value)
+    }
+
+    /// Set `key` to `value`, already encoded.
+    fn set_encoded(&mut self, key: String, value: String) -> Result<()> {
         serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
         self.writer.flush()?;
         let resp = SetResponse::deserialize(&mut self.reader)?;
         match resp {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(err) => Err(err.into()),
         }
     }
 
@@ -57,7 +238,385 @@ impl KvsClient {
         let resp = RemoveResponse::deserialize(&mut self.reader)?;
         match resp {
             RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get information about the server, such as its error counters.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        serde_json::to_writer(&mut self.writer, &Request::Info)?;
+        self.writer.flush()?;
+        let resp = InfoResponse::deserialize(&mut self.reader)?;
+        match resp {
+            InfoResponse::Ok(info) => Ok(info),
+            InfoResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Check that the server is alive, e.g. to measure the round-trip latency.
+    ///
+    /// The server answers without touching the store.
+    pub fn ping(&mut self) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::Ping)?;
+        self.writer.flush()?;
+        match PingResponse::deserialize(&mut self.reader)? {
+            PingResponse::Pong => Ok(()),
+        }
+    }
+
+    /// Apply `commands` on the server atomically, in order.
+    ///
+    /// Unlike a [Batch], whose requests succeed or fail independently, a transaction
+    /// is all-or-nothing: if any command fails, e.g. removing a missing key, none of
+    /// them is applied and the error is returned. On success the server answers with
+    /// one response per command, in the same order.
+    ///
+    /// Only sets and removals can go into a transaction, which is why the commands are
+    /// [BatchOp]s: the engines apply them with [KvsEngine::write_batch](crate::KvsEngine::write_batch),
+    /// which has no reads.
+    pub fn transaction(&mut self, commands: Vec<BatchOp>) -> Result<Vec<Response>> {
+        let encoding = self.encoding;
+        let commands = commands
+            .into_iter()
+            .map(|command| match command {
+                BatchOp::Set { key, value } => {
+                    let value = encoding.encode(value);
+                    Request::Set { key, value }
+                }
+                BatchOp::Rm { key } => Request::Rm { key },
+            })
+            .collect();
+        serde_json::to_writer(&mut self.writer, &Request::Transaction { commands })?;
+        self.writer.flush()?;
+        let resp = TransactionResponse::deserialize(&mut self.reader)?;
+        match resp {
+            TransactionResponse::Ok(results) => Ok(results
+                .into_iter()
+                .map(|result| match result {
+                    CommandResult::Set => Response::Set,
+                    CommandResult::Rm => Response::Remove,
+                })
+                .collect()),
+            TransactionResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the pairs whose keys start with `prefix`, ordered by key.
+    ///
+    /// The server streams the pairs, and the returned [Scan] reads them as it is
+    /// iterated, so that memory stays bounded however many keys match. Dropping the
+    /// [Scan] before the end reads and discards the rest of the stream.
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Scan<'_>> {
+        serde_json::to_writer(&mut self.writer, &Request::Scan { prefix })?;
+        self.writer.flush()?;
+        Ok(Scan {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// Start a pipeline of requests which are sent together by [Batch::execute].
+    ///
+    /// The requests go out in one write and the responses are read afterwards, in
+    /// order, so a pipeline of independent requests costs a single round trip.
+    pub fn pipeline(&mut self) -> Batch<'_> {
+        Batch {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Start a [Batch] of requests, same as [KvsClient::pipeline].
+    pub fn batch(&mut self) -> Batch<'_> {
+        self.pipeline()
+    }
+
+    /// Read the response to `req` from the server.
+    fn read_response(&mut self, req: &Request) -> Result<Response> {
+        Ok(match req {
+            Request::Get { .. } => match GetResponse::deserialize(&mut self.reader)? {
+                GetResponse::Ok(value) => {
+                    match value.map(|value| self.encoding.decode(value)).transpose() {
+                        Ok(value) => Response::Get(value),
+                        Err(err) => Response::Err(err),
+                    }
+                }
+                GetResponse::Err(err) => Response::Err(err.into()),
+            },
+            Request::Set { .. } => match SetResponse::deserialize(&mut self.reader)? {
+                SetResponse::Ok(_) => Response::Set,
+                SetResponse::Err(err) => Response::Err(err.into()),
+            },
+            Request::Rm { .. } => match RemoveResponse::deserialize(&mut self.reader)? {
+                RemoveResponse::Ok(_) => Response::Remove,
+                RemoveResponse::Err(err) => Response::Err(err.into()),
+            },
+            Request::Info => match InfoResponse::deserialize(&mut self.reader)? {
+                InfoResponse::Ok(info) => Response::Info(info),
+                InfoResponse::Err(err) => Response::Err(err.into()),
+            },
+            Request::Ping => unreachable!("pings are not batched"),
+            Request::Auth { .. } => unreachable!("authentication is not batched"),
+            Request::Transaction { .. } => unreachable!("transactions are not batched"),
+            Request::Scan { .. } => unreachable!("scans are not batched"),
+        })
+    }
+}
+
+/// The server's answer to one request of a [Batch].
+#[derive(Debug)]
+pub enum Response {
+    /// Answer to [Batch::get], the value if the key exists.
+    Get(Option<String>),
+    /// Answer to [Batch::set].
+    Set,
+    /// Answer to [Batch::remove].
+    Remove,
+    /// Answer to [Batch::info].
+    Info(ServerInfo),
+    /// The request failed on the server.
+    Err(KvsError),
+}
+
+/// Requests queued on a [KvsClient], see [KvsClient::pipeline].
+///
+/// Nothing is sent until [Batch::execute] is called, which writes all requests at once
+/// and then reads their responses. A batch dropped with pending requests discards them
+/// with a warning.
+pub struct Batch<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Batch<'_> {
+    /// Queue getting the value of a given key.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Queue setting the value of a string key.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        let value = self.client.encoding.encode(value);
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    /// Queue removing a string key.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Rm { key });
+        self
+    }
+
+    /// Queue getting information about the server.
+    pub fn info(&mut self) -> &mut Self {
+        self.requests.push(Request::Info);
+        self
+    }
+
+    /// Send all queued requests and return their responses in the same order.
+    ///
+    /// A request failing on the server yields a [Response::Err] and does not stop
+    /// the following requests. Transport errors fail the whole batch.
+    pub fn execute(mut self) -> Result<Vec<Response>> {
+        let requests = std::mem::take(&mut self.requests);
+        for req in &requests {
+            serde_json::to_writer(&mut self.client.writer, req)?;
+        }
+        self.client.writer.flush()?;
+
+        requests
+            .iter()
+            .map(|req| self.client.read_response(req))
+            .collect()
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        if !self.requests.is_empty() {
+            warn!(
+                "A batch of {} requests was dropped without being executed",
+                self.requests.len()
+            );
+        }
+    }
+}
+
+/// The pairs of a scan as the server streams them, see [KvsClient::scan_prefix].
+///
+/// A scan failing on the server yields the error after the pairs sent before it, and
+/// then ends. A value which cannot be decoded, see [KvsClient::get], yields an error
+/// but does not end the scan.
+pub struct Scan<'a> {
+    client: &'a mut KvsClient,
+    /// Whether the end of the stream was read.
+    done: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let resp = match ScanResponse::deserialize(&mut self.client.reader) {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+        };
+        match resp {
+            ScanResponse::Item(key, value) => {
+                Some(self.client.encoding.decode(value).map(|value| (key, value)))
+            }
+            ScanResponse::End => {
+                self.done = true;
+                None
+            }
+            ScanResponse::Err(err) => {
+                self.done = true;
+                Some(Err(err.into()))
+            }
+        }
+    }
+}
+
+impl Drop for Scan<'_> {
+    fn drop(&mut self) {
+        // the rest of the stream is read, so that the next response is the next request's
+        self.for_each(drop);
+    }
+}
+
+/// A [KvsClient] which reconnects to the server when its connection breaks.
+///
+/// When a request fails with a connection error, e.g. because the server restarted,
+/// the client reconnects, retrying up to [ReconnectingClient::retries] times and
+/// waiting [ReconnectingClient::backoff] times the attempt number in between, and
+/// sends the request once more. Errors returned by the server, such as removing a
+/// missing key, are not retried.
+///
+/// A request whose connection broke may still have been applied by the server, so
+/// it can be applied twice. That is harmless for `get` and `set`, but a replayed
+/// `remove` then fails with the key not found.
+pub struct ReconnectingClient {
+    addr: SocketAddr,
+    /// The token to authenticate with on every connection, if any.
+    token: Option<String>,
+    client: Option<KvsClient>,
+    retries: usize,
+    backoff: Duration,
+}
+
+impl ReconnectingClient {
+    /// Connect to the server at `addr`.
+    pub fn connect(addr: SocketAddr) -> Result<Self> {
+        Ok(ReconnectingClient {
+            addr,
+            token: None,
+            client: Some(KvsClient::connect(addr)?),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        })
+    }
+
+    /// Connect to the server at `addr`, authenticating with `token` on every connection,
+    /// see [KvsClient::connect_with_auth].
+    pub fn connect_with_auth(addr: SocketAddr, token: String) -> Result<Self> {
+        Ok(ReconnectingClient {
+            addr,
+            client: Some(KvsClient::connect_with_auth(addr, token.clone())?),
+            token: Some(token),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        })
+    }
+
+    /// Retry connecting at most `retries` times after a broken connection. Defaults to 3.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Base delay between two attempts to reconnect. Defaults to 100 milliseconds.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Get the value of a given key from the server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call(|client| client.get(key.clone()))
+    }
+
+    /// Set the value of a string key in the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.call(|client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Remove a string key in the server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.call(|client| client.remove(key.clone()))
+    }
+
+    /// Send a request with `f`, reconnecting and sending it again if the connection broke.
+    fn call<T>(&mut self, f: impl Fn(&mut KvsClient) -> Result<T>) -> Result<T> {
+        if let Some(client) = &mut self.client {
+            match f(client) {
+                Err(e) if is_connection_error(&e) => {
+                    warn!("Lost connection to {}: {}", self.addr, e)
+                }
+                res => return res,
+            }
+        }
+        self.client = None;
+
+        let res = f(self.reconnect()?);
+        if matches!(&res, Err(e) if is_connection_error(e)) {
+            self.client = None;
         }
+        res
+    }
+
+    fn reconnect(&mut self) -> Result<&mut KvsClient> {
+        let mut attempt = 0;
+        loop {
+            let client = match &self.token {
+                Some(token) => KvsClient::connect_with_auth(self.addr, token.clone()),
+                None => KvsClient::connect(self.addr),
+            };
+            match client {
+                Ok(client) => return Ok(self.client.insert(client)),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Unable to reconnect to {}, attempt {}: {}",
+                        self.addr, attempt, e
+                    );
+                    thread::sleep(self.backoff * attempt as u32);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A random duration between half of `delay` and `delay`.
+fn jitter(delay: Duration) -> Duration {
+    // every `RandomState` is seeded differently, which is random enough for spreading out
+    let random = RandomState::new().build_hasher().finish();
+    delay / 2 + (delay / 2).mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Whether `err` means the connection to the server is broken, rather than the
+/// server answering with an error.
+fn is_connection_error(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(_) => true,
+        KvsError::Serde(e) => e.is_io() || e.is_eof(),
+        _ => false,
     }
 }
@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use crate::{BatchOp, Bitcask, EngineKind, KvsEngine, Result, SledKvsEngine};
+
+/// Either engine, picked at runtime, see [open_engine].
+#[derive(Clone)]
+pub enum AnyEngine {
+    /// A [Bitcask].
+    Kvs(Bitcask),
+    /// A [SledKvsEngine].
+    Sled(SledKvsEngine),
+}
+
+/// Open the engine of the given `kind` with its data in the directory `path`.
+///
+/// This lets the engine be chosen from configuration, e.g. for a [KvsServer](crate::KvsServer).
+/// Check the directory with [detect_engine](crate::detect_engine) first: each engine
+/// only reads its own data.
+pub fn open_engine(kind: EngineKind, path: &Path) -> Result<AnyEngine> {
+    Ok(match kind {
+        EngineKind::Kvs => AnyEngine::Kvs(Bitcask::open(path)?),
+        EngineKind::Sled => AnyEngine::Sled(SledKvsEngine::new(sled::open(path)?)),
+    })
+}
+
+impl AnyEngine {
+    /// The kind of the engine.
+    pub fn kind(&self) -> EngineKind {
+        match self {
+            AnyEngine::Kvs(_) => EngineKind::Kvs,
+            AnyEngine::Sled(_) => EngineKind::Sled,
+        }
+    }
+}
+
+/// Call `$call` on the engine inside `$self`, bound to `$engine`.
+macro_rules! delegate {
+    ($self:ident, $engine:ident => $call:expr) => {
+        match $self {
+            AnyEngine::Kvs($engine) => $call,
+            AnyEngine::Sled($engine) => $call,
+        }
+    };
+}
+
+impl KvsEngine for AnyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        delegate!(self, engine => engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        delegate!(self, engine => engine.get(key))
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        delegate!(self, engine => engine.get_many(keys))
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        delegate!(self, engine => engine.contains_key(key))
+    }
+
+    fn len(&self) -> Result<usize> {
+        delegate!(self, engine => engine.len())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        delegate!(self, engine => engine.is_empty())
+    }
+
+    fn rm(&self, key: String) -> Result<()> {
+        delegate!(self, engine => engine.rm(key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        delegate!(self, engine => engine.compare_and_swap(key, expected, new))
+    }
+
+    fn get_or_set(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        delegate!(self, engine => engine.get_or_set(key, default))
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        delegate!(self, engine => engine.write_batch(ops))
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        delegate!(self, engine => engine.for_each_prefix(prefix, f))
+    }
+
+    fn clear(&self) -> Result<()> {
+        delegate!(self, engine => engine.clear())
+    }
+
+    fn compact(&self) -> Result<()> {
+        delegate!(self, engine => engine.compact())
+    }
+
+    fn uncompacted_bytes(&self) -> Option<u64> {
+        delegate!(self, engine => engine.uncompacted_bytes())
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// The storage interface of [AsyncKvsServer](crate::AsyncKvsServer), for engines used
+/// from async code.
+///
+/// The methods match those of [KvsEngine], returning futures which can be sent to
+/// another thread, so that implementations may be written with `async fn`.
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    /// Set the value of a string key to a string
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+
+    /// Get the string value of a given string key
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Remove a given key.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn rm(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// An [AsyncKvsEngine] running a blocking [KvsEngine], e.g. a [Bitcask](crate::Bitcask),
+/// on the blocking threads of the Tokio runtime.
+///
+/// Each call takes an idle clone of the engine, or makes a new one, so that the file
+/// handles cached by a clone are reused by later calls.
+pub struct SpawnBlocking<E: KvsEngine> {
+    handles: Arc<Mutex<Handles<E>>>,
+}
+
+struct Handles<E> {
+    /// The engine new clones are made from.
+    engine: E,
+    /// Clones not used by any call right now.
+    idle: Vec<E>,
+}
+
+impl<E: KvsEngine> SpawnBlocking<E> {
+    /// Wrap a blocking `engine`.
+    pub fn new(engine: E) -> Self {
+        SpawnBlocking {
+            handles: Arc::new(Mutex::new(Handles {
+                engine,
+                idle: Vec::new(),
+            })),
+        }
+    }
+
+    /// Run `f` with a clone of the engine on a blocking thread.
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let engine = {
+            let mut handles = self.handles.lock().unwrap();
+            match handles.idle.pop() {
+                Some(engine) => engine,
+                None => handles.engine.clone(),
+            }
+        };
+        let handles = Arc::clone(&self.handles);
+        tokio::task::spawn_blocking(move || {
+            let res = f(&engine);
+            handles.lock().unwrap().idle.push(engine);
+            res
+        })
+        .await
+        .map_err(|e| KvsError::StringError(format!("Blocking task failed: {}", e)))?
+    }
+}
+
+impl<E: KvsEngine> Clone for SpawnBlocking<E> {
+    fn clone(&self) -> Self {
+        SpawnBlocking {
+            handles: Arc::clone(&self.handles),
+        }
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine for SpawnBlocking<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    async fn rm(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.rm(key)).await
+    }
+}
@@ -0,0 +1,120 @@
+//! The value cache of a [Bitcask](super::Bitcask), see
+//! [BitcaskOptions::value_cache_bytes](super::BitcaskOptions::value_cache_bytes).
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use lru::LruCache;
+
+/// Number of shards, each behind its own lock, so that concurrent reads of different
+/// keys rarely wait for each other.
+const SHARDS: usize = 16;
+
+/// A least-recently-used cache of string values, keyed by key and version.
+///
+/// A cached value is only returned for the version the index points to, so a value
+/// read before a later write is never served, however the two raced. Writes still
+/// drop the entries of their keys, to free the memory early. Compactions move values
+/// without changing their version, so they keep the cache as is.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// One shard: at most `capacity` bytes of keys and values.
+struct Shard {
+    lru: LruCache<Vec<u8>, (u64, String)>,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl ValueCache {
+    /// A cache holding about `capacity` bytes of keys and values.
+    pub(super) fn new(capacity: usize) -> Self {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    lru: LruCache::unbounded(),
+                    bytes: 0,
+                    capacity: capacity / SHARDS,
+                })
+            })
+            .collect();
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// The value of `key` at `version`, if cached, marking it as recently used.
+    pub(super) fn get(&self, key: &[u8], version: u64) -> Option<String> {
+        let value = match self.shard(key).lock().unwrap().lru.get(key) {
+            Some((cached, value)) if *cached == version => Some(value.clone()),
+            _ => None,
+        };
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Cache the `value` of `key` at `version`, evicting the least recently used
+    /// values to make room. Values too large for a shard are not cached.
+    pub(super) fn insert(&self, key: Vec<u8>, version: u64, value: String) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        let size = key.len() + value.len();
+        if size > shard.capacity {
+            return;
+        }
+        if let Some((old_key, (_, old_value))) = shard.lru.push(key, (version, value)) {
+            shard.bytes -= old_key.len() + old_value.len();
+        }
+        shard.bytes += size;
+        while shard.bytes > shard.capacity {
+            match shard.lru.pop_lru() {
+                Some((key, (_, value))) => shard.bytes -= key.len() + value.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub(super) fn remove(&self, key: &[u8]) {
+        let mut shard = self.shard(key).lock().unwrap();
+        if let Some((_, value)) = shard.lru.pop(key) {
+            shard.bytes -= key.len() + value.len();
+        }
+    }
+
+    pub(super) fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.lru.clear();
+            shard.bytes = 0;
+        }
+    }
+
+    /// The number of reads served from the cache, and of reads which missed it.
+    pub(super) fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
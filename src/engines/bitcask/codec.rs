@@ -0,0 +1,255 @@
+//! Serialization of the commands in the log files, see [Encoding].
+
+use std::{borrow::Cow, fs::File, io, iter};
+
+use serde::Serialize;
+use serde_json::Deserializer;
+
+use super::{BufReaderWithPos, Cmd, Records};
+use crate::{KvsError, Result};
+
+/// Bytes of a serialized `Cmd::Set` before its key: `{"Set":{"key":`.
+const SET_KEY_PREFIX: u64 = 14;
+/// Bytes of a serialized `Cmd::SetEx` before its key: `{"SetEx":{"key":`.
+const SET_EX_KEY_PREFIX: u64 = 16;
+/// Bytes of a serialized `Cmd::SetBytes` before its key: `{"SetBytes":{"key":`.
+const SET_BYTES_KEY_PREFIX: u64 = 19;
+/// Bytes of a serialized `Cmd::SetEx` between its key and its expiry: `,"expire_at":`.
+const EXPIRE_AT_PREFIX: u64 = 13;
+/// Bytes of a serialized set command before its value: `,"value":`.
+const SET_VALUE_PREFIX: u64 = 9;
+/// Bytes of a serialized set command after its value: `}}`.
+const SET_SUFFIX: u64 = 2;
+
+/// Bytes of the variant index starting a command serialized by bincode.
+const BINCODE_VARIANT_LEN: u64 = 4;
+/// Bytes of the length prefix of a string, or of a byte vector, serialized by bincode.
+const BINCODE_LEN_PREFIX: u64 = 8;
+
+/// How log records are serialized, see [BitcaskOptions::encoding](super::BitcaskOptions::encoding).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Human-readable JSON, the original format of the log files.
+    #[default]
+    Json,
+    /// The compact binary format of [bincode](https://docs.rs/bincode/1), which is
+    /// smaller and much faster to replay when opening a store.
+    Bincode,
+}
+
+impl Encoding {
+    /// The codec serializing records in this encoding.
+    pub(super) fn codec(self) -> &'static dyn Codec {
+        match self {
+            Encoding::Json => &Json,
+            Encoding::Bincode => &Bincode,
+        }
+    }
+}
+
+/// A value read back from a set command.
+pub(super) enum Value {
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub(super) fn into_string(self) -> Result<String> {
+        match self {
+            Value::String(value) => Ok(value),
+            Value::Bytes(value) => Ok(String::from_utf8(value)?),
+        }
+    }
+
+    pub(super) fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Value::String(value) => value.into_bytes(),
+            Value::Bytes(value) => value,
+        }
+    }
+}
+
+/// Serialization of commands.
+///
+/// The value of a set command must be serialized last and on its own, so that reads
+/// deserialize only the value, see [Codec::value_offset].
+pub(super) trait Codec: Sync {
+    /// Append `cmd`, serialized, to `buf`.
+    fn encode(&self, cmd: &Cmd, buf: &mut Vec<u8>) -> Result<()>;
+
+    /// Deserialize a command serialized by `encode`.
+    fn decode(&self, bytes: &[u8]) -> Result<Cmd>;
+
+    /// Iterate over the commands of `reader`, from its position up to its end.
+    fn decode_stream<'a>(&self, reader: &'a mut BufReaderWithPos<File>) -> Records<'a>;
+
+    /// Where the value starts in the serialized set command `cmd`.
+    fn value_offset(&self, cmd: &Cmd) -> u64;
+
+    /// Bytes after the value in a serialized set command.
+    fn value_suffix(&self) -> u64;
+
+    /// Bytes of a serialized string value besides the string itself, e.g. quotes.
+    fn value_overhead(&self) -> u64;
+
+    /// Deserialize the value of a set command, a byte vector if `binary`.
+    fn decode_value(&self, bytes: &[u8], binary: bool) -> Result<Value>;
+
+    /// Deserialize the value of a set command as a string, borrowed from `bytes` if it
+    /// is stored as is. A binary value must be valid UTF-8.
+    fn decode_str<'a>(&self, bytes: &'a [u8], binary: bool) -> Result<Cow<'a, str>>;
+}
+
+/// See [Encoding::Json].
+struct Json;
+
+impl Codec for Json {
+    fn encode(&self, cmd: &Cmd, buf: &mut Vec<u8>) -> Result<()> {
+        Ok(serde_json::to_writer(buf, cmd)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Cmd> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn decode_stream<'a>(&self, reader: &'a mut BufReaderWithPos<File>) -> Records<'a> {
+        let start = reader.pos;
+        let mut pos = start;
+        let mut stream = Deserializer::from_reader(reader).into_iter::<Cmd>();
+        Box::new(iter::from_fn(move || {
+            let cmd = stream.next()?;
+            let new_pos = start + stream.byte_offset() as u64;
+            let range = pos..new_pos;
+            pos = new_pos;
+            Some(cmd.map(|cmd| (cmd, range)).map_err(KvsError::from))
+        }))
+    }
+
+    fn value_offset(&self, cmd: &Cmd) -> u64 {
+        match cmd {
+            Cmd::Set { key, .. } => SET_KEY_PREFIX + json_len(key) + SET_VALUE_PREFIX,
+            Cmd::SetEx { key, expire_at, .. } => {
+                SET_EX_KEY_PREFIX
+                    + json_len(key)
+                    + EXPIRE_AT_PREFIX
+                    + json_len(expire_at)
+                    + SET_VALUE_PREFIX
+            }
+            Cmd::SetBytes { key, .. } => SET_BYTES_KEY_PREFIX + json_len(key) + SET_VALUE_PREFIX,
+            _ => unreachable!("not a set command"),
+        }
+    }
+
+    fn value_suffix(&self) -> u64 {
+        SET_SUFFIX
+    }
+
+    fn value_overhead(&self) -> u64 {
+        2
+    }
+
+    fn decode_value(&self, bytes: &[u8], binary: bool) -> Result<Value> {
+        if binary {
+            Ok(Value::Bytes(serde_json::from_slice(bytes)?))
+        } else {
+            Ok(Value::String(serde_json::from_slice(bytes)?))
+        }
+    }
+
+    fn decode_str<'a>(&self, bytes: &'a [u8], binary: bool) -> Result<Cow<'a, str>> {
+        if binary {
+            return Ok(Cow::Owned(String::from_utf8(serde_json::from_slice(
+                bytes,
+            )?)?));
+        }
+        // only a string without escapes can be borrowed
+        match serde_json::from_slice(bytes) {
+            Ok(value) => Ok(Cow::Borrowed(value)),
+            Err(_) => Ok(Cow::Owned(serde_json::from_slice(bytes)?)),
+        }
+    }
+}
+
+/// See [Encoding::Bincode].
+struct Bincode;
+
+impl Codec for Bincode {
+    fn encode(&self, cmd: &Cmd, buf: &mut Vec<u8>) -> Result<()> {
+        Ok(bincode::serialize_into(buf, cmd)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Cmd> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    fn decode_stream<'a>(&self, reader: &'a mut BufReaderWithPos<File>) -> Records<'a> {
+        Box::new(iter::from_fn(move || {
+            match reader.at_end() {
+                Ok(true) => return None,
+                Ok(false) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            let pos = reader.pos;
+            let cmd = bincode::deserialize_from(&mut *reader);
+            Some(
+                cmd.map(|cmd| (cmd, pos..reader.pos))
+                    .map_err(KvsError::from),
+            )
+        }))
+    }
+
+    fn value_offset(&self, cmd: &Cmd) -> u64 {
+        let key_len = match cmd {
+            Cmd::Set { key, .. } => key.len(),
+            // plus the expiry, a `u64`
+            Cmd::SetEx { key, .. } => key.len() + 8,
+            Cmd::SetBytes { key, .. } => key.len(),
+            _ => unreachable!("not a set command"),
+        };
+        BINCODE_VARIANT_LEN + BINCODE_LEN_PREFIX + key_len as u64
+    }
+
+    fn value_suffix(&self) -> u64 {
+        0
+    }
+
+    fn value_overhead(&self) -> u64 {
+        BINCODE_LEN_PREFIX
+    }
+
+    fn decode_value(&self, bytes: &[u8], binary: bool) -> Result<Value> {
+        if binary {
+            Ok(Value::Bytes(bincode::deserialize(bytes)?))
+        } else {
+            Ok(Value::String(bincode::deserialize(bytes)?))
+        }
+    }
+
+    fn decode_str<'a>(&self, bytes: &'a [u8], binary: bool) -> Result<Cow<'a, str>> {
+        if binary {
+            Ok(Cow::Owned(String::from_utf8(bincode::deserialize(bytes)?)?))
+        } else {
+            Ok(Cow::Borrowed(bincode::deserialize(bytes)?))
+        }
+    }
+}
+
+/// Length of `value` serialized as JSON, e.g. a string including quotes and escapes.
+fn json_len(value: &(impl Serialize + ?Sized)) -> u64 {
+    struct Counter(u64);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, value).expect("a key always serializes");
+    counter.0
+}
@@ -0,0 +1,161 @@
+//! The in-memory index of a [Bitcask](super::Bitcask), see
+//! [BitcaskOptions::ordered_index](super::BitcaskOptions::ordered_index).
+
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::RwLock,
+};
+
+use dashmap::DashMap;
+
+use super::CmdPos;
+
+/// A map of keys to the positions of their values in the log files.
+pub(super) enum Index {
+    /// Sharded by hash, so that writes only lock one shard, but unordered.
+    Hashed(DashMap<Vec<u8>, CmdPos>),
+    /// Ordered by key, but every write locks the whole map.
+    Ordered(RwLock<BTreeMap<Vec<u8>, CmdPos>>),
+}
+
+impl Index {
+    pub(super) fn new(ordered: bool) -> Self {
+        if ordered {
+            Index::Ordered(RwLock::new(BTreeMap::new()))
+        } else {
+            Index::Hashed(DashMap::new())
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match self {
+            Index::Hashed(map) => map.len(),
+            Index::Ordered(map) => map.read().unwrap().len(),
+        }
+    }
+
+    pub(super) fn clear(&self) {
+        match self {
+            Index::Hashed(map) => map.clear(),
+            Index::Ordered(map) => map.write().unwrap().clear(),
+        }
+    }
+
+    /// A copy of the position of `key`, so that no lock is held while its value is read.
+    pub(super) fn get(&self, key: &[u8]) -> Option<CmdPos> {
+        match self {
+            Index::Hashed(map) => map.get(key).map(|cmd_pos| cmd_pos.clone()),
+            Index::Ordered(map) => map.read().unwrap().get(key).cloned(),
+        }
+    }
+
+    /// Point `key` to `cmd_pos`, returning its previous position.
+    pub(super) fn insert(&self, key: Vec<u8>, cmd_pos: CmdPos) -> Option<CmdPos> {
+        match self {
+            Index::Hashed(map) => map.insert(key, cmd_pos),
+            Index::Ordered(map) => map.write().unwrap().insert(key, cmd_pos),
+        }
+    }
+
+    pub(super) fn remove(&self, key: &[u8]) -> Option<CmdPos> {
+        match self {
+            Index::Hashed(map) => map.remove(key).map(|(_, cmd_pos)| cmd_pos),
+            Index::Ordered(map) => map.write().unwrap().remove(key),
+        }
+    }
+
+    /// Remove `key` if its position satisfies `f`, atomically.
+    pub(super) fn remove_if(&self, key: &[u8], f: impl FnOnce(&CmdPos) -> bool) -> Option<CmdPos> {
+        match self {
+            Index::Hashed(map) => map
+                .remove_if(key, |_, cmd_pos| f(cmd_pos))
+                .map(|(_, cmd_pos)| cmd_pos),
+            Index::Ordered(map) => {
+                let mut map = map.write().unwrap();
+                if map.get(key).is_some_and(f) {
+                    map.remove(key)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Change the position of `key` in place with `f`, if the key is present.
+    pub(super) fn update(&self, key: &[u8], f: impl FnOnce(&mut CmdPos)) {
+        match self {
+            Index::Hashed(map) => {
+                if let Some(mut cmd_pos) = map.get_mut(key) {
+                    f(&mut cmd_pos);
+                }
+            }
+            Index::Ordered(map) => {
+                if let Some(cmd_pos) = map.write().unwrap().get_mut(key) {
+                    f(cmd_pos);
+                }
+            }
+        }
+    }
+
+    /// Call `f` on every entry, in no particular order.
+    ///
+    /// The index is locked meanwhile, a shard at a time if hashed, so `f` must not
+    /// access the index.
+    pub(super) fn for_each(&self, mut f: impl FnMut(&[u8], &CmdPos)) {
+        match self {
+            Index::Hashed(map) => map.iter().for_each(|entry| f(entry.key(), entry.value())),
+            Index::Ordered(map) => map
+                .read()
+                .unwrap()
+                .iter()
+                .for_each(|(key, cmd_pos)| f(key, cmd_pos)),
+        }
+    }
+
+    /// Collect what `f` returns for the entries, like [Index::for_each].
+    pub(super) fn filter_map<T>(&self, mut f: impl FnMut(&[u8], &CmdPos) -> Option<T>) -> Vec<T> {
+        let mut items = Vec::new();
+        self.for_each(|key, cmd_pos| items.extend(f(key, cmd_pos)));
+        items
+    }
+
+    /// The keys between `start` and `end`, ordered by their bytes.
+    ///
+    /// A hashed index is scanned in full and the matching keys are sorted.
+    pub(super) fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Vec<Vec<u8>> {
+        if is_empty_range(start, end) {
+            // `BTreeMap::range` panics on these
+            return Vec::new();
+        }
+        match self {
+            Index::Hashed(map) => {
+                let mut keys: Vec<Vec<u8>> = map
+                    .iter()
+                    .filter(|entry| {
+                        RangeBounds::<[u8]>::contains(&(start, end), entry.key().as_slice())
+                    })
+                    .map(|entry| entry.key().clone())
+                    .collect();
+                keys.sort_unstable();
+                keys
+            }
+            Index::Ordered(map) => map
+                .read()
+                .unwrap()
+                .range::<[u8], _>((start, end))
+                .map(|(key, _)| key.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Whether no key can lie between `start` and `end`.
+fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
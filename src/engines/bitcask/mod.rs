@@ -0,0 +1,3544 @@
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter,
+    num::NonZeroUsize,
+    ops::{Bound, Range, RangeBounds},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{error, info, warn};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use self::cache::ValueCache;
+pub use self::codec::Encoding;
+use self::codec::Value;
+use self::index::Index;
+pub use self::verify::{verify, verify_with_naming, CorruptRecord, Inconsistency, IntegrityReport};
+use crate::{BatchOp, KvsEngine, KvsError, Result};
+
+mod cache;
+mod codec;
+mod index;
+mod verify;
+
+/// Default of [BitcaskOptions::compaction_threshold].
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// How often a stalled write re-checks whether compaction caught up.
+const STALL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The file a writable [Bitcask] locks its data directory with.
+const LOCK_FILE: &str = "LOCK";
+
+/// Most expired keys removed under one hold of the writer lock, see [Bitcask::sweep_expired].
+const SWEEP_BATCH: usize = 1024;
+
+/// Bytes before each checksummed record: its length and its CRC32, both little-endian `u32`s.
+const FRAME_HEADER_LEN: u64 = 8;
+
+/// Largest scratch buffer a reader keeps between reads, see [Reader::with_value_bytes].
+const MAX_SCRATCH_BYTES: usize = 1024 * 1024;
+
+/// Options for opening a [Bitcask], see [Bitcask::open_with_options].
+#[derive(Debug, Clone)]
+pub struct BitcaskOptions {
+    /// Compact once more stale bytes than this are in the log files. Defaults to 1 MiB.
+    /// Ignored if [BitcaskOptions::compaction_ratio] is set.
+    pub compaction_threshold: u64,
+    /// Compact once stale bytes make up more than this fraction of the log files,
+    /// between 0 and 1 exclusive, e.g. `0.4`, instead of once they exceed
+    /// [BitcaskOptions::compaction_threshold]. `None` (the default) uses the threshold.
+    ///
+    /// A fixed threshold compacts a large store while little of it is stale, and lets a
+    /// small store grow many times its live size. A ratio bounds the disk usage to about
+    /// `1 / (1 - ratio)` times the live size, at the cost of compacting small stores
+    /// after proportionally few writes.
+    pub compaction_ratio: Option<f64>,
+    /// Throttle writes while compaction lags behind. `None` (the default) never throttles.
+    pub write_stall: Option<WriteStall>,
+    /// Maintain a histogram of value sizes, reported by [Bitcask::stats]. Off by default.
+    pub value_size_histogram: bool,
+    /// Refuse to open unless the filesystem of the data directory has at least this
+    /// many bytes available. `None` (the default) skips the check.
+    pub min_free_space: Option<u64>,
+    /// Order of keys in [Bitcask::scan], [Bitcask::first_key] and [Bitcask::last_key].
+    /// `None` (the default) orders keys lexicographically.
+    pub key_comparator: Option<KeyComparator>,
+    /// Read values of sealed log files through memory maps. Off by default.
+    ///
+    /// A `get` then parses the value straight from the mapped pages, without a seek
+    /// and a read syscall, and the OS page cache serves repeated reads. This helps
+    /// random-read heavy workloads whose data fits in memory. It hurts when the data
+    /// is much larger than memory, as every page fault blocks the reading thread.
+    /// The active log file is always read through a buffer, since it still grows.
+    ///
+    /// Requires the `mmap` feature. Compare both read paths with
+    /// `cargo bench --features mmap --bench engine`.
+    #[cfg(feature = "mmap")]
+    pub mmap_reads: bool,
+    /// Frame new log records with their length and a CRC32 checksum. Off by default.
+    ///
+    /// Checksums are verified when the store is opened and on every read, which fails
+    /// with [KvsError::ChecksumMismatch]. When opening, a log file is truncated after
+    /// its last valid record instead, e.g. after a torn write.
+    ///
+    /// Each log file records whether it is checksummed, so existing logs stay readable
+    /// and are converted by the next compaction.
+    pub checksums: bool,
+    /// Serialization of new log records. Defaults to [Encoding::Json].
+    ///
+    /// Like [BitcaskOptions::checksums], the encoding is recorded per log file, so logs
+    /// of either encoding are readable and converted by the next compaction. Compare
+    /// the startup time of both with `cargo bench --bench engine open`.
+    pub encoding: Encoding,
+    /// When writes are synced to disk. Defaults to [SyncPolicy::None].
+    pub sync: SyncPolicy,
+    /// Open without writing anything to the data directory. Off by default.
+    ///
+    /// No log file is created, a torn record at the end of a log is left in place,
+    /// and writes and compactions fail with [KvsError::ReadOnly]. This lets backup
+    /// and inspection tools read the data directory of a store opened elsewhere.
+    pub read_only: bool,
+    /// Reject keys longer than this many bytes with [KvsError::KeyTooLarge].
+    /// `None` (the default) accepts any size.
+    pub max_key_bytes: Option<usize>,
+    /// Reject values longer than this many bytes with [KvsError::ValueTooLarge].
+    /// `None` (the default) accepts any size.
+    ///
+    /// Oversized writes are rejected before anything is written, so they neither
+    /// pollute the log nor need to be compacted away.
+    pub max_value_bytes: Option<usize>,
+    /// Roll to a new log file once a log or compaction file holds this many bytes.
+    /// `None` (the default) writes into a single file until the next compaction, and
+    /// compacts into a single file, however large.
+    ///
+    /// A file only exceeds the limit if a single record, or batch, does.
+    pub max_file_bytes: Option<u64>,
+    /// Remove expired keys on a background thread this often, see [Bitcask::sweep_expired].
+    /// `None` (the default) leaves them until the next compaction. Ignored when
+    /// [BitcaskOptions::read_only] is set.
+    pub sweep_interval: Option<Duration>,
+    /// Keep the index in a `BTreeMap` behind a `RwLock` instead of a `DashMap`, so
+    /// that [Bitcask::get_range] walks the keys in order instead of sorting them all.
+    /// Off by default.
+    ///
+    /// The `DashMap` is sharded: a write only blocks the readers of one shard. The
+    /// ordered index is a single map, so every write blocks all readers of the index
+    /// for a moment, which costs throughput under concurrent reads and writes.
+    pub ordered_index: bool,
+    /// How log and hint files are named. Defaults to `{fid}.log` and `{fid}.hint`.
+    ///
+    /// Only the files named this way are opened, so a store opened with another naming
+    /// sees an empty directory.
+    pub log_naming: LogNaming,
+    /// Cache up to about this many bytes of recently read string values, keys
+    /// included, so that reads of hot keys skip the log. `None` (the default) reads
+    /// every value from the log.
+    ///
+    /// [KvsEngine::get] consults the cache before the log, and writes drop the cached
+    /// values of their keys. The hits and misses are reported by [Bitcask::stats].
+    /// Compare both with `cargo bench --bench engine zipfian_read`.
+    pub value_cache_bytes: Option<usize>,
+    /// Keep at most this many log files open for reading per handle, closing the least
+    /// recently read one to open another. `None` (the default) keeps every log file
+    /// read so far open.
+    ///
+    /// Each clone of a [Bitcask] reads through its own file handles, e.g. one per
+    /// server thread, so a store opens up to this many files per clone, plus the
+    /// active log file and those of a running compaction. A closed file is opened
+    /// again when read, so a cap below the number of log files read in turn costs an
+    /// `open` per read.
+    pub max_open_files: Option<usize>,
+    /// Called after every compaction, with what it reclaimed. `None` by default.
+    ///
+    /// The hook runs on a thread of its own, once the writer lock is released, so it
+    /// may use the store, e.g. to warm a cache or to record metrics. It may thus run
+    /// after [KvsEngine::compact] returns.
+    pub on_compaction: Option<CompactionHook>,
+    /// The clock deciding when keys set with a TTL expire, see [Clock]. `None` (the
+    /// default) reads the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Write only the last operation of each key of a batch, see
+    /// [KvsEngine::write_batch]. Off by default.
+    ///
+    /// A batch setting a key five times then appends a single record instead of five,
+    /// four of which would be stale right away. The batch still reads as applied in
+    /// order and as a whole, but its log records no longer are the operations as
+    /// given: the intermediate values of a key are never written, the records are in
+    /// the order of the last operation of their keys, and a key set and then removed
+    /// by the batch leaves no record at all. Tools replaying the log, e.g.
+    /// [inspect_log], only see the outcome of the batch.
+    pub coalesce_batches: bool,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_ratio: None,
+            write_stall: None,
+            value_size_histogram: false,
+            min_free_space: None,
+            key_comparator: None,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+            checksums: false,
+            encoding: Encoding::Json,
+            sync: SyncPolicy::None,
+            read_only: false,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            max_file_bytes: None,
+            sweep_interval: None,
+            ordered_index: false,
+            log_naming: LogNaming::default(),
+            value_cache_bytes: None,
+            max_open_files: None,
+            on_compaction: None,
+            clock: None,
+            coalesce_batches: false,
+        }
+    }
+}
+
+type CompareFn = dyn Fn(&str, &str) -> cmp::Ordering + Send + Sync;
+
+/// A custom order of keys, see [BitcaskOptions::key_comparator].
+#[derive(Clone)]
+pub struct KeyComparator(Arc<CompareFn>);
+
+impl KeyComparator {
+    /// Order keys with `f`, which must be a total order.
+    pub fn new(f: impl Fn(&str, &str) -> cmp::Ordering + Send + Sync + 'static) -> Self {
+        KeyComparator(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for KeyComparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyComparator")
+    }
+}
+
+/// A source of the current time, see [BitcaskOptions::clock].
+///
+/// Tests of expiry can inject a clock they advance by hand instead of sleeping. The
+/// time is read whenever a key with a TTL is written, read, swept or compacted, so
+/// it should not go backwards, or expired keys may come back until compacted.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+}
+
+type CompactionFn = dyn Fn(CompactionStats) + Send + Sync;
+
+/// A function called after every compaction, see [BitcaskOptions::on_compaction].
+#[derive(Clone)]
+pub struct CompactionHook(Arc<CompactionFn>);
+
+impl CompactionHook {
+    /// Call `f` after every compaction.
+    pub fn new(f: impl Fn(CompactionStats) + Send + Sync + 'static) -> Self {
+        CompactionHook(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for CompactionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionHook")
+    }
+}
+
+/// Throttling of writes while too many stale bytes wait for compaction.
+///
+/// This is the equivalent of a write-stall in LSM engines: it keeps the disk usage
+/// bounded when writes come in faster than compaction can reclaim space.
+#[derive(Debug, Clone)]
+pub struct WriteStall {
+    /// Writes are stalled while more stale bytes than this are waiting for compaction.
+    ///
+    /// Without a [BitcaskOptions::compaction_ratio], it must not be below
+    /// [BitcaskOptions::compaction_threshold]: compaction only starts above the
+    /// threshold, so every write in between would wait the full `max_stall`.
+    /// [Bitcask::open_with_options] rejects such options.
+    pub high_water_mark: u64,
+    /// The longest a single write waits for compaction to catch up.
+    pub max_stall: Duration,
+}
+
+/// When a [Bitcask] syncs its active log file to disk, see [BitcaskOptions::sync].
+///
+/// Every write is flushed to the OS before it returns, which survives a crash of the
+/// process but not of the machine: until the OS writes its cache back, a power loss
+/// can lose acknowledged writes. Syncing closes that window at the cost of a disk
+/// round trip. A compaction file is synced before the logs it replaces are deleted,
+/// unless the policy is [SyncPolicy::None].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never sync, leaving it to the OS. Fastest, but a power loss can lose any
+    /// write not written back yet, typically up to half a minute of them.
+    #[default]
+    None,
+    /// Sync after every write, before it returns. An acknowledged write survives a
+    /// power loss, but each write waits for the disk.
+    EveryWrite,
+    /// Sync after every `n` writes, so at most the last `n - 1` acknowledged writes
+    /// can be lost. The remaining writes are synced when the store is dropped.
+    EveryN(u64),
+    /// Sync on the first write at least this long after the previous sync.
+    ///
+    /// This bounds the writes lost to those of the last interval while writes keep
+    /// coming. There is no background thread: the writes before an idle period stay
+    /// unsynced until the next write, until the store is dropped, or until the OS
+    /// writes them back.
+    Interval(Duration),
+}
+
+/// How the files of a [Bitcask] are named, see [BitcaskOptions::log_naming].
+///
+/// Log file `fid` is named `{prefix}{fid}.{extension}`, its hint file
+/// `{prefix}{fid}.hint`, with `fid` padded with zeros to `width` digits. E.g. a
+/// prefix of `seg-`, a width of 6 and an extension of `data` give `seg-000001.data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogNaming {
+    /// Put before the fid. Empty by default.
+    pub prefix: String,
+    /// The minimum number of digits of the fid. 0 by default, i.e. no padding.
+    pub width: usize,
+    /// The extension of log files, `log` by default. It must not be `hint`.
+    pub extension: String,
+}
+
+impl Default for LogNaming {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            width: 0,
+            extension: "log".to_owned(),
+        }
+    }
+}
+
+impl LogNaming {
+    /// Reject namings which would name files outside the data directory, or log
+    /// files like hint files.
+    fn check(&self) -> Result<()> {
+        let invalid = |part: &str| part.contains(std::path::is_separator);
+        if self.extension.is_empty()
+            || self.extension == "hint"
+            || invalid(&self.prefix)
+            || invalid(&self.extension)
+        {
+            return Err(KvsError::StringError(format!(
+                "Invalid log naming: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+
+    fn file_name(&self, fid: u64, extension: &str) -> String {
+        format!(
+            "{}{:0width$}.{}",
+            self.prefix,
+            fid,
+            extension,
+            width = self.width
+        )
+    }
+
+    /// The fid of the file named `name`, if it is one with `extension`.
+    pub(crate) fn parse(&self, name: &str, extension: &str) -> Option<u64> {
+        let fid = name
+            .strip_prefix(self.prefix.as_str())?
+            .strip_suffix(extension)?
+            .strip_suffix('.')?;
+        if fid.is_empty() || !fid.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        fid.parse().ok()
+    }
+}
+
+/// Statistics of a [Bitcask], see [Bitcask::stats].
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Number of keys in the index, including expired keys which were not compacted yet.
+    pub num_keys: usize,
+    /// Bytes of stale commands which the next compaction reclaims.
+    pub uncompacted_bytes: u64,
+    /// Total size of the log files, see [BitcaskOptions::compaction_ratio].
+    pub total_bytes: u64,
+    /// Number of log files in the data directory.
+    pub num_log_files: usize,
+    /// Total time writes spent stalled by [WriteStall].
+    pub write_stall_time: Duration,
+    /// Number of live values by size, if [BitcaskOptions::value_size_histogram] is set.
+    ///
+    /// Bucket `0` counts empty values and bucket `i` counts values of `2^(i-1)` up to
+    /// `2^i - 1` bytes. Trailing empty buckets are left out. Sizes are derived from
+    /// the record lengths, so escaped characters are counted at their escaped size, and
+    /// binary values at the size of their serialized form.
+    pub value_sizes: Option<Vec<u64>>,
+    /// Reads served by the cache of [BitcaskOptions::value_cache_bytes].
+    pub cache_hits: u64,
+    /// Reads which missed the cache of [BitcaskOptions::value_cache_bytes].
+    pub cache_misses: u64,
+}
+
+/// What a compaction would reclaim, see [Bitcask::compaction_preview].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Total size of the log files.
+    pub total_bytes: u64,
+    /// Size of the records of the live keys, including their checksums.
+    pub live_bytes: u64,
+    /// Bytes a compaction would reclaim, `total_bytes - live_bytes`.
+    pub reclaimable_bytes: u64,
+}
+
+/// A log file of a [Bitcask], see [Bitcask::log_files].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFileInfo {
+    /// The number of the file, named after [BitcaskOptions::log_naming].
+    pub fid: u64,
+    /// Size of the file, including writes still buffered for the active file.
+    pub bytes: u64,
+    /// Whether new writes go to this file.
+    pub active: bool,
+}
+
+/// Where the value of a key is stored, see [Bitcask::get_with_meta].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// The log file of the record.
+    pub fid: u64,
+    /// Start of the command in its log file, after the checksum if any.
+    pub pos: u64,
+    /// Length of the serialized command.
+    pub len: u64,
+    /// Whether the record is framed with a checksum, see [BitcaskOptions::checksums].
+    pub checksummed: bool,
+    /// When the key expires, in milliseconds since the Unix epoch, if it was set with
+    /// [Bitcask::set_with_ttl].
+    pub expire_at: Option<u64>,
+    /// The version of the key, see [Bitcask::get_versioned].
+    pub version: u64,
+}
+
+/// What a finished compaction did, see [BitcaskOptions::on_compaction].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Number of compaction files the live records were copied into.
+    pub files_written: usize,
+    /// Number of log files the compaction replaced and deleted.
+    pub files_removed: usize,
+    /// Stale bytes reclaimed. What became stale while compacting is left for the
+    /// next compaction.
+    pub bytes_reclaimed: u64,
+    /// How long the compaction took, from its start to when it was installed.
+    pub duration: Duration,
+}
+
+/// Counters shared by all handles of one [Bitcask].
+#[derive(Default)]
+struct Counters {
+    /// Mirror of `Writer::uncompacted`, readable without taking the writer lock.
+    uncompacted: AtomicU64,
+    /// Total time writes spent stalled, in nanoseconds.
+    write_stall_nanos: AtomicU64,
+}
+
+/// The [Bitcask] stores string or binary key/value pairs into disk.
+///
+/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+///
+/// ## Concurrency
+///
+/// Reads only take the lock of their shard of the index, so they run in parallel with
+/// each other and with writes. Writes, even of unrelated keys, take turns on a single
+/// writer lock, since they all append to the active log file: the index must point to
+/// the last write of a key in log order, so a write updates it before the next write
+/// appends. Commands are encoded before taking the lock, which is then held to append
+/// them, sync them as [BitcaskOptions::sync] asks, and update the index. Compare the
+/// throughput of concurrent writers with `cargo bench --bench engine concurrent_writes`.
+///
+/// ## Terminology
+///
+/// * `command` - A request or the representation of a request made to the database.
+///   These are issued on the command line or over the network.
+///   They have an in-memory representation, a textual representation, and a machine-readable serialized representation.
+///
+/// * `log` - An on-disk sequence of commands, in the order originally received and executed.
+///   Our database's on-disk format is almost entirely made up of logs.
+///   It will be simple, but also surprisingly efficient.
+///
+/// * `log pointer` - A file offset into the log. Sometimes we'll just call this a "file offset".
+///
+/// * `log compaction` - As writes are issued to the database they sometimes invalidate old log entries.
+///   For example, writing key/value a = 0 then writing a = 1, makes the first log entry for "a" useless.
+///   Compaction — in our database at least — is the process of reducing the size of the database by remove stale commands from the log.
+///
+/// * `in-memory index` (or `index`) - A map of keys to log pointers.
+///   When a read request is issued, the in-memory index is searched for the appropriate log pointer,
+///   and when it is found the value is retrieved from the on-disk log.
+///   In our key/value store, like in bitcask, the index for the entire database is stored in memory.
+///
+/// * `index file` - The on-disk representation of the in-memory index.
+///   Without this the log would need to be completely replayed to restore
+///   the state of the in-memory index each time the database is started.
+#[derive(Clone)]
+pub struct Bitcask {
+    /// [Bitcask] build caches to quickly find reader belongs to `fid` using `HashMap`.
+    ///
+    /// This hashmap insert all exsiting log files when [Bitcask]::open is called.
+    reader: Reader,
+    /// Current writer to write `command`s into disk
+    cur_writer: Arc<Mutex<Writer>>,
+
+    /// In-memory Index maps from keys to [CmdPos], see [BitcaskOptions::ordered_index].
+    ///
+    /// It is built from the `log files` in the disk when [Bitcask]::open is called.
+    index: Arc<Index>,
+    /// See [BitcaskOptions::value_cache_bytes].
+    cache: Option<Arc<ValueCache>>,
+
+    options: Arc<BitcaskOptions>,
+    counters: Arc<Counters>,
+    /// The thread of [BitcaskOptions::sweep_interval], if any, stopped when the last
+    /// handle is dropped.
+    sweeper: Option<Arc<Sweeper>>,
+    /// The locked `LOCK` file of a writable store, unlocked when the last handle is
+    /// dropped. Declared last, so that the writer is dropped first.
+    _lock: Option<Arc<File>>,
+}
+
+impl Bitcask {
+    /// Open the [Bitcask] at a given path. Return the [Bitcask].
+    ///  
+    /// This will create a new directory to store log files if the given one does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, BitcaskOptions::default())
+    }
+
+    /// Open the [Bitcask] at a given path, writing checksummed log records.
+    ///
+    /// See [BitcaskOptions::checksums].
+    pub fn open_checked(path: impl Into<PathBuf>) -> Result<Self> {
+        let options = BitcaskOptions {
+            checksums: true,
+            ..BitcaskOptions::default()
+        };
+        Self::open_with_options(path, options)
+    }
+
+    /// Open the [Bitcask] at a given path for reading only.
+    ///
+    /// See [BitcaskOptions::read_only].
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let options = BitcaskOptions {
+            read_only: true,
+            ..BitcaskOptions::default()
+        };
+        Self::open_with_options(path, options)
+    }
+
+    /// Open the [Bitcask] at a given path with the given [BitcaskOptions].
+    pub fn open_with_options(path: impl Into<PathBuf>, options: BitcaskOptions) -> Result<Self> {
+        options.log_naming.check()?;
+        if let Some(ratio) = options.compaction_ratio {
+            if !(ratio > 0.0 && ratio < 1.0) {
+                return Err(KvsError::StringError(format!(
+                    "Invalid compaction ratio {}, expected a fraction between 0 and 1",
+                    ratio
+                )));
+            }
+        }
+        if let (Some(stall), None) = (&options.write_stall, options.compaction_ratio) {
+            if stall.high_water_mark < options.compaction_threshold {
+                return Err(KvsError::StringError(format!(
+                    "Invalid write stall high water mark {}, expected at least the compaction threshold {}",
+                    stall.high_water_mark, options.compaction_threshold
+                )));
+            }
+        }
+        // open or create a directory to store log files
+        let dir = Arc::new(DataDir {
+            path: path.into(),
+            naming: options.log_naming.clone(),
+        });
+        let lock = if options.read_only {
+            None
+        } else {
+            fs::create_dir_all(&dir.path)?;
+            Some(Arc::new(lock_dir(&dir.path)?))
+        };
+
+        if let Some(min_free_space) = options.min_free_space {
+            let available = fs2::available_space(&dir.path)?;
+            if available < min_free_space {
+                return Err(KvsError::StringError(format!(
+                    "Not enough disk space for {:?}: {} bytes available, {} required",
+                    dir.path, available, min_free_space
+                )));
+            }
+        }
+
+        let mut readers = reader_cache(options.max_open_files);
+        let index = Arc::new(Index::new(options.ordered_index));
+
+        let fids = dir.sorted_fids()?;
+        let mut uncompacted = 0;
+        let mut version = 0;
+        let now = now_millis(options.clock.as_deref());
+        let mut log_lens = BTreeMap::new();
+
+        // Indexing and building cache of readers
+        for &fid in &fids {
+            log_lens.insert(fid, fs::metadata(dir.log_path(fid))?.len());
+            let mut reader = new_log_reader(&dir, fid)?;
+            uncompacted += match Self::load_hint(&dir, fid, &mut reader, &index, &mut version, now)
+            {
+                Ok(Some(uncompacted)) => uncompacted,
+                Ok(None) => Self::load(&dir, fid, &mut reader, &index, &mut version, &options)?,
+                Err(e) => {
+                    warn!("Replaying log {} as its hint file is unusable: {}", fid, e);
+                    Self::load(&dir, fid, &mut reader, &index, &mut version, &options)?
+                }
+            };
+            readers.put(fid, reader);
+        }
+
+        // Create a new log file which fid = (max of fids) + 1
+        let cur_fid = *fids.last().unwrap_or(&0) + 1;
+        let format = LogFormat {
+            encoding: options.encoding,
+            checksums: options.checksums,
+        };
+        let cur_writer = if options.read_only {
+            None
+        } else {
+            Some(new_log_writer(&dir, cur_fid, format)?)
+        };
+
+        let reader = Reader {
+            dir: Arc::clone(&dir),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+            scratch: RefCell::new(Vec::new()),
+            #[cfg(feature = "mmap")]
+            mmaps: options.mmap_reads.then(|| Mmaps {
+                // the last log file may still grow when the store is written elsewhere
+                active_fid: Arc::new(AtomicU64::new(if options.read_only {
+                    cur_fid - 1
+                } else {
+                    cur_fid
+                })),
+                maps: RefCell::new(HashMap::new()),
+            }),
+        };
+
+        let value_sizes = options.value_size_histogram.then(|| {
+            let mut histogram = ValueSizes::default();
+            index.for_each(|_, cmd_pos| histogram.add(cmd_pos));
+            histogram
+        });
+
+        let counters = Arc::new(Counters::default());
+        counters.uncompacted.store(uncompacted, Ordering::Relaxed);
+        let cache = options
+            .value_cache_bytes
+            .map(|capacity| Arc::new(ValueCache::new(capacity)));
+
+        let writer = Writer {
+            dir: Arc::clone(&dir),
+            reader: reader.clone(),
+            cur_writer,
+            cur_fid,
+            uncompacted,
+            sealed_bytes: log_lens.values().sum(),
+            log_lens,
+            version,
+            index: Arc::clone(&index),
+            cache: cache.clone(),
+            counters: Arc::clone(&counters),
+            value_sizes,
+            format,
+            compaction_threshold: options.compaction_threshold,
+            compaction_ratio: options.compaction_ratio,
+            sync: options.sync,
+            max_key_bytes: options.max_key_bytes,
+            max_value_bytes: options.max_value_bytes,
+            max_file_bytes: options.max_file_bytes,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            compaction: None,
+            on_compaction: options.on_compaction.clone(),
+            clock: options.clock.clone(),
+            coalesce_batches: options.coalesce_batches,
+        };
+
+        let mut store = Self {
+            reader,
+            cur_writer: Arc::new(Mutex::new(writer)),
+            index,
+            cache,
+            options: Arc::new(options),
+            counters,
+            sweeper: None,
+            _lock: lock,
+        };
+        match store.options.sweep_interval {
+            Some(interval) if !store.options.read_only => {
+                store.sweeper = Some(Arc::new(Sweeper::spawn(store.clone(), interval)?));
+            }
+            _ => {}
+        }
+        Ok(store)
+    }
+
+    /// The time keys expire against, see [BitcaskOptions::clock].
+    fn now(&self) -> u64 {
+        now_millis(self.options.clock.as_deref())
+    }
+
+    /// Lock the writer, or fail if the store is read-only.
+    fn writer(&self) -> Result<MutexGuard<'_, Writer>> {
+        self.check_writable()?;
+        Ok(self.cur_writer.lock().unwrap())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Append the set command `cmd`, checked and encoded before the writer is locked.
+    fn append_set(&self, cmd: Cmd) -> Result<()> {
+        self.check_writable()?;
+        if let Some((key_len, value_len)) = cmd.key_value_len() {
+            check_size(
+                self.options.max_key_bytes,
+                self.options.max_value_bytes,
+                key_len,
+                value_len,
+            )?;
+        }
+        let format = LogFormat::new(self.options.encoding, self.options.checksums);
+        let set = PreparedSet::new(cmd, format)?;
+        self.stall();
+        self.writer()?.append_prepared(set)
+    }
+
+    /// Returns the statistics of this [Bitcask].
+    pub fn stats(&self) -> Stats {
+        let writer = self.cur_writer.lock().unwrap();
+        let value_sizes = writer.value_sizes.as_ref().map(ValueSizes::to_vec);
+        // the writer lock keeps compaction from adding or deleting log files meanwhile
+        let num_log_files = writer.dir.sorted_fids().map_or_else(
+            |e| {
+                error!("Log files cannot be listed: {}", e);
+                0
+            },
+            |fids| fids.len(),
+        );
+        let (cache_hits, cache_misses) = self
+            .cache
+            .as_ref()
+            .map_or((0, 0), |cache| cache.hits_and_misses());
+
+        Stats {
+            num_keys: self.index.len(),
+            uncompacted_bytes: writer.uncompacted,
+            total_bytes: writer.total_bytes(),
+            num_log_files,
+            write_stall_time: Duration::from_nanos(
+                self.counters.write_stall_nanos.load(Ordering::Relaxed),
+            ),
+            value_sizes,
+            cache_hits,
+            cache_misses,
+        }
+    }
+
+    /// Returns the log files in the data directory, ordered by fid.
+    ///
+    /// While a compaction runs in the background, the files it is writing are listed
+    /// too, just before the active file. A read-only store has no active file.
+    pub fn log_files(&self) -> Result<Vec<LogFileInfo>> {
+        // the writer lock keeps compaction from adding or deleting log files meanwhile
+        let writer = self.cur_writer.lock().unwrap();
+        writer
+            .dir
+            .sorted_fids()?
+            .into_iter()
+            .map(|fid| {
+                Ok(match &writer.cur_writer {
+                    // the active log file may have buffered writes
+                    Some(log) if fid == writer.cur_fid => LogFileInfo {
+                        fid,
+                        bytes: log.pos,
+                        active: true,
+                    },
+                    _ => LogFileInfo {
+                        fid,
+                        bytes: fs::metadata(writer.dir.log_path(fid))?.len(),
+                        active: false,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Remove the expired keys now, writing a remove record for each, and return how
+    /// many were removed.
+    ///
+    /// Expired keys are invisible already, but their index entries take up memory
+    /// until a compaction drops them. The keys are removed in batches, each under the
+    /// writer lock, so that writes go on in between.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        let now = self.now();
+        let expired = self.index.filter_map(|key, cmd_pos| {
+            cmd_pos
+                .is_expired(now)
+                .then(|| (key.to_vec(), cmd_pos.version))
+        });
+        let mut removed = 0;
+        for batch in expired.chunks(SWEEP_BATCH) {
+            removed += self.writer()?.sweep(batch)?;
+        }
+        Ok(removed)
+    }
+
+    /// Estimate what a compaction would reclaim, without compacting.
+    ///
+    /// The log files are compared with the records of the live keys, which are all a
+    /// compaction keeps: expired keys count as stale. Nothing is written. While a
+    /// compaction runs in the background, its files count as stale until it finishes.
+    pub fn compaction_preview(&self) -> Result<CompactionReport> {
+        // the writer lock keeps compaction from adding or deleting log files meanwhile
+        let writer = self.cur_writer.lock().unwrap();
+        let mut total_bytes = 0;
+        for fid in writer.dir.sorted_fids()? {
+            total_bytes += match &writer.cur_writer {
+                // the active log file may have buffered writes
+                Some(log) if fid == writer.cur_fid => log.pos,
+                _ => fs::metadata(writer.dir.log_path(fid))?.len(),
+            };
+        }
+        drop(writer);
+
+        let now = self.now();
+        let mut live_bytes = 0;
+        self.index.for_each(|_, cmd_pos| {
+            if !cmd_pos.is_expired(now) {
+                live_bytes += cmd_pos.disk_len();
+            }
+        });
+        Ok(CompactionReport {
+            total_bytes,
+            live_bytes,
+            reclaimable_bytes: total_bytes.saturating_sub(live_bytes),
+        })
+    }
+
+    /// Copy a point-in-time snapshot of the store into the directory `dest`.
+    ///
+    /// The active log file is sealed and later writes go to a new one, so the snapshot
+    /// is made of files which no longer change. The writer lock is only held to open
+    /// them: writes and compactions go on while they are copied. `Bitcask::open(dest)`
+    /// then sees the store as it was when `backup` was called.
+    ///
+    /// ## Errors
+    ///
+    /// It fails if `dest` already holds log files.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = DataDir {
+            path: dest.as_ref().to_owned(),
+            naming: self.options.log_naming.clone(),
+        };
+        fs::create_dir_all(&dest.path)?;
+        if !dest.sorted_fids()?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{:?} already holds log files",
+                dest.path
+            )));
+        }
+
+        let files = self.cur_writer.lock().unwrap().snapshot()?;
+        for (mut file, path) in files {
+            let mut copy = File::create(dest.path.join(path.file_name().unwrap()))?;
+            io::copy(&mut file, &mut copy)?;
+            copy.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Block the calling write while compaction lags behind, see [WriteStall].
+    fn stall(&self) {
+        let stall = match &self.options.write_stall {
+            Some(stall) => stall,
+            None => return,
+        };
+        if self.counters.uncompacted.load(Ordering::Relaxed) <= stall.high_water_mark {
+            return;
+        }
+
+        let start = Instant::now();
+        while self.counters.uncompacted.load(Ordering::Relaxed) > stall.high_water_mark
+            && start.elapsed() < stall.max_stall
+        {
+            // stalled writers may be the only ones left to install a finished compaction
+            if let Err(e) = self.cur_writer.lock().unwrap().poll_compaction() {
+                error!("Compaction failed: {}", e);
+            }
+            thread::sleep(STALL_POLL_INTERVAL);
+        }
+        self.counters
+            .write_stall_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Compare two keys in the order of [BitcaskOptions::key_comparator].
+    fn compare_keys(&self, a: &str, b: &str) -> cmp::Ordering {
+        match &self.options.key_comparator {
+            Some(KeyComparator(f)) => f(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    /// Returns the key/value pairs whose keys fall into `range`, ordered by key.
+    ///
+    /// Keys are ordered by [BitcaskOptions::key_comparator], and so are the bounds of
+    /// `range`. The index is not ordered, so every scan sorts the matching keys.
+    /// Keys removed while scanning are left out, and so are binary keys which are not
+    /// valid UTF-8, see [Bitcask::set_bytes].
+    pub fn scan<'a, R: RangeBounds<&'a str>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let in_range = |key: &str| {
+            let after_start = match range.start_bound() {
+                Bound::Included(start) => self.compare_keys(key, start).is_ge(),
+                Bound::Excluded(start) => self.compare_keys(key, start).is_gt(),
+                Bound::Unbounded => true,
+            };
+            let before_end = match range.end_bound() {
+                Bound::Included(end) => self.compare_keys(key, end).is_le(),
+                Bound::Excluded(end) => self.compare_keys(key, end).is_lt(),
+                Bound::Unbounded => true,
+            };
+            after_start && before_end
+        };
+
+        self.sorted_pairs(in_range)
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, ordered by key.
+    ///
+    /// Keys are ordered by [BitcaskOptions::key_comparator]. Values are read from the
+    /// log one by one, so a compaction running meanwhile does not invalidate the
+    /// result. Keys removed while scanning and binary keys are left out.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.sorted_pairs(|key| key.starts_with(prefix))
+    }
+
+    /// Read the key/value pairs whose keys match `filter`, ordered by key.
+    fn sorted_pairs(&self, filter: impl Fn(&str) -> bool) -> Result<Vec<(String, String)>> {
+        self.read_pairs(self.sorted_keys(filter))
+    }
+
+    /// The UTF-8 keys matching `filter`, ordered by key.
+    fn sorted_keys(&self, filter: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut keys = self.index.filter_map(|key, _| {
+            std::str::from_utf8(key)
+                .ok()
+                .filter(|key| filter(key))
+                .map(str::to_owned)
+        });
+        keys.sort_unstable_by(|a, b| self.compare_keys(a, b));
+        keys
+    }
+
+    /// Read the values of `keys`, leaving out the keys which are gone.
+    fn read_pairs(&self, keys: impl IntoIterator<Item = String>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for key in keys {
+            // looked up again, as the key may have moved or gone since it was listed
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Returns the key/value pairs whose keys lie between `start` and `end`, ordered by
+    /// the bytes of the keys.
+    ///
+    /// Unlike [Bitcask::scan], [BitcaskOptions::key_comparator] is ignored, so that
+    /// with [BitcaskOptions::ordered_index] the keys in range are walked in order,
+    /// e.g. `get_range(Bound::Included(x), Bound::Unbounded)` starts at the smallest
+    /// key `>= x`. Otherwise, every call sorts the matching keys. Keys removed while
+    /// reading and binary keys are left out.
+    pub fn get_range(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> Result<Vec<(String, String)>> {
+        let keys = self.index.range(
+            start.as_ref().map(|key| key.as_bytes()),
+            end.as_ref().map(|key| key.as_bytes()),
+        );
+        self.read_pairs(
+            keys.into_iter()
+                .filter_map(|key| String::from_utf8(key).ok()),
+        )
+    }
+
+    /// Returns all keys, in no particular order.
+    ///
+    /// The keys are copied out of the index before returning, so the caller may write
+    /// to the store while going through them. Writes made while the keys are being
+    /// copied may or may not be reflected. Expired keys and binary keys which are not
+    /// valid UTF-8 are left out.
+    pub fn keys(&self) -> Vec<String> {
+        let now = self.now();
+        self.index.filter_map(|key, cmd_pos| {
+            if cmd_pos.is_expired(now) {
+                return None;
+            }
+            std::str::from_utf8(key).ok().map(str::to_owned)
+        })
+    }
+
+    /// Write all key/value pairs to `w`, as a logical dump for [Bitcask::import].
+    ///
+    /// Each pair is written as the key then the value, both prefixed with their length
+    /// as a little-endian `u32`. Unlike the log files, the dump does not depend on the
+    /// [Encoding] or on [BitcaskOptions::checksums]. Only the keys are listed up front:
+    /// values are read from the log one by one while writing, so memory stays bounded.
+    /// Keys removed meanwhile are left out, and TTLs are not kept.
+    pub fn export(&self, w: impl Write) -> Result<()> {
+        let now = self.now();
+        let keys = self
+            .index
+            .filter_map(|key, cmd_pos| (!cmd_pos.is_expired(now)).then(|| key.to_vec()));
+
+        let mut w = BufWriter::new(w);
+        for key in keys {
+            // looked up again, as the key may have moved or gone since it was listed
+            if let Some(value) = self.get_bytes(&key)? {
+                write_exported(&mut w, &key)?;
+                write_exported(&mut w, &value)?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Set all key/value pairs of a dump written by [Bitcask::export].
+    ///
+    /// Pairs are set one by one, so the pairs before an error, e.g. a truncated dump,
+    /// stay set.
+    pub fn import(&self, r: impl Read) -> Result<()> {
+        let mut r = BufReader::new(r);
+        while !r.fill_buf()?.is_empty() {
+            let key = read_exported(&mut r)?;
+            let value = read_exported(&mut r)?;
+            self.set_bytes(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the smallest key, ordered by [BitcaskOptions::key_comparator].
+    ///
+    /// Binary keys which are not valid UTF-8 are left out.
+    pub fn first_key(&self) -> Option<String> {
+        self.keys()
+            .into_iter()
+            .min_by(|a, b| self.compare_keys(a, b))
+    }
+
+    /// Returns the largest key, ordered by [BitcaskOptions::key_comparator].
+    ///
+    /// Binary keys which are not valid UTF-8 are left out.
+    pub fn last_key(&self) -> Option<String> {
+        self.keys()
+            .into_iter()
+            .max_by(|a, b| self.compare_keys(a, b))
+    }
+
+    /// Set the value of a string key which expires after `ttl`.
+    ///
+    /// Once expired, the key reads as absent and its value is dropped by the next
+    /// compaction. Setting the key again, with or without a TTL, replaces the expiry.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expire_at = self.now().saturating_add(ttl.as_millis() as u64);
+        self.append_set(Cmd::SetEx {
+            key,
+            expire_at,
+            value,
+        })
+    }
+
+    /// Set the value of a binary key to binary data.
+    ///
+    /// Binary keys share their namespace with string keys, which are their UTF-8
+    /// bytes: `get` reads a value set here, failing with [KvsError::Utf8] if the
+    /// value is not valid UTF-8, and `get_bytes` reads a value set by `set`.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.append_set(Cmd::set_bytes(key, value))
+    }
+
+    /// Get the binary value of a given binary key, see [Bitcask::set_bytes].
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.read_live(key, |cmd_pos| self.reader.read_bytes(cmd_pos))?;
+        Ok(value.map(|(_, value)| value))
+    }
+
+    /// Get the string value of a given key into `buf`, replacing its contents, and
+    /// return whether the key exists. `buf` is left empty if it does not.
+    ///
+    /// Unlike `get`, this reuses the allocation of `buf`, so a loop of reads into the
+    /// same buffer allocates nothing once it holds the largest value. The value cache
+    /// is not consulted, see [BitcaskOptions::value_cache_bytes].
+    pub fn get_into(&self, key: &str, buf: &mut String) -> Result<bool> {
+        buf.clear();
+        let found = self.read_live(key.as_bytes(), |cmd_pos| {
+            self.reader.read_into(cmd_pos, buf)
+        })?;
+        Ok(found.is_some())
+    }
+
+    /// Remove a given binary key, see [Bitcask::set_bytes].
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn rm_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.stall();
+        self.writer()?.rm(key)
+    }
+
+    /// Look up the position of `key`, unless the key is absent or expired.
+    fn live(&self, key: &[u8]) -> Option<CmdPos> {
+        self.index
+            .get(key)
+            .filter(|cmd_pos| !cmd_pos.is_expired(self.now()))
+    }
+
+    /// Look up `key` and read its value with `read`, unless the key is absent or expired.
+    ///
+    /// The index is not locked while reading, so a compaction may finish meanwhile and
+    /// delete the log file the key was found in. The key then moved to a compaction
+    /// file, and is looked up again.
+    fn read_live<T>(
+        &self,
+        key: &[u8],
+        mut read: impl FnMut(&CmdPos) -> Result<T>,
+    ) -> Result<Option<(CmdPos, T)>> {
+        loop {
+            let cmd_pos = match self.live(key) {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            match read(&cmd_pos) {
+                Err(_) if self.reader.is_stale(&cmd_pos) => continue,
+                res => return res.map(|value| Some((cmd_pos, value))),
+            }
+        }
+    }
+
+    /// Set and remove several keys together, or not at all, see [KvsEngine::write_batch].
+    ///
+    /// All operations are written to the log first, and the index is only updated once
+    /// the whole transaction is in the log. If the write fails partway, none of the
+    /// operations reach the index: the records already written count as stale bytes for
+    /// the next compaction, and later writes go to a new log file, so that the torn
+    /// transaction ends its log file and is ignored when the store is opened again.
+    ///
+    /// Whether a transaction survives a crash after it returned depends on the
+    /// [SyncPolicy]: only [SyncPolicy::EveryWrite] syncs it before returning.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if a removed key does not exist, in which
+    /// case nothing is written.
+    pub fn transaction(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.stall();
+        self.writer()?.write_batch(ops)
+    }
+
+    /// Get the value of a given key together with its version.
+    ///
+    /// The version is bumped on every write to the key, so two reads returning
+    /// the same version are guaranteed to have observed the same write.
+    /// Versions are only comparable within one opened [Bitcask].
+    pub fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        let value = self.read_live(key.as_bytes(), |cmd_pos| self.reader.read_command(cmd_pos))?;
+        Ok(value.and_then(|(cmd_pos, value)| Some((value?, cmd_pos.version))))
+    }
+
+    /// Get the value of a given key together with where it is stored.
+    ///
+    /// This is meant for debugging, e.g. to check that a compaction moved the value. The
+    /// location is only valid until the next compaction. Records carry no time of
+    /// writing, so only the expiry of the key is known.
+    pub fn get_with_meta(&self, key: String) -> Result<Option<(String, ValueMeta)>> {
+        let value = self.read_live(key.as_bytes(), |cmd_pos| self.reader.read_command(cmd_pos))?;
+        Ok(value.and_then(|(cmd_pos, value)| {
+            let meta = ValueMeta {
+                fid: cmd_pos.fid,
+                pos: cmd_pos.pos,
+                len: cmd_pos.len,
+                checksummed: cmd_pos.format.checksums,
+                expire_at: cmd_pos.expire_at,
+                version: cmd_pos.version,
+            };
+            Some((value?, meta))
+        }))
+    }
+
+    /// Set the value of a string key, returning its previous value if it had one.
+    ///
+    /// The previous value is read under the writer lock, so unlike a `get` before the
+    /// `set`, no other write can slip in between.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::Utf8` if the previous value is binary, in which case
+    /// nothing is written.
+    pub fn set_returning_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.stall();
+        self.writer()?.set_returning_old(key, value)
+    }
+
+    /// Remove a given key, returning its value, like [Bitcask::set_returning_old].
+    ///
+    /// Unlike `rm`, an absent key is not an error: nothing is written and `None` is
+    /// returned.
+    pub fn rm_returning_old(&self, key: String) -> Result<Option<String>> {
+        self.stall();
+        self.writer()?.rm_returning_old(key)
+    }
+
+    /// Move the value of `from` to `to`, replacing any value of `to`, atomically.
+    ///
+    /// The value is read under the writer lock and written back as a batch which sets
+    /// `to` and removes `from`, so other writes cannot slip in between, and after a
+    /// crash the store has either both keys as before or the renamed one only. An
+    /// expiry of `from` is not carried over.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `from` does not exist, and
+    /// `KvsError::Utf8` if its value is binary. Nothing is written then.
+    pub fn rename(&self, from: String, to: String) -> Result<()> {
+        self.stall();
+        self.writer()?.rename(from, to)
+    }
+
+    /// Store the value locations of log file `fid` in the index map from its hint file,
+    /// which is much faster than replaying the log file.
+    ///
+    /// Keys expired at `now` are left out. Returns `None` if there is no hint file,
+    /// otherwise how many bytes can be saved after a compaction. The index is left unchanged if the hint file is invalid.
+    fn load_hint(
+        dir: &DataDir,
+        fid: u64,
+        reader: &mut BufReaderWithPos<File>,
+        index: &Index,
+        version: &mut u64,
+        now: u64,
+    ) -> Result<Option<u64>> {
+        let file = match File::open(dir.hint_path(fid)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let hints = Deserializer::from_reader(BufReader::new(file))
+            .into_iter::<Hint>()
+            .collect::<serde_json::Result<Vec<_>>>()?;
+
+        let format = read_magic(reader)?;
+        let log_len = fs::metadata(dir.log_path(fid))?.len();
+        if hints.iter().any(|hint| hint.pos + hint.len > log_len) {
+            return Err(KvsError::StringError(format!(
+                "hint file of log {} points past its end",
+                fid
+            )));
+        }
+
+        let mut uncompacted = 0;
+        for hint in hints {
+            *version += 1;
+            let cmd_pos = CmdPos {
+                fid,
+                pos: hint.pos,
+                len: hint.len,
+                version: *version,
+                value_offset: hint.value_offset,
+                expire_at: hint.expire_at,
+                format,
+                bytes: hint.bytes,
+            };
+            if cmd_pos.is_expired(now) {
+                uncompacted += cmd_pos.disk_len();
+                continue;
+            }
+            uncompacted += index
+                .insert(hint.key, cmd_pos)
+                .map_or(0, |old_cmd| old_cmd.disk_len());
+        }
+        Ok(Some(uncompacted))
+    }
+
+    /// Load the whole log file and store value locations in the index map.
+    ///
+    /// `version` is bumped for every `set` command replayed. A checksummed log file
+    /// is truncated after its last valid record, unless the store is read-only.
+    ///
+    /// Returns how many bytes can be saved after a compaction.
+    fn load(
+        dir: &DataDir,
+        fid: u64,
+        reader: &mut BufReaderWithPos<File>,
+        index: &Index,
+        version: &mut u64,
+        options: &BitcaskOptions,
+    ) -> Result<u64> {
+        let mut uncompacted = 0;
+        let (format, mut records) = log_records(fid, reader)?;
+        // bytes of a record besides its command
+        let frame_len = if format.checksums {
+            FRAME_HEADER_LEN
+        } else {
+            0
+        };
+
+        // Index `cmd`, written at `range`, and return the bytes it made stale.
+        let now = now_millis(options.clock.as_deref());
+        let mut apply = |cmd: Cmd, range: Range<u64>| {
+            match cmd {
+                Cmd::Set { .. } | Cmd::SetEx { .. } | Cmd::SetBytes { .. } => {}
+                Cmd::Rm { .. } | Cmd::RmBytes { .. } => {
+                    let old_len = index
+                        .remove(&cmd.into_key())
+                        .map_or(0, |old_cmd| old_cmd.disk_len());
+                    // the "remove" command itself can be deleted in the next compaction.
+                    // so we add its length to `uncompacted`.
+                    return Ok(old_len + range.end - range.start + frame_len);
+                }
+                Cmd::Batch { .. } => return Err(KvsError::Unknown),
+            }
+
+            *version += 1;
+            let cmd_pos = CmdPos::set(fid, range, &cmd, *version, format);
+            if cmd_pos.is_expired(now) {
+                // already expired: it hides any older value, but is not indexed itself.
+                let old_len = index
+                    .remove(&cmd.into_key())
+                    .map_or(0, |old_cmd| old_cmd.disk_len());
+                return Ok(old_len + cmd_pos.disk_len());
+            }
+            Ok(index
+                .insert(cmd.into_key(), cmd_pos)
+                .map_or(0, |old_cmd| old_cmd.disk_len()))
+        };
+
+        let is_torn = |record: &Result<Record>| is_torn(format, record);
+
+        // where the log file has to be cut
+        let mut truncate_at = None;
+        // the end of the last complete record
+        let mut end = format.magic().map_or(0, |magic| magic.len() as u64);
+        // indexing
+        while let Some(record) = records.next() {
+            if is_torn(&record) {
+                truncate_at = Some(match record {
+                    Err(KvsError::ChecksumMismatch { pos, .. }) => pos,
+                    _ => end,
+                });
+                break;
+            }
+            let (cmd, range) = record?;
+            match cmd {
+                Cmd::Batch { len } => {
+                    let mut batch = Vec::with_capacity(len);
+                    while batch.len() < len {
+                        match records.next() {
+                            Some(record) if is_torn(&record) => break,
+                            Some(record) => batch.push(record?),
+                            None => break,
+                        }
+                    }
+                    if batch.len() < len {
+                        warn!("Ignoring an incomplete batch at the end of log {}", fid);
+                        truncate_at = Some(range.start - frame_len);
+                        break;
+                    }
+                    end = batch.last().map_or(range.end, |(_, range)| range.end);
+                    // the header itself can be deleted in the next compaction.
+                    uncompacted += range.end - range.start + frame_len;
+                    for (cmd, range) in batch {
+                        uncompacted += apply(cmd, range)?;
+                    }
+                }
+                cmd => {
+                    end = range.end;
+                    uncompacted += apply(cmd, range)?;
+                }
+            }
+        }
+        drop(records);
+
+        match truncate_at {
+            Some(pos) if options.read_only => warn!(
+                "Ignoring log {} after its last valid record, at byte {}",
+                fid, pos
+            ),
+            Some(pos) => {
+                warn!(
+                    "Truncating log {} to its last valid record, at byte {}",
+                    fid, pos
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(dir.log_path(fid))?
+                    .set_len(pos)?;
+            }
+            None => {}
+        }
+
+        Ok(uncompacted)
+    }
+}
+
+impl KvsEngine for Bitcask {
+    /// Set the value of a string key to a string
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.append_set(Cmd::set(key, value))
+    }
+
+    /// Get the string value of a given string key
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let read = |cmd_pos: &CmdPos| {
+            let cache = match &self.cache {
+                Some(cache) => cache,
+                None => return self.reader.read_command(cmd_pos),
+            };
+            if let Some(value) = cache.get(key.as_bytes(), cmd_pos.version) {
+                return Ok(Some(value));
+            }
+            let value = self.reader.read_command(cmd_pos)?;
+            if let Some(value) = &value {
+                cache.insert(key.as_bytes().to_vec(), cmd_pos.version, value.clone());
+            }
+            Ok(value)
+        };
+        let value = self.read_live(key.as_bytes(), read)?;
+        Ok(value.and_then(|(_, value)| value))
+    }
+
+    /// Get the values of `keys`, in the same order.
+    ///
+    /// All keys are looked up first, then the values are read in log order, file by
+    /// file, so that values close to each other are read from the same buffer.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut cmd_positions: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| Some((i, self.live(key.as_bytes())?)))
+            .collect();
+        cmd_positions.sort_unstable_by_key(|(_, cmd_pos)| (cmd_pos.fid, cmd_pos.pos));
+
+        let mut values = vec![None; keys.len()];
+        for (i, cmd_pos) in cmd_positions {
+            values[i] = match self.reader.read_command(&cmd_pos) {
+                // the key moved, see `Bitcask::read_live`
+                Err(_) if self.reader.is_stale(&cmd_pos) => self.get(keys[i].clone())?,
+                res => res?,
+            };
+        }
+        Ok(values)
+    }
+
+    /// Whether the given key exists, answered from the index without reading the log.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.live(key.as_bytes()).is_some())
+    }
+
+    /// The number of keys in the index, like [Stats::num_keys].
+    ///
+    /// This does not go through the keys, so it includes keys which expired but
+    /// were not compacted yet, and binary keys.
+    fn len(&self) -> Result<usize> {
+        Ok(self.index.len())
+    }
+
+    /// Remove a given key
+    ///   
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn rm(&self, key: String) -> Result<()> {
+        self.stall();
+        self.writer()?.rm(key.into_bytes())
+    }
+
+    /// Atomically replace the value of `key` with `new` if it currently is `expected`.
+    ///
+    /// The value is read and compared under the writer lock, so no other write can
+    /// slip in between. A binary value never equals a `expected` string.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.stall();
+        self.writer()?.compare_and_swap(key, expected, new)
+    }
+
+    /// Get the value of `key`, first setting it to `default()` if the key does not exist.
+    ///
+    /// A missing key is checked again and set under the writer lock, so `default`
+    /// runs at most once and no other write can slip in between.
+    fn get_or_set(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        self.stall();
+        self.writer()?.get_or_set(key, default)
+    }
+
+    /// Apply a batch of writes atomically, in order.
+    ///
+    /// The batch is written to the log behind a single header, with one write and one
+    /// flush for all operations. This makes it much faster than one `set` per key for
+    /// bulk loads.
+    /// When the store is opened again, a batch which was not completely written,
+    /// e.g. because of a crash, is ignored as a whole.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if a removed key does not exist, in which
+    /// case nothing is written.
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.transaction(ops)
+    }
+
+    /// Only the matching keys are listed and sorted up front, as in
+    /// [Bitcask::scan_prefix], and their values are read from the log one by one as
+    /// `f` is called. Keys removed meanwhile and binary keys are left out.
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        mut f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        for key in self.sorted_keys(|key| key.starts_with(&prefix)) {
+            // looked up again, as the key may have moved or gone since it was listed
+            if let Some(value) = self.get(key.clone())? {
+                f(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all keys.
+    ///
+    /// The writes go to a new, empty log file and all older log and hint files are
+    /// deleted, so that the store is still empty when opened again.
+    fn clear(&self) -> Result<()> {
+        let mut writer = self.writer()?;
+        let res = writer.clear();
+        writer
+            .counters
+            .uncompacted
+            .store(writer.uncompacted, Ordering::Relaxed);
+        res
+    }
+
+    /// Compact the log files now, whatever the number of stale bytes.
+    fn compact(&self) -> Result<()> {
+        let mut writer = self.writer()?;
+        let res = writer.compact();
+        writer
+            .counters
+            .uncompacted
+            .store(writer.uncompacted, Ordering::Relaxed);
+        res
+    }
+
+    /// Like [Stats::uncompacted_bytes], but without locking the writer.
+    fn uncompacted_bytes(&self) -> Option<u64> {
+        Some(self.counters.uncompacted.load(Ordering::Relaxed))
+    }
+}
+
+/// A per-handle cache of log file readers.
+struct Reader {
+    dir: Arc<DataDir>,
+    // generation file number of the latest compaction file
+    safe_point: Arc<AtomicU64>,
+    /// Open log files, at most [BitcaskOptions::max_open_files] of them.
+    readers: RefCell<LruCache<u64, BufReaderWithPos<File>>>,
+    /// Buffer values are read into before they are deserialized, reused across reads.
+    scratch: RefCell<Vec<u8>>,
+    /// Memory maps of sealed log files, if [BitcaskOptions::mmap_reads] is set.
+    #[cfg(feature = "mmap")]
+    mmaps: Option<Mmaps>,
+}
+
+/// Memory maps of the sealed log files, see [BitcaskOptions::mmap_reads].
+#[cfg(feature = "mmap")]
+struct Mmaps {
+    /// fid of the active log file. Files with a smaller fid are never written again.
+    active_fid: Arc<AtomicU64>,
+    maps: RefCell<HashMap<u64, memmap2::Mmap>>,
+}
+
+impl Reader {
+    /// Whether `cmd_pos` is in a log file which a compaction replaced, so that the
+    /// file may be gone.
+    fn is_stale(&self, cmd_pos: &CmdPos) -> bool {
+        cmd_pos.fid < self.safe_point.load(Ordering::SeqCst)
+    }
+
+    /// Close file handles with generation file number less than safe_point.
+    ///
+    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
+    /// The compaction generation contains the sum of all operations before it and the
+    /// in-memory index contains no entries with generation number less than safe_point.
+    /// So we can safely close those file handles and the stale files can be deleted.
+    fn close_stale_handles(&self) {
+        // a compaction may write several files, which all are at or after the safe point
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        let stale: Vec<u64> = readers
+            .iter()
+            .map(|(&fid, _)| fid)
+            .filter(|&fid| fid < safe_point)
+            .collect();
+        for fid in stale {
+            readers.pop(&fid);
+        }
+
+        #[cfg(feature = "mmap")]
+        if let Some(mmaps) = &self.mmaps {
+            mmaps.maps.borrow_mut().retain(|&fid, _| fid >= safe_point);
+        }
+    }
+
+    /// First Call `close_stale_handles`. Then Read the bytes at `range` of log file `fid`
+    /// and apply `f` to them.
+    fn read_and<F, R>(&self, fid: u64, range: Range<u64>, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+
+        // Open the file if we haven't opened it in this `Reader`, closing the least
+        // recently used one if there are too many open.
+        let reader_with_pos = readers.try_get_or_insert_mut(fid, || {
+            BufReaderWithPos::new(File::open(self.dir.log_path(fid))?)
+        })?;
+
+        reader_with_pos.seek_to(range.start)?;
+        // cmd_reader read up to the end of `range`
+        let cmd_reader = reader_with_pos.take(range.end - range.start);
+        f(cmd_reader)
+    }
+
+    // Read the value of the `set` command on the disk.
+    //
+    // Only the value is deserialized: its position within the command is known, so the
+    // key, which the caller already has, is neither parsed nor allocated again.
+    fn read_command(&self, cmd_pos: &CmdPos) -> Result<Option<String>> {
+        Ok(Some(self.read_value(cmd_pos)?.into_string()?))
+    }
+
+    /// Same as `read_command`, but for a binary value.
+    fn read_bytes(&self, cmd_pos: &CmdPos) -> Result<Vec<u8>> {
+        Ok(self.read_value(cmd_pos)?.into_bytes())
+    }
+
+    /// Same as `read_command`, but replace the contents of `buf` with the value.
+    ///
+    /// The value is deserialized straight from the log bytes when it needs no
+    /// unescaping, so that a read allocates nothing once `buf` is large enough.
+    fn read_into(&self, cmd_pos: &CmdPos, buf: &mut String) -> Result<()> {
+        let codec = cmd_pos.format.encoding.codec();
+        self.with_value_bytes(cmd_pos, |bytes| {
+            let value = codec.decode_str(bytes, cmd_pos.bytes)?;
+            buf.clear();
+            buf.push_str(&value);
+            Ok(())
+        })
+    }
+
+    /// Deserialize the value of the `set` command at `cmd_pos`.
+    fn read_value(&self, cmd_pos: &CmdPos) -> Result<Value> {
+        let codec = cmd_pos.format.encoding.codec();
+        self.with_value_bytes(cmd_pos, |bytes| codec.decode_value(bytes, cmd_pos.bytes))
+    }
+
+    /// Apply `f` to the serialized value of the `set` command at `cmd_pos`.
+    ///
+    /// The bytes are borrowed from the memory map of a sealed log file, or read into
+    /// the scratch buffer of this handle.
+    fn with_value_bytes<R>(
+        &self,
+        cmd_pos: &CmdPos,
+        f: impl FnOnce(&[u8]) -> Result<R>,
+    ) -> Result<R> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmaps) = &self.mmaps {
+            if cmd_pos.fid < mmaps.active_fid.load(Ordering::SeqCst) {
+                return self.with_mapped_bytes(mmaps, cmd_pos, f);
+            }
+        }
+
+        let mut scratch = self.scratch.borrow_mut();
+        scratch.clear();
+        let res = if cmd_pos.format.checksums {
+            // the whole record is needed to verify it
+            let range = cmd_pos.frame();
+            self.read_and(cmd_pos.fid, range.clone(), |mut reader| {
+                Ok(reader.read_to_end(&mut scratch)?)
+            })
+            .and_then(|_| check_frame(cmd_pos.fid, range.start, &scratch))
+            .and_then(|cmd| f(cmd_pos.value_of(cmd)))
+        } else {
+            self.read_and(cmd_pos.fid, cmd_pos.value(), |mut reader| {
+                Ok(reader.read_to_end(&mut scratch)?)
+            })
+            .and_then(|_| f(&scratch))
+        };
+        // a single large value should not stay allocated for good
+        if scratch.capacity() > MAX_SCRATCH_BYTES {
+            *scratch = Vec::new();
+        }
+        res
+    }
+
+    /// Read the serialized command at `cmd_pos`, verifying its checksum if it has one.
+    fn read_record(&self, cmd_pos: &CmdPos) -> Result<Vec<u8>> {
+        let range = cmd_pos.frame();
+        let mut frame = Vec::with_capacity((range.end - range.start) as usize);
+        self.read_and(cmd_pos.fid, range, |mut reader| {
+            Ok(reader.read_to_end(&mut frame)?)
+        })?;
+        if cmd_pos.format.checksums {
+            check_frame(cmd_pos.fid, cmd_pos.frame().start, &frame)?;
+            frame.drain(..FRAME_HEADER_LEN as usize);
+        }
+        Ok(frame)
+    }
+
+    /// Same as `with_value_bytes`, but borrow the value from the memory map of its
+    /// sealed log file.
+    #[cfg(feature = "mmap")]
+    fn with_mapped_bytes<R>(
+        &self,
+        mmaps: &Mmaps,
+        cmd_pos: &CmdPos,
+        f: impl FnOnce(&[u8]) -> Result<R>,
+    ) -> Result<R> {
+        self.close_stale_handles();
+
+        let mut maps = mmaps.maps.borrow_mut();
+        let map = match maps.entry(cmd_pos.fid) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let file = File::open(self.dir.log_path(cmd_pos.fid))?;
+                // SAFETY: sealed log files are never written again, and compaction only
+                // deletes them, which leaves existing mappings intact.
+                entry.insert(unsafe { memmap2::Mmap::map(&file)? })
+            }
+        };
+
+        if cmd_pos.format.checksums {
+            let range = cmd_pos.frame();
+            let frame = map
+                .get(range.start as usize..range.end as usize)
+                .ok_or(KvsError::Unknown)?;
+            let cmd = check_frame(cmd_pos.fid, range.start, frame)?;
+            return f(cmd_pos.value_of(cmd));
+        }
+        let value = cmd_pos.value();
+        let bytes = map
+            .get(value.start as usize..value.end as usize)
+            .ok_or(KvsError::Unknown)?;
+        f(bytes)
+    }
+}
+
+impl Clone for Reader {
+    fn clone(&self) -> Self {
+        Self {
+            dir: Arc::clone(&self.dir),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: RefCell::new(reader_cache(Some(self.readers.borrow().cap().get()))),
+            scratch: RefCell::new(Vec::new()),
+            #[cfg(feature = "mmap")]
+            mmaps: self.mmaps.as_ref().map(|mmaps| Mmaps {
+                active_fid: Arc::clone(&mmaps.active_fid),
+                maps: RefCell::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+/// An empty cache of log file readers, holding at most `max_open_files` of them.
+fn reader_cache(max_open_files: Option<usize>) -> LruCache<u64, BufReaderWithPos<File>> {
+    match max_open_files {
+        // an unbounded cache has a capacity of `usize::MAX`, which must not be allocated
+        Some(max) if max < usize::MAX => {
+            LruCache::new(NonZeroUsize::new(max).unwrap_or(NonZeroUsize::MIN))
+        }
+        _ => LruCache::unbounded(),
+    }
+}
+
+/// The background thread of [BitcaskOptions::sweep_interval].
+struct Sweeper {
+    /// Set to stop the thread, which waits on the condition variable in between sweeps.
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /// Sweep `store` every `interval`. The store must not hold the sweeper itself, so
+    /// that dropping the last handle of the store stops it.
+    fn spawn(store: Bitcask, interval: Duration) -> Result<Self> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("bitcask-sweeper".to_owned())
+                .spawn(move || loop {
+                    let (stopped, wake) = &*stop;
+                    let stopped = wake
+                        .wait_timeout_while(stopped.lock().unwrap(), interval, |stopped| !*stopped)
+                        .unwrap()
+                        .0;
+                    if *stopped {
+                        break;
+                    }
+                    drop(stopped);
+                    match store.sweep_expired() {
+                        Ok(0) => {}
+                        Ok(removed) => info!("Swept {} expired keys", removed),
+                        Err(e) => error!("Sweeping expired keys failed: {}", e),
+                    }
+                })?
+        };
+        Ok(Sweeper {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_one();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The sweeper thread panicked");
+            }
+        }
+    }
+}
+
+/// The single writer appending commands to the active log file.
+struct Writer {
+    dir: Arc<DataDir>,
+    reader: Reader,
+    /// The active log file, `None` if the store is read-only.
+    cur_writer: Option<BufWriterWithPos<File>>,
+    cur_fid: u64,
+    /// The number of bytes representing "stale" commands that could be
+    /// deleted during a compaction.
+    uncompacted: u64,
+    /// The length of each sealed log file, kept up to date so that
+    /// [Writer::total_bytes] needs no `stat`.
+    log_lens: BTreeMap<u64, u64>,
+    /// The sum of `log_lens`.
+    sealed_bytes: u64,
+    /// The version handed to the latest `set`.
+    version: u64,
+    index: Arc<Index>,
+    cache: Option<Arc<ValueCache>>,
+    counters: Arc<Counters>,
+    /// Histogram of live value sizes, if enabled.
+    value_sizes: Option<ValueSizes>,
+    /// The format of the log files written by this writer.
+    format: LogFormat,
+    /// See [BitcaskOptions::compaction_threshold].
+    compaction_threshold: u64,
+    /// See [BitcaskOptions::compaction_ratio].
+    compaction_ratio: Option<f64>,
+    /// See [BitcaskOptions::sync].
+    sync: SyncPolicy,
+    /// See [BitcaskOptions::max_key_bytes].
+    max_key_bytes: Option<usize>,
+    /// See [BitcaskOptions::max_value_bytes].
+    max_value_bytes: Option<usize>,
+    /// See [BitcaskOptions::max_file_bytes].
+    max_file_bytes: Option<u64>,
+    /// Writes since the active log file was last synced.
+    unsynced: u64,
+    last_sync: Instant,
+    /// The compaction running in the background, if any.
+    compaction: Option<Compaction>,
+    /// See [BitcaskOptions::on_compaction].
+    on_compaction: Option<CompactionHook>,
+    /// See [BitcaskOptions::clock].
+    clock: Option<Arc<dyn Clock>>,
+    /// See [BitcaskOptions::coalesce_batches].
+    coalesce_batches: bool,
+}
+
+impl Writer {
+    fn now(&self) -> u64 {
+        now_millis(self.clock.as_deref())
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.append_set(Cmd::set(key, value))
+    }
+
+    /// Append `cmd` to the active log file and return where the command was written.
+    fn append(&mut self, cmd: &Cmd) -> Result<Range<u64>> {
+        let (buf, range) = encode_cmd(cmd, self.format)?;
+        self.append_encoded(&buf, range)
+    }
+
+    /// Append the record `buf`, holding a command at `range`, and return where the
+    /// command was written.
+    fn append_encoded(&mut self, buf: &[u8], range: Range<u64>) -> Result<Range<u64>> {
+        self.roll_if_full(buf.len() as u64)?;
+        let log = self.active_log()?;
+        let pos = log.pos;
+        log.write_all(buf)?;
+        log.flush()?;
+        self.maybe_sync()?;
+        Ok(pos + range.start..pos + range.end)
+    }
+
+    fn active_log(&mut self) -> Result<&mut BufWriterWithPos<File>> {
+        self.cur_writer.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    /// Flush the active log file and, unless the [SyncPolicy] is `None`, sync the writes
+    /// [Writer::maybe_sync] left unsynced, so that a clean shutdown loses none of them.
+    fn close(&mut self) -> Result<()> {
+        let sync = self.sync != SyncPolicy::None && self.unsynced > 0;
+        if let Some(log) = &mut self.cur_writer {
+            if sync {
+                log.sync()?;
+                self.unsynced = 0;
+            } else {
+                log.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync the active log file after a write if the [SyncPolicy] asks for it.
+    fn maybe_sync(&mut self) -> Result<()> {
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncPolicy::None => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.active_log()?.sync()?;
+            self.unsynced = 0;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Append a `Cmd::Set`, `Cmd::SetEx` or `Cmd::SetBytes` and index it.
+    fn append_set(&mut self, cmd: Cmd) -> Result<()> {
+        if let Some((key_len, value_len)) = cmd.key_value_len() {
+            self.check_size(key_len, value_len)?;
+        }
+        self.append_prepared(PreparedSet::new(cmd, self.format)?)
+    }
+
+    /// Append a set command encoded in advance and index it.
+    fn append_prepared(&mut self, set: PreparedSet) -> Result<()> {
+        debug_assert_eq!(set.format, self.format);
+        let range = self.append_encoded(&set.buf, set.range)?;
+        self.index_set(set.cmd, range);
+
+        self.maybe_compact()
+    }
+
+    fn check_size(&self, key_len: usize, value_len: usize) -> Result<()> {
+        check_size(self.max_key_bytes, self.max_value_bytes, key_len, value_len)
+    }
+
+    /// Whether `key` is in the index and not expired.
+    fn is_live(&self, key: &[u8]) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|cmd_pos| !cmd_pos.is_expired(self.now()))
+    }
+
+    fn rm(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.is_live(&key) {
+            let cmd = Cmd::rm_bytes(key);
+            let range = self.append(&cmd)?;
+            self.index_rm(cmd.into_key(), range);
+
+            self.maybe_compact()
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Remove the expired `keys`, given with the version found expired, and return how
+    /// many were removed.
+    fn sweep(&mut self, keys: &[(Vec<u8>, u64)]) -> Result<usize> {
+        let mut removed = 0;
+        for (key, version) in keys {
+            // the key may have been written again or dropped by a compaction meanwhile,
+            // while the same version is still expired
+            let expired = self
+                .index
+                .get(key)
+                .is_some_and(|cmd_pos| cmd_pos.version == *version);
+            if expired {
+                let cmd = Cmd::rm_bytes(key.clone());
+                let range = self.append(&cmd)?;
+                self.index_rm(cmd.into_key(), range);
+                removed += 1;
+            }
+        }
+        self.maybe_compact()?;
+        Ok(removed)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let cmd_pos = self
+            .index
+            .get(key.as_bytes())
+            .filter(|cmd_pos| !cmd_pos.is_expired(self.now()));
+        let matches = match (&cmd_pos, &expected) {
+            (Some(cmd_pos), Some(expected)) => {
+                !cmd_pos.bytes && self.reader.read_bytes(cmd_pos)? == expected.as_bytes()
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        if !matches {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if cmd_pos.is_some() => self.rm(key.into_bytes())?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn get_or_set(&mut self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.live_value(key.as_bytes())? {
+            return Ok(value);
+        }
+        let value = default();
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// The string value of `key`, unless the key is absent or expired.
+    ///
+    /// Compactions are only installed by the writer, so under the writer lock the log
+    /// files of the index stay in place while the value is read.
+    fn live_value(&self, key: &[u8]) -> Result<Option<String>> {
+        let cmd_pos = self
+            .index
+            .get(key)
+            .filter(|cmd_pos| !cmd_pos.is_expired(self.now()));
+        match cmd_pos {
+            Some(cmd_pos) => self.reader.read_command(&cmd_pos),
+            None => Ok(None),
+        }
+    }
+
+    fn set_returning_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.live_value(key.as_bytes())?;
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let value = self
+            .live_value(from.as_bytes())?
+            .ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        self.write_batch(vec![
+            BatchOp::Set { key: to, value },
+            BatchOp::Rm { key: from },
+        ])
+    }
+
+    fn rm_returning_old(&mut self, key: String) -> Result<Option<String>> {
+        let old = self.live_value(key.as_bytes())?;
+        if old.is_some() {
+            self.rm(key.into_bytes())?;
+        }
+        Ok(old)
+    }
+
+    /// Write all `ops` after a `Cmd::Batch` header with a single write and flush.
+    ///
+    /// Removals are checked before anything is written, so a failing batch leaves
+    /// neither the log nor the index changed.
+    fn write_batch(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        let mut live = HashMap::new();
+        for op in &ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.check_size(key.len(), value.len())?;
+                    live.insert(key.as_str(), true);
+                }
+                BatchOp::Rm { key } => {
+                    let exists = match live.get(key.as_str()) {
+                        Some(&exists) => exists,
+                        None => self.is_live(key.as_bytes()),
+                    };
+                    if !exists {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    live.insert(key.as_str(), false);
+                }
+            }
+        }
+        let ops = if self.coalesce_batches {
+            coalesce(ops, |key| self.is_live(key.as_bytes()))
+        } else {
+            ops
+        };
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        // Serialize the whole batch up front: an encoding error leaves the log untouched,
+        // and the batch reaches the file in a single write.
+        let cmds: Vec<Cmd> = ops.into_iter().map(Cmd::from).collect();
+        let mut buf = Vec::new();
+        let header = Cmd::Batch { len: cmds.len() };
+        let codec = self.format.encoding.codec();
+        encode_record(&mut buf, self.format.checksums, |buf| {
+            codec.encode(&header, buf)
+        })?;
+        let header_len = buf.len() as u64;
+        let mut ranges = Vec::with_capacity(cmds.len());
+        for cmd in &cmds {
+            ranges.push(encode_record(&mut buf, self.format.checksums, |buf| {
+                codec.encode(cmd, buf)
+            })?);
+        }
+
+        self.roll_if_full(buf.len() as u64)?;
+        let log = self.active_log()?;
+        let pos = log.pos;
+        let res = log.write_all(&buf).and_then(|()| log.flush());
+        if let Err(e) = res.map_err(KvsError::from).and_then(|()| self.maybe_sync()) {
+            self.abandon_active_log(pos)?;
+            return Err(e);
+        }
+        // the header is only needed until the batch is compacted
+        self.uncompacted += header_len;
+
+        for (cmd, range) in cmds.into_iter().zip(ranges) {
+            let range = pos + range.start..pos + range.end;
+            match cmd {
+                Cmd::Set { .. } => self.index_set(cmd, range),
+                Cmd::Rm { key } => self.index_rm(key.into_bytes(), range),
+                _ => unreachable!("not a batch operation"),
+            }
+        }
+
+        self.maybe_compact()
+    }
+
+    /// Point the key of the set command `cmd` to where it was written, at `range` of the
+    /// active log file.
+    fn index_set(&mut self, cmd: Cmd, range: Range<u64>) {
+        self.version += 1;
+        let cmd_pos = CmdPos::set(self.cur_fid, range, &cmd, self.version, self.format);
+        let key = cmd.into_key();
+        if let Some(cache) = &self.cache {
+            cache.remove(&key);
+        }
+        if let Some(value_sizes) = &mut self.value_sizes {
+            value_sizes.add(&cmd_pos);
+        }
+        if let Some(old_cmd_pos) = self.index.insert(key, cmd_pos) {
+            if let Some(value_sizes) = &mut self.value_sizes {
+                value_sizes.remove(&old_cmd_pos);
+            }
+            self.supersede(&old_cmd_pos);
+        }
+    }
+
+    /// Count the record at `cmd_pos`, which was just overwritten or removed, as stale.
+    fn supersede(&mut self, cmd_pos: &CmdPos) {
+        self.uncompacted += cmd_pos.disk_len();
+        if let Some(compaction) = &mut self.compaction {
+            if cmd_pos.fid < compaction.fid {
+                compaction.superseded += cmd_pos.disk_len();
+            }
+        }
+    }
+
+    /// Drop `key` from the index after its `rm` command was written at `range`.
+    fn index_rm(&mut self, key: Vec<u8>, range: Range<u64>) {
+        let old_cmd_pos = self.index.remove(&key).expect("key not found");
+        if let Some(cache) = &self.cache {
+            cache.remove(&key);
+        }
+        if let Some(value_sizes) = &mut self.value_sizes {
+            value_sizes.remove(&old_cmd_pos);
+        }
+        self.supersede(&old_cmd_pos);
+        // the "remove" command itself can be deleted in the next compaction
+        // so we add its length to `uncompacted`
+        self.uncompacted += range.end - range.start;
+        if self.format.checksums {
+            self.uncompacted += FRAME_HEADER_LEN;
+        }
+    }
+
+    /// Install a finished compaction, then start one if there are enough stale bytes,
+    /// and publish `uncompacted` to [Counters].
+    fn maybe_compact(&mut self) -> Result<()> {
+        let res = self.poll_compaction().and_then(|()| {
+            let compacting = self.compaction.as_ref().map_or(0, |c| c.uncompacted);
+            let total = self.total_bytes().saturating_sub(compacting);
+            if !self.needs_compaction(self.uncompacted - compacting, total) {
+                return Ok(());
+            }
+            // Only one compaction runs at a time, so writes only wait here when stale
+            // bytes pile up faster than the running compaction reclaims them.
+            self.finish_compaction()?;
+            if self.needs_compaction(self.uncompacted, self.total_bytes()) {
+                self.start_compaction()?;
+            }
+            Ok(())
+        });
+        self.counters
+            .uncompacted
+            .store(self.uncompacted, Ordering::Relaxed);
+        res
+    }
+
+    /// Whether `uncompacted` stale bytes in `total` bytes of log files call for a
+    /// compaction, see [BitcaskOptions::compaction_ratio].
+    fn needs_compaction(&self, uncompacted: u64, total: u64) -> bool {
+        match self.compaction_ratio {
+            Some(ratio) => uncompacted as f64 > ratio * total as f64,
+            None => uncompacted > self.compaction_threshold,
+        }
+    }
+
+    /// The total size of the log files, including the active one.
+    fn total_bytes(&self) -> u64 {
+        self.sealed_bytes + self.cur_writer.as_ref().map_or(0, |log| log.pos)
+    }
+
+    /// Record the length of log file `fid`, which is sealed.
+    fn seal(&mut self, fid: u64, len: u64) {
+        if let Some(old_len) = self.log_lens.insert(fid, len) {
+            self.sealed_bytes -= old_len;
+        }
+        self.sealed_bytes += len;
+    }
+
+    /// Forget the lengths of the log files before `fid`, which are deleted.
+    fn forget_logs_before(&mut self, fid: u64) {
+        let kept = self.log_lens.split_off(&fid);
+        self.sealed_bytes -= self.log_lens.values().sum::<u64>();
+        self.log_lens = kept;
+    }
+
+    /// Compact all sealed log files now, and wait for it.
+    fn compact(&mut self) -> Result<()> {
+        self.finish_compaction()?;
+        self.start_compaction()?;
+        self.finish_compaction()
+    }
+
+    /// Seal the active log file and start compacting all log files in the background.
+    ///
+    /// The compaction files get the fids after the sealed log files, and new writes go
+    /// to a log file after them, so that new writes are loaded after the copies on the
+    /// next `open`.
+    fn start_compaction(&mut self) -> Result<()> {
+        let fid = self.cur_fid + 1;
+        let last_fid = fid + self.reserved_compaction_files() - 1;
+        self.roll_to(last_fid + 1)?;
+
+        let job = CompactionJob {
+            dir: Arc::clone(&self.dir),
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+            format: self.format,
+            sync: self.sync,
+            max_file_bytes: self.max_file_bytes,
+            clock: self.clock.clone(),
+            fid,
+            last_fid,
+        };
+        info!("Compaction starts");
+        let handle = thread::Builder::new()
+            .name("bitcask-compaction".to_owned())
+            .spawn(move || job.run())?;
+        self.compaction = Some(Compaction {
+            handle,
+            fid,
+            uncompacted: self.uncompacted,
+            superseded: 0,
+            started: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// The number of fids to reserve for the files of a compaction.
+    ///
+    /// This is twice the files the live records need, as records may grow when
+    /// converted to another format and files are not filled up to the last byte. A
+    /// compaction which runs out of fids writes the rest into its last file.
+    fn reserved_compaction_files(&self) -> u64 {
+        match self.max_file_bytes {
+            None => 1,
+            Some(max_file_bytes) => {
+                let mut live = 0;
+                self.index.for_each(|_, cmd_pos| live += cmd_pos.disk_len());
+                2 * live.div_ceil(max_file_bytes.max(1)) + 1
+            }
+        }
+    }
+
+    /// Install the background compaction if it finished.
+    fn poll_compaction(&mut self) -> Result<()> {
+        match &self.compaction {
+            Some(compaction) if compaction.handle.is_finished() => self.finish_compaction(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for the background compaction, if any, and install it.
+    fn finish_compaction(&mut self) -> Result<()> {
+        let compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+        let files = compaction
+            .handle
+            .join()
+            .map_err(|_| KvsError::StringError("The compaction thread panicked".to_owned()))??;
+        let files_written = files.len();
+        self.forget_logs_before(compaction.fid);
+
+        let mut stale_copies = 0;
+        for file in files {
+            self.seal(file.fid, file.len);
+            // Only point the index to the compaction files once they are flushed, so that
+            // concurrent readers, e.g. a scan, never see a position that is not readable
+            // yet. Keys written since the compaction started are newer than their copy.
+            let format = self.format;
+            for (hint, version) in file.hints.into_iter().zip(file.versions) {
+                let mut moved = false;
+                self.index.update(&hint.key, |cmd_pos| {
+                    if cmd_pos.version == version {
+                        cmd_pos.fid = file.fid;
+                        cmd_pos.pos = hint.pos;
+                        cmd_pos.len = hint.len;
+                        cmd_pos.value_offset = hint.value_offset;
+                        cmd_pos.format = format;
+                        moved = true;
+                    }
+                });
+                if !moved {
+                    stale_copies += hint.len;
+                    if format.checksums {
+                        stale_copies += FRAME_HEADER_LEN;
+                    }
+                }
+            }
+            for (key, version) in file.expired {
+                let expired = self
+                    .index
+                    .remove_if(&key, |cmd_pos| cmd_pos.version == version);
+                if let Some(cmd_pos) = expired {
+                    if let Some(value_sizes) = &mut self.value_sizes {
+                        value_sizes.remove(&cmd_pos);
+                    }
+                    if let Some(cache) = &self.cache {
+                        cache.remove(&key);
+                    }
+                }
+            }
+        }
+
+        // update safe_point
+        self.reader
+            .safe_point
+            .store(compaction.fid, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // remove stale log files
+        // Note that actually these files are not deleted immediately because `Reader`s
+        // still keep open file handles. When a `Reader` is used next time, it will clear
+        // its stale file handles. On Unix, the files will be deleted after all the handles
+        // are closed. On Windows, the deletions below will fail and stale files are expected
+        // to be deleted in the next compaction.
+
+        let stale_fids = self
+            .dir
+            .sorted_fids()?
+            .into_iter()
+            .filter(|&fid| fid < compaction.fid);
+
+        let mut files_removed = 0;
+        for stale_fid in stale_fids {
+            let file_path = self.dir.log_path(stale_fid);
+            match fs::remove_file(&file_path) {
+                Ok(()) => files_removed += 1,
+                Err(e) => error!("{:?} cannot be deleted: {}", file_path, e),
+            }
+            let hint_path = self.dir.hint_path(stale_fid);
+            match fs::remove_file(&hint_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    error!("{:?} cannot be deleted: {}", hint_path, e);
+                }
+                _ => {}
+            }
+        }
+        // Records of the deleted logs which became stale while compacting are gone too,
+        // while the copies of those records are stale in the compaction files. Keys
+        // written before the compaction got to them were not copied at all.
+        self.uncompacted =
+            self.uncompacted - compaction.uncompacted - compaction.superseded + stale_copies;
+        self.counters
+            .uncompacted
+            .store(self.uncompacted, Ordering::Relaxed);
+        let duration = compaction.started.elapsed();
+        info!("Compaction finished, cost {:?}", duration);
+
+        if let Some(hook) = &self.on_compaction {
+            let stats = CompactionStats {
+                files_written,
+                files_removed,
+                bytes_reclaimed: compaction.uncompacted,
+                duration,
+            };
+            // the writer lock is held, which the hook would deadlock on if it wrote
+            let hook = Arc::clone(&hook.0);
+            let spawned = thread::Builder::new()
+                .name("bitcask-compaction-hook".to_owned())
+                .spawn(move || hook(stats));
+            if let Err(e) = spawned {
+                error!("Unable to call the compaction hook: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Seal the active log file and go on writing to the new log file `fid`.
+    fn roll_to(&mut self, fid: u64) -> Result<()> {
+        // later syncs only sync the new log file
+        self.close()?;
+        if let Some(log) = &self.cur_writer {
+            self.seal(self.cur_fid, log.pos);
+        }
+        self.open_log(fid)
+    }
+
+    /// Roll to the next log file if writing `len` more bytes would take the active log
+    /// file past [BitcaskOptions::max_file_bytes], like compaction files.
+    ///
+    /// Sealed log files keep their fids, so the index still points to them.
+    fn roll_if_full(&mut self, len: u64) -> Result<()> {
+        let max_file_bytes = match self.max_file_bytes {
+            Some(max_file_bytes) => max_file_bytes,
+            None => return Ok(()),
+        };
+        let header_len = self.format.magic().map_or(0, |magic| magic.len() as u64);
+        let pos = self.active_log()?.pos;
+        if pos > header_len && pos + len > max_file_bytes {
+            self.roll_to(self.cur_fid + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Give up the active log file after a failed write which started at `pos`, and
+    /// continue in a new log file.
+    ///
+    /// What is still buffered is dropped, and what reached the file is left unindexed
+    /// at its end, where `open` ignores an incomplete batch.
+    fn abandon_active_log(&mut self, pos: u64) -> Result<()> {
+        if let Some(log) = self.cur_writer.take() {
+            let (_, buffered) = log.writer.into_parts();
+            let buffered = buffered.map_or(0, |buf| buf.len() as u64);
+            self.uncompacted += log.pos.saturating_sub(buffered + pos);
+            self.seal(self.cur_fid, log.pos.saturating_sub(buffered));
+        }
+        self.open_log(self.cur_fid + 1)
+    }
+
+    /// Make log file `fid` the active one.
+    fn open_log(&mut self, fid: u64) -> Result<()> {
+        self.cur_fid = fid;
+        self.cur_writer = Some(new_log_writer(&self.dir, fid, self.format)?);
+        #[cfg(feature = "mmap")]
+        if let Some(mmaps) = &self.reader.mmaps {
+            mmaps.active_fid.store(fid, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Seal the active log file and open all log and hint files written so far,
+    /// returning them with their paths.
+    ///
+    /// The files stay readable through the returned handles even if a compaction
+    /// deletes them.
+    fn snapshot(&mut self) -> Result<Vec<(File, PathBuf)>> {
+        // the files of a running compaction are incomplete
+        self.finish_compaction()?;
+        if self.cur_writer.is_some() {
+            self.roll_to(self.cur_fid + 1)?;
+        }
+
+        let mut files = Vec::new();
+        for fid in self.dir.sorted_fids()? {
+            if fid >= self.cur_fid {
+                continue;
+            }
+            let log_path = self.dir.log_path(fid);
+            files.push((File::open(&log_path)?, log_path));
+            let hint_path = self.dir.hint_path(fid);
+            match File::open(&hint_path) {
+                Ok(file) => files.push((file, hint_path)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(files)
+    }
+
+    /// Drop all keys: switch to a fresh, empty log file and delete all older ones.
+    fn clear(&mut self) -> Result<()> {
+        // a running compaction would leave its files behind
+        if let Err(e) = self.finish_compaction() {
+            error!("Compaction failed: {}", e);
+        }
+        self.roll_to(self.cur_fid + 1)?;
+        if self.sync != SyncPolicy::None {
+            self.active_log()?.sync()?;
+        }
+
+        self.index.clear();
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        if let Some(value_sizes) = &mut self.value_sizes {
+            *value_sizes = ValueSizes::default();
+        }
+        self.uncompacted = 0;
+        self.unsynced = 0;
+
+        self.reader.safe_point.store(self.cur_fid, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // Unlike in a compaction, a log left behind would bring its keys back on the
+        // next `open`, so failing to delete one is an error.
+        self.forget_logs_before(self.cur_fid);
+        let stale_fids = self
+            .dir
+            .sorted_fids()?
+            .into_iter()
+            .filter(|&fid| fid < self.cur_fid);
+        for stale_fid in stale_fids {
+            fs::remove_file(self.dir.log_path(stale_fid))?;
+            match fs::remove_file(self.dir.hint_path(stale_fid)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // the compaction thread must not write to the data directory once it is closed
+        if let Err(e) = self.finish_compaction() {
+            error!("Compaction failed: {}", e);
+        }
+        if let Err(e) = self.close() {
+            error!("Closing the active log failed: {}", e);
+        }
+    }
+}
+
+/// A compaction running in the background, see [Writer::start_compaction].
+struct Compaction {
+    handle: thread::JoinHandle<Result<Vec<CompactionFile>>>,
+    /// The first compaction file. All older log files are replaced by the compaction.
+    fid: u64,
+    /// The stale bytes reclaimed by the compaction.
+    uncompacted: u64,
+    /// Bytes of the replaced log files which became stale since the compaction started.
+    superseded: u64,
+    started: Instant,
+}
+
+/// Copies the live records of the sealed log files into compaction files, on a
+/// background thread.
+struct CompactionJob {
+    dir: Arc<DataDir>,
+    reader: Reader,
+    index: Arc<Index>,
+    format: LogFormat,
+    sync: SyncPolicy,
+    max_file_bytes: Option<u64>,
+    clock: Option<Arc<dyn Clock>>,
+    /// The first compaction file, right after the sealed log files.
+    fid: u64,
+    /// The last fid reserved for compaction files.
+    last_fid: u64,
+}
+
+impl CompactionJob {
+    fn now(&self) -> u64 {
+        now_millis(self.clock.as_deref())
+    }
+
+    /// Copy all live commands of the sealed log files into new log files from `fid`
+    /// on, rolling to the next file at [BitcaskOptions::max_file_bytes].
+    ///
+    /// Writes go on meanwhile, so the copies are made from a snapshot of the positions
+    /// and versions in the index. Positions in the sealed files never change, so each
+    /// copy is the value of the key at its version, even if the key was written again
+    /// since. [Writer::finish_compaction] then only moves a key to its copy if its
+    /// version is still the same, so a concurrent write is neither lost nor undone.
+    fn run(self) -> Result<Vec<CompactionFile>> {
+        // Copy the positions out first, as iterating the index locks its shards and
+        // would block writes while the logs are read.
+        let now = self.now();
+        let mut expired = Vec::new();
+        let mut live = Vec::new();
+        self.index.for_each(|key, cmd_pos| {
+            if cmd_pos.fid >= self.fid {
+                // written since the compaction started
+                return;
+            }
+            if cmd_pos.is_expired(now) {
+                expired.push((key.to_vec(), cmd_pos.version));
+            } else {
+                live.push((key.to_vec(), cmd_pos.clone()));
+            }
+        });
+
+        let codec = self.format.encoding.codec();
+        let mut fid = self.fid;
+        let mut compaction_writer = new_log_writer(&self.dir, fid, self.format)?;
+        let mut buf = Vec::new();
+        let mut files = vec![CompactionFile::new(fid)];
+        files[0].expired = expired;
+        for (key, cmd_pos) in live {
+            // records are framed again, as the compaction file may differ in checksums
+            let record = self.reader.read_record(&cmd_pos)?;
+            buf.clear();
+            let (range, value_offset) = if cmd_pos.format.encoding == self.format.encoding {
+                let range = encode_record(&mut buf, self.format.checksums, |buf| {
+                    buf.extend_from_slice(&record);
+                    Ok(())
+                })?;
+                (range, cmd_pos.value_offset)
+            } else {
+                // converted to the encoding of new records
+                let cmd = cmd_pos.format.encoding.codec().decode(&record)?;
+                let range = encode_record(&mut buf, self.format.checksums, |buf| {
+                    codec.encode(&cmd, buf)
+                })?;
+                (range, codec.value_offset(&cmd) as u32)
+            };
+
+            let full = self.max_file_bytes.is_some_and(|max_file_bytes| {
+                compaction_writer.pos + buf.len() as u64 > max_file_bytes
+            });
+            if full && !files.last().unwrap().hints.is_empty() && fid < self.last_fid {
+                self.finish_file(&mut compaction_writer, files.last_mut().unwrap())?;
+                fid += 1;
+                compaction_writer = new_log_writer(&self.dir, fid, self.format)?;
+                files.push(CompactionFile::new(fid));
+            }
+
+            let pos = compaction_writer.pos;
+            compaction_writer.write_all(&buf)?;
+            let file = files.last_mut().unwrap();
+            file.hints.push(Hint {
+                key,
+                pos: pos + range.start,
+                len: range.end - range.start,
+                value_offset,
+                expire_at: cmd_pos.expire_at,
+                bytes: cmd_pos.bytes,
+            });
+            file.versions.push(cmd_pos.version);
+        }
+        self.finish_file(&mut compaction_writer, files.last_mut().unwrap())?;
+        Ok(files)
+    }
+
+    /// Flush a compaction file, sync it unless the [SyncPolicy] is `None`, as the logs
+    /// it replaces are deleted afterwards, and write its hint file.
+    fn finish_file(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        file: &mut CompactionFile,
+    ) -> Result<()> {
+        writer.flush()?;
+        file.len = writer.pos;
+        if self.sync != SyncPolicy::None {
+            writer.sync()?;
+        }
+        // a missing hint file only slows down the next `open`
+        if let Err(e) = write_hint(&self.dir, file.fid, &file.hints) {
+            error!("Hint file of log {} cannot be written: {}", file.fid, e);
+        }
+        Ok(())
+    }
+}
+
+/// A file written by a [CompactionJob].
+struct CompactionFile {
+    fid: u64,
+    /// The length of the file, once finished.
+    len: u64,
+    /// The records copied into the file.
+    hints: Vec<Hint>,
+    /// The version of the command copied into each hint.
+    versions: Vec<u64>,
+    /// Keys found expired, with the version of their command.
+    expired: Vec<(Vec<u8>, u64)>,
+}
+
+impl CompactionFile {
+    fn new(fid: u64) -> Self {
+        CompactionFile {
+            fid,
+            len: 0,
+            hints: Vec::new(),
+            versions: Vec::new(),
+            expired: Vec::new(),
+        }
+    }
+}
+
+/// The data directory of a [Bitcask] and the [LogNaming] of its files.
+struct DataDir {
+    path: PathBuf,
+    naming: LogNaming,
+}
+
+impl DataDir {
+    /// The fids of the log files in the directory, in ascending order.
+    fn sorted_fids(&self) -> Result<Vec<u64>> {
+        self.sorted_fids_of(&self.naming.extension)
+    }
+
+    /// The fids of the files with `extension` in the directory, in ascending order.
+    fn sorted_fids_of(&self, extension: &str) -> Result<Vec<u64>> {
+        let mut fids: Vec<u64> = fs::read_dir(&self.path)?
+            .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                path.file_name()
+                    .and_then(OsStr::to_str)
+                    .and_then(|name| self.naming.parse(name, extension))
+            })
+            .collect();
+
+        fids.sort_unstable();
+
+        Ok(fids)
+    }
+
+    /// join path: {dir}/{prefix}{fid}.{extension}
+    fn log_path(&self, fid: u64) -> PathBuf {
+        self.path
+            .join(self.naming.file_name(fid, &self.naming.extension))
+    }
+
+    /// join path: {dir}/{prefix}{fid}.hint
+    fn hint_path(&self, fid: u64) -> PathBuf {
+        self.path.join(self.naming.file_name(fid, "hint"))
+    }
+}
+
+/// A record of a log file, see [inspect_log].
+#[derive(Debug)]
+pub struct LogRecord {
+    /// The log file of the record.
+    pub fid: u64,
+    /// Start of the command in its log file, after the checksum if any.
+    pub pos: u64,
+    /// Length of the serialized command.
+    pub len: u64,
+    /// The command.
+    pub cmd: Cmd,
+}
+
+/// Decode every record of the [Bitcask] data directory at `path`, in the order they are
+/// replayed on `open`, without opening the store.
+///
+/// A log file ending in a torn record, e.g. after a crash in the middle of a write, is
+/// read up to its last valid record. The files are not changed, unlike on `open`.
+pub fn inspect_log(path: &Path) -> Result<Vec<LogRecord>> {
+    inspect_log_with_naming(path, &LogNaming::default())
+}
+
+/// Like [inspect_log], for a data directory whose files are named by `naming`, see
+/// [BitcaskOptions::log_naming].
+pub fn inspect_log_with_naming(path: &Path, naming: &LogNaming) -> Result<Vec<LogRecord>> {
+    naming.check()?;
+    let dir = DataDir {
+        path: path.to_owned(),
+        naming: naming.clone(),
+    };
+    let mut log = Vec::new();
+    for fid in dir.sorted_fids()? {
+        let mut reader = new_log_reader(&dir, fid)?;
+        let (format, records) = log_records(fid, &mut reader)?;
+        for record in records {
+            if is_torn(format, &record) {
+                warn!("Ignoring the torn end of log {}", fid);
+                break;
+            }
+            let (cmd, range) = record?;
+            log.push(LogRecord {
+                fid,
+                pos: range.start,
+                len: range.end - range.start,
+                cmd,
+            });
+        }
+    }
+    Ok(log)
+}
+
+/// Lock the data directory `dir` against other writers, by locking its `LOCK` file.
+///
+/// The lock is held until the returned file is closed. The OS also releases it when
+/// the process dies, so a `LOCK` file left behind by a crash does not keep the
+/// directory locked. The file holds the id of the locking process, for humans.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    if let Err(e) = fs2::FileExt::try_lock_exclusive(&file) {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            return Err(KvsError::DirectoryLocked(dir.to_owned()));
+        }
+        return Err(e.into());
+    }
+    file.set_len(0)?;
+    write!(file, "{}", process::id())?;
+    Ok(file)
+}
+
+/// Write the hint file of log file `fid`, listing all of its records.
+///
+/// The hint file is written aside and renamed into place, so that an incomplete hint
+/// file is never read.
+fn write_hint(dir: &DataDir, fid: u64, hints: &[Hint]) -> Result<()> {
+    let path = dir.hint_path(fid);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for hint in hints {
+        serde_json::to_writer(&mut writer, hint)?;
+    }
+    writer.flush()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Create a new [BufReaderWithPos] for `fid`'s log file.
+fn new_log_reader(dir: &DataDir, fid: u64) -> Result<BufReaderWithPos<File>> {
+    BufReaderWithPos::new(File::open(dir.log_path(fid))?)
+}
+
+/// Creat a new log file with `fid` and return the writer to the log.
+///
+/// A new log file starts with the magic bytes of its `format`, if any.
+fn new_log_writer(dir: &DataDir, fid: u64, format: LogFormat) -> Result<BufWriterWithPos<File>> {
+    let path = dir.log_path(fid);
+    let mut writer =
+        BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    if let Some(magic) = format.magic() {
+        if writer.pos == 0 {
+            writer.write_all(magic)?;
+        }
+    }
+
+    Ok(writer)
+}
+
+/// How the records of a log file are written, told by the first bytes of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LogFormat {
+    encoding: Encoding,
+    /// see [BitcaskOptions::checksums]
+    checksums: bool,
+}
+
+impl LogFormat {
+    /// All formats, the first one being plain JSON, the original format.
+    const ALL: [LogFormat; 4] = [
+        LogFormat::new(Encoding::Json, false),
+        LogFormat::new(Encoding::Json, true),
+        LogFormat::new(Encoding::Bincode, false),
+        LogFormat::new(Encoding::Bincode, true),
+    ];
+
+    /// Length of the magic bytes, see [LogFormat::magic].
+    const MAGIC_LEN: usize = 8;
+
+    const fn new(encoding: Encoding, checksums: bool) -> Self {
+        LogFormat {
+            encoding,
+            checksums,
+        }
+    }
+
+    /// First bytes of a log file in this format. Plain JSON logs, which predate other
+    /// formats, have none.
+    fn magic(self) -> Option<&'static [u8; Self::MAGIC_LEN]> {
+        match (self.encoding, self.checksums) {
+            (Encoding::Json, false) => None,
+            (Encoding::Json, true) => Some(b"RSKVCRC1"),
+            (Encoding::Bincode, false) => Some(b"RSKVBIN1"),
+            (Encoding::Bincode, true) => Some(b"RSKVBNC1"),
+        }
+    }
+}
+
+/// Check the byte lengths of a key and its value against the configured limits.
+fn check_size(
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    key_len: usize,
+    value_len: usize,
+) -> Result<()> {
+    match (max_key_bytes, max_value_bytes) {
+        (Some(limit), _) if key_len > limit => Err(KvsError::KeyTooLarge {
+            size: key_len,
+            limit,
+        }),
+        (_, Some(limit)) if value_len > limit => Err(KvsError::ValueTooLarge {
+            size: value_len,
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// A set command encoded before the writer is locked, see [Writer::append_prepared].
+struct PreparedSet {
+    cmd: Cmd,
+    /// The record of the command, in `format`.
+    buf: Vec<u8>,
+    /// Where the command is in `buf`.
+    range: Range<u64>,
+    format: LogFormat,
+}
+
+impl PreparedSet {
+    fn new(cmd: Cmd, format: LogFormat) -> Result<Self> {
+        let (buf, range) = encode_cmd(&cmd, format)?;
+        Ok(PreparedSet {
+            cmd,
+            buf,
+            range,
+            format,
+        })
+    }
+}
+
+/// The record of `cmd` in `format`, and the range of the command in it.
+fn encode_cmd(cmd: &Cmd, format: LogFormat) -> Result<(Vec<u8>, Range<u64>)> {
+    let mut buf = Vec::new();
+    let codec = format.encoding.codec();
+    let range = encode_record(&mut buf, format.checksums, |buf| codec.encode(cmd, buf))?;
+    Ok((buf, range))
+}
+
+/// Append a record to `buf`, with the command serialized by `write`, and return the
+/// range of the command in `buf`.
+///
+/// With `checksums`, the command is preceded by its length and CRC32.
+fn encode_record<F>(buf: &mut Vec<u8>, checksums: bool, write: F) -> Result<Range<u64>>
+where
+    F: FnOnce(&mut Vec<u8>) -> Result<()>,
+{
+    let header = buf.len();
+    if checksums {
+        buf.extend_from_slice(&[0; FRAME_HEADER_LEN as usize]);
+    }
+    let start = buf.len();
+    write(buf)?;
+    if checksums {
+        let len = (buf.len() - start) as u32;
+        let checksum = crc32fast::hash(&buf[start..]);
+        buf[header..header + 4].copy_from_slice(&len.to_le_bytes());
+        buf[header + 4..start].copy_from_slice(&checksum.to_le_bytes());
+    }
+    Ok(start as u64..buf.len() as u64)
+}
+
+/// Write `bytes` prefixed with their length, see [Bitcask::export].
+fn write_exported(w: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too large to export"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+/// Read bytes written by [write_exported].
+fn read_exported(r: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    // read what arrives instead of trusting the length to allocate up front
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Verify the checksummed record `frame`, read at `pos` of log file `fid`, and return
+/// its command.
+fn check_frame(fid: u64, pos: u64, frame: &[u8]) -> Result<&[u8]> {
+    let corrupted = KvsError::ChecksumMismatch { fid, pos };
+    if frame.len() < FRAME_HEADER_LEN as usize {
+        return Err(corrupted);
+    }
+    let (header, cmd) = frame.split_at(FRAME_HEADER_LEN as usize);
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if cmd.len() != len as usize || crc32fast::hash(cmd) != checksum {
+        return Err(corrupted);
+    }
+    Ok(cmd)
+}
+
+/// A command of a log file and where it was read.
+type Record = (Cmd, Range<u64>);
+
+/// The records of a log file, see [log_records].
+type Records<'a> = Box<dyn Iterator<Item = Result<Record>> + 'a>;
+
+/// Iterate over the records of log file `fid`, and return the format of the file.
+///
+/// The records of a checksummed file end at the first one which is incomplete or does
+/// not match its checksum, with a `KvsError::ChecksumMismatch`.
+fn log_records(fid: u64, reader: &mut BufReaderWithPos<File>) -> Result<(LogFormat, Records<'_>)> {
+    let format = read_magic(reader)?;
+    let codec = format.encoding.codec();
+    if !format.checksums {
+        return Ok((format, codec.decode_stream(reader)));
+    }
+
+    let mut pos = reader.pos;
+    let records = iter::from_fn(move || {
+        let record = read_frame(fid, reader, pos, codec).transpose()?;
+        if let Ok((_, range)) = &record {
+            pos = range.end;
+        }
+        Some(record)
+    });
+    Ok((format, Box::new(records)))
+}
+
+/// Whether `record` is the torn last record of a log file in `format`, e.g. after a crash
+/// in the middle of a write. Checksummed records report it as a mismatch instead.
+fn is_torn(format: LogFormat, record: &Result<Record>) -> bool {
+    match record {
+        Err(KvsError::ChecksumMismatch { .. }) => true,
+        Err(e) => !format.checksums && is_unexpected_eof(e),
+        Ok(_) => false,
+    }
+}
+
+/// Whether `err`, from decoding a log file, means that it ended in the middle of a record.
+fn is_unexpected_eof(err: &KvsError) -> bool {
+    match err {
+        KvsError::Serde(e) => e.is_eof(),
+        KvsError::Bincode(e) => {
+            matches!(&**e, bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+        }
+        _ => false,
+    }
+}
+
+/// The format of the log file of `reader`, leaving `reader` at its first record.
+fn read_magic(reader: &mut BufReaderWithPos<File>) -> Result<LogFormat> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = Vec::with_capacity(LogFormat::MAGIC_LEN);
+    reader
+        .by_ref()
+        .take(LogFormat::MAGIC_LEN as u64)
+        .read_to_end(&mut magic)?;
+    let format = LogFormat::ALL
+        .into_iter()
+        .find(|format| format.magic().is_some_and(|m| m[..] == magic[..]));
+    match format {
+        Some(format) => Ok(format),
+        None => {
+            reader.seek(SeekFrom::Start(0))?;
+            Ok(LogFormat::ALL[0])
+        }
+    }
+}
+
+/// Read the checksummed record at `pos` of log file `fid`, or `None` at the end of the file.
+fn read_frame(
+    fid: u64,
+    reader: &mut impl Read,
+    pos: u64,
+    codec: &dyn codec::Codec,
+) -> Result<Option<Record>> {
+    let mut frame = Vec::new();
+    reader
+        .by_ref()
+        .take(FRAME_HEADER_LEN)
+        .read_to_end(&mut frame)?;
+    if frame.is_empty() {
+        return Ok(None);
+    }
+    if frame.len() == FRAME_HEADER_LEN as usize {
+        let len = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        reader.by_ref().take(len as u64).read_to_end(&mut frame)?;
+    }
+    let cmd = codec.decode(check_frame(fid, pos, &frame)?)?;
+    let start = pos + FRAME_HEADER_LEN;
+    Ok(Some((cmd, start..pos + frame.len() as u64)))
+}
+
+/// A command of the log.
+///
+/// Logs in [Encoding::Bincode] identify variants by their index, so new variants must
+/// be added last.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Cmd {
+    /// Set `key` to `value`.
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+    /// A `Set` which expires at `expire_at`, in milliseconds since the Unix epoch.
+    ///
+    /// `expire_at` comes before `value`, so that the value ends every set command.
+    SetEx {
+        /// The key.
+        key: String,
+        /// When the key expires.
+        expire_at: u64,
+        /// The value.
+        value: String,
+    },
+    /// A `Set` of binary data, see [Bitcask::set_bytes].
+    SetBytes {
+        /// The key.
+        key: Vec<u8>,
+        /// The value.
+        value: Vec<u8>,
+    },
+    /// Remove `key`.
+    Rm {
+        /// The key.
+        key: String,
+    },
+    /// A `Rm` of a binary key.
+    RmBytes {
+        /// The key.
+        key: Vec<u8>,
+    },
+    /// Header of a batch: the next `len` commands were written together by
+    /// [KvsEngine::write_batch] and are only applied if all of them are in the log.
+    Batch {
+        /// The number of commands in the batch.
+        len: usize,
+    },
+}
+
+impl Cmd {
+    fn set(key: String, value: String) -> Self {
+        Cmd::Set { key, value }
+    }
+
+    fn rm(key: String) -> Self {
+        Cmd::Rm { key }
+    }
+
+    /// Byte lengths of the key and the value of a set command.
+    fn key_value_len(&self) -> Option<(usize, usize)> {
+        match self {
+            Cmd::Set { key, value } | Cmd::SetEx { key, value, .. } => {
+                Some((key.len(), value.len()))
+            }
+            Cmd::SetBytes { key, value } => Some((key.len(), value.len())),
+            Cmd::Rm { .. } | Cmd::RmBytes { .. } | Cmd::Batch { .. } => None,
+        }
+    }
+
+    /// A `Cmd::Set` if both `key` and `value` are valid UTF-8, otherwise a `Cmd::SetBytes`.
+    fn set_bytes(key: Vec<u8>, value: Vec<u8>) -> Self {
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => Cmd::set(key, value),
+            (key, value) => Cmd::SetBytes {
+                key: key.map_or_else(|e| e.into_bytes(), String::into_bytes),
+                value: value.map_or_else(|e| e.into_bytes(), String::into_bytes),
+            },
+        }
+    }
+
+    /// A `Cmd::Rm` if `key` is valid UTF-8, otherwise a `Cmd::RmBytes`.
+    fn rm_bytes(key: Vec<u8>) -> Self {
+        match String::from_utf8(key) {
+            Ok(key) => Cmd::rm(key),
+            Err(e) => Cmd::RmBytes {
+                key: e.into_bytes(),
+            },
+        }
+    }
+
+    /// The key of a set or remove command, as bytes.
+    fn into_key(self) -> Vec<u8> {
+        match self {
+            Cmd::Set { key, .. } | Cmd::SetEx { key, .. } | Cmd::Rm { key } => key.into_bytes(),
+            Cmd::SetBytes { key, .. } | Cmd::RmBytes { key } => key,
+            Cmd::Batch { .. } => unreachable!("a batch header has no key"),
+        }
+    }
+}
+
+/// The last operation of each key of `ops`, in their order, see
+/// [BitcaskOptions::coalesce_batches].
+///
+/// The last operation of a key removed by the batch is left out too if the key did
+/// not exist before the batch, as told by `existed`.
+fn coalesce(ops: Vec<BatchOp>, existed: impl Fn(&str) -> bool) -> Vec<BatchOp> {
+    let mut last = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        let key = match op {
+            BatchOp::Set { key, .. } | BatchOp::Rm { key } => key.as_str(),
+        };
+        last.insert(key, i);
+    }
+    let last: HashSet<usize> = last.into_values().collect();
+    ops.into_iter()
+        .enumerate()
+        .filter(|(i, op)| {
+            last.contains(i)
+                && match op {
+                    BatchOp::Set { .. } => true,
+                    BatchOp::Rm { key } => existed(key),
+                }
+        })
+        .map(|(_, op)| op)
+        .collect()
+}
+
+impl From<BatchOp> for Cmd {
+    fn from(op: BatchOp) -> Self {
+        match op {
+            BatchOp::Set { key, value } => Cmd::set(key, value),
+            BatchOp::Rm { key } => Cmd::rm(key),
+        }
+    }
+}
+
+/// An entry of a hint file: where the `set` command of `key` is in the log file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Hint {
+    key: Vec<u8>,
+    pos: u64,
+    len: u64,
+    value_offset: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expire_at: Option<u64>,
+    bytes: bool,
+}
+
+#[derive(Debug, Clone)]
+/// In-memory representation of a `command`.
+///
+/// Indicates where we can find it.
+pub struct CmdPos {
+    /// which file this commmand belong to
+    fid: u64,
+    /// start position of command
+    pos: u64,
+    /// length of command
+    len: u64,
+    /// version of the key, bumped on every write to it
+    version: u64,
+    /// start of the serialized value, relative to `pos`
+    value_offset: u32,
+    /// when the key expires, in milliseconds since the Unix epoch
+    expire_at: Option<u64>,
+    /// the format of the log file of the command
+    format: LogFormat,
+    /// whether the value is binary, see [Bitcask::set_bytes]
+    bytes: bool,
+}
+
+impl CmdPos {
+    /// Position of the set command `cmd` written at `range` of log file `fid`, which has
+    /// the given `format`.
+    fn set(fid: u64, range: Range<u64>, cmd: &Cmd, version: u64, format: LogFormat) -> Self {
+        let (expire_at, bytes) = match cmd {
+            Cmd::Set { .. } => (None, false),
+            Cmd::SetEx { expire_at, .. } => (Some(*expire_at), false),
+            Cmd::SetBytes { .. } => (None, true),
+            _ => unreachable!("not a set command"),
+        };
+        CmdPos {
+            fid,
+            pos: range.start,
+            len: range.end - range.start,
+            version,
+            value_offset: format.encoding.codec().value_offset(cmd) as u32,
+            expire_at,
+            format,
+            bytes,
+        }
+    }
+
+    /// Whether the key has expired at `now`, in milliseconds since the Unix epoch.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
+
+    /// Byte range of the whole record in its log file, including its checksum if any.
+    fn frame(&self) -> Range<u64> {
+        self.pos + self.len - self.disk_len()..self.pos + self.len
+    }
+
+    /// Bytes taken by the record in its log file.
+    fn disk_len(&self) -> u64 {
+        if self.format.checksums {
+            self.len + FRAME_HEADER_LEN
+        } else {
+            self.len
+        }
+    }
+
+    /// The serialized value within the serialized command `cmd`.
+    fn value_of<'a>(&self, cmd: &'a [u8]) -> &'a [u8] {
+        let value_end = self.len - self.format.encoding.codec().value_suffix();
+        &cmd[self.value_offset as usize..value_end as usize]
+    }
+
+    /// Byte range of the serialized value in its log file.
+    fn value(&self) -> Range<u64> {
+        let value_end = self.pos + self.len - self.format.encoding.codec().value_suffix();
+        self.pos + self.value_offset as u64..value_end
+    }
+}
+
+/// Milliseconds since the Unix epoch, on `clock` if any, or else on the system clock.
+fn now_millis(clock: Option<&dyn Clock>) -> u64 {
+    let now = match clock {
+        Some(clock) => clock.now(),
+        None => SystemTime::now(),
+    };
+    now.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Histogram of live value sizes, bucketed by powers of two.
+struct ValueSizes {
+    buckets: [u64; u64::BITS as usize + 1],
+}
+
+impl Default for ValueSizes {
+    fn default() -> Self {
+        ValueSizes {
+            buckets: [0; u64::BITS as usize + 1],
+        }
+    }
+}
+
+impl ValueSizes {
+    /// Count the value of a `set` command.
+    fn add(&mut self, cmd_pos: &CmdPos) {
+        self.buckets[Self::bucket(cmd_pos)] += 1;
+    }
+
+    /// Forget a value counted by [ValueSizes::add].
+    fn remove(&mut self, cmd_pos: &CmdPos) {
+        self.buckets[Self::bucket(cmd_pos)] -= 1;
+    }
+
+    fn bucket(cmd_pos: &CmdPos) -> usize {
+        let value = cmd_pos.value();
+        // minus e.g. the quotes of a JSON string
+        let overhead = cmd_pos.format.encoding.codec().value_overhead();
+        let size = (value.end - value.start).saturating_sub(overhead);
+        (u64::BITS - size.leading_zeros()) as usize
+    }
+
+    /// Buckets up to the last non-empty one.
+    fn to_vec(&self) -> Vec<u64> {
+        let len = self
+            .buckets
+            .iter()
+            .rposition(|&count| count > 0)
+            .map_or(0, |last| last + 1);
+        self.buckets[..len].to_vec()
+    }
+}
+
+/// A `BufReader` with position where it read to
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+
+    /// Whether there is nothing left to read.
+    fn at_end(&mut self) -> io::Result<bool> {
+        Ok(self.reader.fill_buf()?.is_empty())
+    }
+
+    /// Seek to `pos`, keeping the buffered data if `pos` is within it.
+    fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+        self.reader.seek_relative(pos as i64 - self.pos as i64)?;
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// A `BufWriter` with position where it read to
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Flush the buffer and sync the written data to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,335 @@
+//! An offline integrity check of a [Bitcask](super::Bitcask) data directory, see
+//! [verify].
+
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    ops::Range,
+    path::Path,
+};
+
+use serde_json::Deserializer;
+
+use super::{
+    check_frame, log_records, new_log_reader, read_magic, BufReaderWithPos, Cmd, DataDir, Hint,
+    LogFormat, LogNaming, FRAME_HEADER_LEN,
+};
+use crate::Result;
+
+/// What [verify] found in a data directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of log files checked.
+    pub log_files: usize,
+    /// Number of records which decoded, and matched their checksums if any.
+    pub valid_records: u64,
+    /// The records which did not, in the order of the log files.
+    pub corrupt_records: Vec<CorruptRecord>,
+    /// Total size of the corrupt records.
+    pub corrupt_bytes: u64,
+    /// Hint files and batches which disagree with the log files.
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl IntegrityReport {
+    /// Whether nothing corrupt or inconsistent was found.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_records.is_empty() && self.inconsistencies.is_empty()
+    }
+}
+
+/// Bytes of a log file which are not a valid record, see [IntegrityReport].
+///
+/// A checksummed record which does not match its checksum is skipped, so the records
+/// after it are still checked. A record of a log file without checksums which fails
+/// to decode cannot be told from the ones after it, so the rest of the file is
+/// reported as one corrupt record. Either may be a torn write at the end of the file,
+/// which `open` drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptRecord {
+    /// The log file of the record.
+    pub fid: u64,
+    /// Where the record starts in the log file, including its checksum if any.
+    pub pos: u64,
+    /// Length of the record.
+    pub len: u64,
+}
+
+/// A disagreement between the files of a data directory, see [IntegrityReport].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// A hint file whose log file is missing.
+    OrphanedHintFile {
+        /// The fid of the hint file.
+        fid: u64,
+    },
+    /// A hint file which does not decode. `open` replays the log file instead.
+    InvalidHintFile {
+        /// The fid of the hint file.
+        fid: u64,
+    },
+    /// A hint which does not point to a valid `set` of its key in the log file, so the
+    /// key would be loaded with a wrong value.
+    OrphanedKey {
+        /// The fid of the hint file.
+        fid: u64,
+        /// The key of the hint.
+        key: Vec<u8>,
+        /// Where the hint points to in the log file.
+        pos: u64,
+    },
+    /// A batch followed by fewer valid records than it holds. `open` ignores all of it.
+    IncompleteBatch {
+        /// The log file of the batch.
+        fid: u64,
+        /// Where the batch header starts in the log file, after its checksum if any.
+        pos: u64,
+        /// Number of records of the batch.
+        len: usize,
+        /// Number of valid records found after its header.
+        found: usize,
+    },
+}
+
+/// Check every record of the [Bitcask](super::Bitcask) data directory at `path`,
+/// without opening the store.
+///
+/// The log files are replayed, verifying the checksums of checksummed records, and
+/// every hint is checked against the record it points to. Nothing is changed, so
+/// this may run on the data directory of an open store, though records written
+/// meanwhile may then be reported as torn.
+///
+/// ## Errors
+///
+/// It fails if a file cannot be read, not if it is corrupt.
+pub fn verify(path: &Path) -> Result<IntegrityReport> {
+    verify_with_naming(path, &LogNaming::default())
+}
+
+/// Like [verify], for a data directory whose files are named by `naming`, see
+/// [BitcaskOptions::log_naming](super::BitcaskOptions::log_naming).
+pub fn verify_with_naming(path: &Path, naming: &LogNaming) -> Result<IntegrityReport> {
+    naming.check()?;
+    let dir = DataDir {
+        path: path.to_owned(),
+        naming: naming.clone(),
+    };
+    let mut report = IntegrityReport::default();
+    let fids = dir.sorted_fids()?;
+    for &fid in &fids {
+        report.log_files += 1;
+        verify_log(&dir, fid, &mut report)?;
+        verify_hints(&dir, fid, &mut report)?;
+    }
+
+    let logs: BTreeSet<u64> = fids.into_iter().collect();
+    for fid in dir.sorted_fids_of("hint")? {
+        if !logs.contains(&fid) {
+            report
+                .inconsistencies
+                .push(Inconsistency::OrphanedHintFile { fid });
+        }
+    }
+    Ok(report)
+}
+
+/// Check the records of log file `fid`.
+fn verify_log(dir: &DataDir, fid: u64, report: &mut IntegrityReport) -> Result<()> {
+    let log_len = fs::metadata(dir.log_path(fid))?.len();
+    let mut reader = new_log_reader(dir, fid)?;
+    let mut check = LogCheck {
+        fid,
+        report,
+        batch: None,
+    };
+    let format = read_magic(&mut reader)?;
+    if format.checksums {
+        verify_frames(&mut reader, format, log_len, &mut check)?;
+    } else {
+        let mut end = reader.pos;
+        let (_, records) = log_records(fid, &mut reader)?;
+        for record in records {
+            match record {
+                Ok((cmd, range)) => {
+                    end = range.end;
+                    check.valid(&cmd, range);
+                }
+                Err(_) => {
+                    check.corrupt(end..log_len);
+                    break;
+                }
+            }
+        }
+    }
+    check.finish();
+    Ok(())
+}
+
+/// Check the checksummed records of a log file, skipping those which do not match.
+fn verify_frames(
+    reader: &mut BufReaderWithPos<File>,
+    format: LogFormat,
+    log_len: u64,
+    check: &mut LogCheck<'_>,
+) -> Result<()> {
+    let codec = format.encoding.codec();
+    let mut pos = reader.pos;
+    while pos < log_len {
+        let mut frame = Vec::new();
+        reader
+            .by_ref()
+            .take(FRAME_HEADER_LEN)
+            .read_to_end(&mut frame)?;
+        if frame.len() < FRAME_HEADER_LEN as usize {
+            check.corrupt(pos..log_len);
+            break;
+        }
+        let len = u32::from_le_bytes(frame[..4].try_into().unwrap()) as u64;
+        let end = pos + FRAME_HEADER_LEN + len;
+        if end > log_len {
+            check.corrupt(pos..log_len);
+            break;
+        }
+        reader.by_ref().take(len).read_to_end(&mut frame)?;
+        match check_frame(check.fid, pos, &frame).and_then(|cmd| codec.decode(cmd)) {
+            Ok(cmd) => check.valid(&cmd, pos + FRAME_HEADER_LEN..end),
+            Err(_) => check.corrupt(pos..end),
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+/// Check that the hints of log file `fid`, if it has a hint file, point to the `set`
+/// commands of their keys.
+fn verify_hints(dir: &DataDir, fid: u64, report: &mut IntegrityReport) -> Result<()> {
+    let file = match File::open(dir.hint_path(fid)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let hints = Deserializer::from_reader(BufReader::new(file))
+        .into_iter::<Hint>()
+        .collect::<serde_json::Result<Vec<_>>>();
+    let hints = match hints {
+        Ok(hints) => hints,
+        Err(_) => {
+            report
+                .inconsistencies
+                .push(Inconsistency::InvalidHintFile { fid });
+            return Ok(());
+        }
+    };
+
+    let log_len = fs::metadata(dir.log_path(fid))?.len();
+    let mut reader = new_log_reader(dir, fid)?;
+    let format = read_magic(&mut reader)?;
+    for hint in hints {
+        let cmd = hinted_cmd(&mut reader, fid, format, log_len, &hint);
+        let matches = match cmd {
+            Ok(cmd @ (Cmd::Set { .. } | Cmd::SetEx { .. } | Cmd::SetBytes { .. })) => {
+                cmd.into_key() == hint.key
+            }
+            _ => false,
+        };
+        if !matches {
+            report.inconsistencies.push(Inconsistency::OrphanedKey {
+                fid,
+                key: hint.key,
+                pos: hint.pos,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The command `hint` points to in log file `fid`, verifying its checksum if any.
+fn hinted_cmd(
+    reader: &mut BufReaderWithPos<File>,
+    fid: u64,
+    format: LogFormat,
+    log_len: u64,
+    hint: &Hint,
+) -> Result<Cmd> {
+    let frame_len = if format.checksums {
+        FRAME_HEADER_LEN
+    } else {
+        0
+    };
+    let out_of_bounds = || {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the hint points past the end of the log",
+        )
+    };
+    let start = hint.pos.checked_sub(frame_len).ok_or_else(out_of_bounds)?;
+    let end = hint.pos.saturating_add(hint.len);
+    if end > log_len {
+        return Err(out_of_bounds().into());
+    }
+    reader.seek_to(start)?;
+    let mut record = Vec::new();
+    reader.by_ref().take(end - start).read_to_end(&mut record)?;
+    let cmd = if format.checksums {
+        check_frame(fid, start, &record)?
+    } else {
+        &record[..]
+    };
+    format.encoding.codec().decode(cmd)
+}
+
+/// The state of the check of one log file.
+struct LogCheck<'a> {
+    fid: u64,
+    report: &'a mut IntegrityReport,
+    /// The batch whose records are being read: where it starts, how many records it
+    /// holds and how many were found so far.
+    batch: Option<(u64, usize, usize)>,
+}
+
+impl LogCheck<'_> {
+    /// Count the valid record `cmd`, read at `range`.
+    fn valid(&mut self, cmd: &Cmd, range: Range<u64>) {
+        self.report.valid_records += 1;
+        if let Cmd::Batch { len } = *cmd {
+            self.end_batch();
+            if len > 0 {
+                self.batch = Some((range.start, len, 0));
+            }
+        } else if let Some((pos, len, found)) = self.batch {
+            self.batch = (found + 1 < len).then_some((pos, len, found + 1));
+        }
+    }
+
+    /// Report the bytes at `range` as a corrupt record, which also cuts short the batch
+    /// being read, if any.
+    fn corrupt(&mut self, range: Range<u64>) {
+        self.end_batch();
+        let len = range.end - range.start;
+        self.report.corrupt_bytes += len;
+        self.report.corrupt_records.push(CorruptRecord {
+            fid: self.fid,
+            pos: range.start,
+            len,
+        });
+    }
+
+    /// Report the batch being read, if any, as incomplete.
+    fn end_batch(&mut self) {
+        if let Some((pos, len, found)) = self.batch.take() {
+            self.report
+                .inconsistencies
+                .push(Inconsistency::IncompleteBatch {
+                    fid: self.fid,
+                    pos,
+                    len,
+                    found,
+                });
+        }
+    }
+
+    fn finish(mut self) {
+        self.end_batch();
+    }
+}
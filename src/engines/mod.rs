@@ -1,8 +1,23 @@
+use std::sync::Arc;
+
 use crate::Result;
 
+mod any;
+#[cfg(feature = "tokio")]
+mod async_engine;
 mod bitcask;
+mod shared;
 mod sled;
-pub use self::bitcask::Bitcask;
+pub use self::any::{open_engine, AnyEngine};
+#[cfg(feature = "tokio")]
+pub use self::async_engine::{AsyncKvsEngine, SpawnBlocking};
+pub use self::bitcask::{
+    inspect_log, inspect_log_with_naming, verify, verify_with_naming, Bitcask, BitcaskOptions,
+    Clock, Cmd, CompactionHook, CompactionReport, CompactionStats, CorruptRecord, Encoding,
+    Inconsistency, IntegrityReport, KeyComparator, LogFileInfo, LogNaming, LogRecord, Stats,
+    SyncPolicy, ValueMeta, WriteStall,
+};
+pub use self::shared::{KvsStore, SharedEngine};
 pub use self::sled::SledKvsEngine;
 
 /// Defines the storage interface called by KvsServer
@@ -17,6 +32,26 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Returns `None` if the given key does not exist.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Get the values of `keys`, in the same order.
+    ///
+    /// Missing keys are `None`. The default implementation calls `get` for each key.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Whether the given key exists.
+    ///
+    /// Unlike `get`, this does not need to read the value.
+    fn contains_key(&self, key: String) -> Result<bool>;
+
+    /// The number of keys in the store.
+    fn len(&self) -> Result<usize>;
+
+    /// Whether the store has no keys.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
     /// Remove a given key
     ///   
     /// ## Errors
@@ -25,4 +60,160 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It propagates I/O or serialization errors during writing the log.
     fn rm(&self, key: String) -> Result<()>;
+
+    /// Atomically replace the value of `key` with `new` if it currently is `expected`.
+    ///
+    /// `None` as `expected` means the key must be absent, and `None` as `new` removes
+    /// the key. Returns whether the swap happened.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+
+    /// Get the value of `key`, first setting it to `default()` if the key does not exist.
+    ///
+    /// `default` is only called if the key is absent. Concurrent callers all get the
+    /// same value: the default implementation sets the key with
+    /// [compare_and_swap](KvsEngine::compare_and_swap) and returns the value of the
+    /// winner if another write got in first.
+    fn get_or_set(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = default();
+        loop {
+            if self.compare_and_swap(key.clone(), None, Some(value.clone()))? {
+                return Ok(value);
+            }
+            if let Some(value) = self.get(key.clone())? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Apply a batch of writes atomically, in order.
+    ///
+    /// Either all operations are applied or none is.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if a [BatchOp::Rm] removes a key which neither
+    /// exists nor is set by an earlier operation of the batch. Nothing is written then.
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()>;
+
+    /// Call `f` with the key/value pairs whose keys start with `prefix`, ordered by key.
+    ///
+    /// The pairs are handed over one by one instead of being collected, so that e.g.
+    /// [KvsServer](crate::KvsServer) streams them to the client with bounded memory.
+    /// An error returned by `f` stops the scan and is returned.
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Remove all keys.
+    fn clear(&self) -> Result<()>;
+
+    /// Reclaim the space of stale data now, instead of waiting for the engine to do so.
+    fn compact(&self) -> Result<()>;
+
+    /// Bytes of stale data which the next compaction reclaims, if the engine tracks them.
+    ///
+    /// The default implementation returns `None`.
+    fn uncompacted_bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A single write of a batch, see [KvsEngine::write_batch].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set the value of a key.
+    Set {
+        /// The key to set.
+        key: String,
+        /// The new value.
+        value: String,
+    },
+    /// Remove a key.
+    Rm {
+        /// The key to remove.
+        key: String,
+    },
+}
+
+/// Shares a single engine instance between all its clones.
+///
+/// This suits engines which are expensive to clone, or whose clones would not share
+/// their state: wrap the engine once and hand the `Arc` to [KvsServer](crate::KvsServer).
+/// The engine must still be `Clone`; a store which is not implements [KvsStore] and
+/// is wrapped in a [SharedEngine] instead.
+impl<E: KvsEngine + Sync> KvsEngine for Arc<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        (**self).get_many(keys)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        (**self).contains_key(key)
+    }
+
+    fn len(&self) -> Result<usize> {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        (**self).is_empty()
+    }
+
+    fn rm(&self, key: String) -> Result<()> {
+        (**self).rm(key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        (**self).compare_and_swap(key, expected, new)
+    }
+
+    fn get_or_set(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        (**self).get_or_set(key, default)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        (**self).write_batch(ops)
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        (**self).for_each_prefix(prefix, f)
+    }
+
+    fn clear(&self) -> Result<()> {
+        (**self).clear()
+    }
+
+    fn compact(&self) -> Result<()> {
+        (**self).compact()
+    }
+
+    fn uncompacted_bytes(&self) -> Option<u64> {
+        (**self).uncompacted_bytes()
+    }
 }
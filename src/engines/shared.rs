@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use crate::{BatchOp, KvsEngine, Result};
+
+/// The operations of a [KvsEngine] for a store which is not `Clone`.
+///
+/// Such a store is served by wrapping it once in a [SharedEngine]. The methods match
+/// those of [KvsEngine], see there for what they do.
+pub trait KvsStore: Send + Sync + 'static {
+    /// See [KvsEngine::set].
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// See [KvsEngine::get].
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// See [KvsEngine::get_many].
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// See [KvsEngine::contains_key].
+    fn contains_key(&self, key: String) -> Result<bool>;
+
+    /// See [KvsEngine::len].
+    fn len(&self) -> Result<usize>;
+
+    /// See [KvsEngine::is_empty].
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// See [KvsEngine::rm].
+    fn rm(&self, key: String) -> Result<()>;
+
+    /// See [KvsEngine::compare_and_swap].
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+
+    /// See [KvsEngine::get_or_set].
+    fn get_or_set(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = default();
+        loop {
+            if self.compare_and_swap(key.clone(), None, Some(value.clone()))? {
+                return Ok(value);
+            }
+            if let Some(value) = self.get(key.clone())? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// See [KvsEngine::write_batch].
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()>;
+
+    /// See [KvsEngine::for_each_prefix].
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()>;
+
+    /// See [KvsEngine::clear].
+    fn clear(&self) -> Result<()>;
+
+    /// See [KvsEngine::compact].
+    fn compact(&self) -> Result<()>;
+
+    /// See [KvsEngine::uncompacted_bytes].
+    fn uncompacted_bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A [KvsStore] shared between all clones of the engine.
+///
+/// This makes a [KvsEngine] of a store which cannot be cloned, e.g. to hand it to a
+/// [KvsServer](crate::KvsServer). Cloning only clones the reference to the store.
+pub struct SharedEngine<S: KvsStore> {
+    store: Arc<S>,
+}
+
+impl<S: KvsStore> SharedEngine<S> {
+    /// Wrap `store`.
+    pub fn new(store: S) -> Self {
+        SharedEngine {
+            store: Arc::new(store),
+        }
+    }
+
+    /// The shared store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S: KvsStore> Clone for SharedEngine<S> {
+    fn clone(&self) -> Self {
+        SharedEngine {
+            store: Arc::clone(&self.store),
+        }
+    }
+}
+
+impl<S: KvsStore> KvsEngine for SharedEngine<S> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.store.get_many(keys)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.store.contains_key(key)
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.store.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.store.is_empty()
+    }
+
+    fn rm(&self, key: String) -> Result<()> {
+        self.store.rm(key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.store.compare_and_swap(key, expected, new)
+    }
+
+    fn get_or_set(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        self.store.get_or_set(key, default)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.store.write_batch(ops)
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        self.store.for_each_prefix(prefix, f)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.store.clear()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.store.compact()
+    }
+
+    fn uncompacted_bytes(&self) -> Option<u64> {
+        self.store.uncompacted_bytes()
+    }
+}
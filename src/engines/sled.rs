@@ -1,6 +1,12 @@
-use sled::Db;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Db,
+};
 
-use crate::{KvsEngine, KvsError};
+use crate::{BatchOp, KvsEngine, KvsError};
 
 /// Kvs engine implementation by seld database
 #[derive(Clone)]
@@ -11,6 +17,65 @@ impl SledKvsEngine {
     pub fn new(db: Db) -> SledKvsEngine {
         SledKvsEngine(db)
     }
+
+    /// Run `f` as a transaction over the whole database.
+    ///
+    /// Writes made through the `TransactionalTree` are applied atomically once `f`
+    /// returns `Ok`. Returning `Err(ConflictableTransactionError::Abort(e))` discards
+    /// all of them and `e` is returned from this method.
+    ///
+    /// ## Retries
+    ///
+    /// sled may run `f` more than once when it conflicts with a concurrent
+    /// transaction, so `f` must be free of side effects outside the transaction.
+    pub fn transaction<F, R>(&self, f: F) -> crate::Result<R>
+    where
+        F: Fn(&TransactionalTree) -> ConflictableTransactionResult<R, KvsError>,
+    {
+        self.0.transaction(f).map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => KvsError::Sled(e),
+        })
+    }
+
+    /// Set the value of a string key, returning its previous value if it had one.
+    pub fn set_returning_old(&self, key: String, value: String) -> crate::Result<Option<String>> {
+        Ok(self
+            .0
+            .insert(&key, value.as_bytes())?
+            .map(|v| String::from_utf8(v.as_ref().to_vec()))
+            .transpose()?)
+    }
+
+    /// Move the value of `from` to `to`, replacing any value of `to`, in one transaction.
+    ///
+    /// ## Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `from` does not exist.
+    pub fn rename(&self, from: String, to: String) -> crate::Result<()> {
+        self.transaction(|tx| {
+            let value = tx
+                .get(from.as_str())?
+                .ok_or(ConflictableTransactionError::Abort(KvsError::KeyNotFound))?;
+            if from != to {
+                tx.insert(to.as_str(), value)?;
+                tx.remove(from.as_str())?;
+            }
+            Ok(())
+        })?;
+        self.0.flush()?;
+        Ok(())
+    }
+
+    /// Remove a given key, returning its value. An absent key is not an error, unlike
+    /// in `rm`: `None` is returned.
+    pub fn rm_returning_old(&self, key: String) -> crate::Result<Option<String>> {
+        let old = self.0.remove(&key)?;
+        self.0.flush()?;
+        Ok(old
+            .map(|v| String::from_utf8(v.as_ref().to_vec()))
+            .transpose()?)
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
@@ -28,9 +93,83 @@ impl KvsEngine for SledKvsEngine {
             .transpose()?)
     }
 
+    fn contains_key(&self, key: String) -> crate::Result<bool> {
+        Ok(self.0.contains_key(&key)?)
+    }
+
+    /// Counts the keys by going through all of them, which sled does not track.
+    fn len(&self) -> crate::Result<usize> {
+        Ok(self.0.len())
+    }
+
+    fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.0.is_empty())
+    }
+
     fn rm(&self, key: String) -> crate::Result<()> {
         self.0.remove(&key)?.ok_or(KvsError::KeyNotFound)?;
         self.0.flush()?;
         Ok(())
     }
+
+    /// Uses sled's own compare-and-swap.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> crate::Result<bool> {
+        Ok(self
+            .0
+            .compare_and_swap(key, expected.as_deref(), new.as_deref())?
+            .is_ok())
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> crate::Result<()> {
+        self.transaction(|tx| {
+            for op in &ops {
+                match op {
+                    BatchOp::Set { key, value } => {
+                        tx.insert(key.as_str(), value.as_bytes())?;
+                    }
+                    BatchOp::Rm { key } => {
+                        if tx.remove(key.as_str())?.is_none() {
+                            return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        self.0.flush()?;
+        Ok(())
+    }
+
+    /// Walks sled's ordered tree, so only the pair being handed over is in memory.
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        mut f: impl FnMut(String, String) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        for pair in self.0.scan_prefix(&prefix) {
+            let (key, value) = pair?;
+            f(
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> crate::Result<()> {
+        self.0.clear()?;
+        self.0.flush()?;
+        Ok(())
+    }
+
+    /// sled reclaims space on its own, so this only flushes pending writes.
+    fn compact(&self) -> crate::Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
 }
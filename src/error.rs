@@ -1,5 +1,6 @@
-use std::string::FromUtf8Error;
+use std::{path::PathBuf, string::FromUtf8Error};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 /// Error type for kvs.
@@ -11,6 +12,9 @@ pub enum KvsError {
     ///  Serialization or deserialization error.
     #[error("{0}")]
     Serde(#[from] serde_json::Error),
+    /// Binary serialization or deserialization error.
+    #[error("{0}")]
+    Bincode(#[from] bincode::Error),
     /// Removing non-existent key error.
     #[error("Key not found")]
     KeyNotFound,
@@ -18,15 +22,150 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[error("Unexpected command type")]
     Unknown,
+    /// A log record is incomplete or does not match its checksum.
+    #[error("Corrupted record at byte {pos} of log {fid}")]
+    ChecksumMismatch {
+        /// The log file of the record.
+        fid: u64,
+        /// Where the record starts in the log file.
+        pos: u64,
+    },
     /// Error with a string message
     #[error("{0}")]
     StringError(String),
+    /// TLS error, e.g. a failed handshake.
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
     /// Sled error
     #[error("sled error: {0}")]
     Sled(#[from] sled::Error),
     /// Key or value is invalid UTF-8 sequence
     #[error("UTF-8 error: {0}")]
     Utf8(#[from] FromUtf8Error),
+    /// A key longer than [BitcaskOptions::max_key_bytes](crate::BitcaskOptions::max_key_bytes).
+    #[error("Key of {size} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge {
+        /// Length of the key.
+        size: usize,
+        /// The configured limit.
+        limit: usize,
+    },
+    /// A value longer than [BitcaskOptions::max_value_bytes](crate::BitcaskOptions::max_value_bytes).
+    #[error("Value of {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge {
+        /// Length of the value.
+        size: usize,
+        /// The configured limit.
+        limit: usize,
+    },
+    /// A request on a connection which did not authenticate with the server's token,
+    /// see [KvsServer::auth_token](crate::KvsServer::auth_token).
+    #[error("Unauthorized")]
+    Unauthorized,
+    /// The data directory is opened for writing by another [Bitcask](crate::Bitcask),
+    /// possibly in another process.
+    #[error("Data directory {0:?} is opened by another process")]
+    DirectoryLocked(PathBuf),
+    /// A write to a store opened read-only.
+    #[error("The store is opened read-only")]
+    ReadOnly,
+    /// A request failed on the server, see [KvsClient](crate::KvsClient).
+    ///
+    /// A missing key is reported as [KvsError::KeyNotFound] instead.
+    #[error("{message}")]
+    Remote {
+        /// The classification of the error on the server.
+        code: ErrorCode,
+        /// The error as displayed by the server.
+        message: String,
+    },
+    /// A request which the server could not parse, see
+    /// [ServerOptions::malformed_requests](crate::ServerOptions::malformed_requests).
+    #[error("Malformed request: {0}")]
+    MalformedRequest(serde_json::Error),
+    /// The server speaks another version of the protocol than the client.
+    #[error(
+        "Protocol version mismatch: client speaks version {client}, \
+         server speaks version {server} (rskv {server_crate_version})"
+    )]
+    VersionMismatch {
+        /// Protocol version of the client.
+        client: u32,
+        /// Protocol version of the server.
+        server: u32,
+        /// Crate version of the server.
+        server_crate_version: String,
+    },
+}
+
+impl KvsError {
+    /// Classify this error, e.g. for counting failures.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Serde(_)
+            | KvsError::Bincode(_)
+            | KvsError::Unknown
+            | KvsError::ChecksumMismatch { .. }
+            | KvsError::Utf8(_) => ErrorCode::Corrupt,
+            KvsError::Sled(sled::Error::Io(_)) => ErrorCode::Io,
+            KvsError::Sled(sled::Error::Corruption { .. }) => ErrorCode::Corrupt,
+            KvsError::Unauthorized => ErrorCode::Unauthorized,
+            KvsError::MalformedRequest(_) => ErrorCode::Malformed,
+            KvsError::Remote { code, .. } => *code,
+            #[cfg(feature = "tls")]
+            KvsError::Tls(_) => ErrorCode::Other,
+            KvsError::Sled(_)
+            | KvsError::StringError(_)
+            | KvsError::ReadOnly
+            | KvsError::DirectoryLocked(_)
+            | KvsError::KeyTooLarge { .. }
+            | KvsError::ValueTooLarge { .. }
+            | KvsError::VersionMismatch { .. } => ErrorCode::Other,
+        }
+    }
+}
+
+/// Coarse classification of a [KvsError], see [KvsError::code].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The key does not exist.
+    KeyNotFound,
+    /// An I/O error.
+    Io,
+    /// Stored data could not be decoded, e.g. a corrupted log.
+    Corrupt,
+    /// The connection is not authenticated.
+    Unauthorized,
+    /// The request could not be parsed.
+    Malformed,
+    /// Any other error.
+    Other,
+}
+
+impl ErrorCode {
+    pub(crate) const ALL: [ErrorCode; 6] = [
+        ErrorCode::KeyNotFound,
+        ErrorCode::Io,
+        ErrorCode::Corrupt,
+        ErrorCode::Unauthorized,
+        ErrorCode::Malformed,
+        ErrorCode::Other,
+    ];
+
+    /// The name of the variant, e.g. for the status of a request in a span.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ErrorCode::KeyNotFound => "KeyNotFound",
+            ErrorCode::Io => "Io",
+            ErrorCode::Corrupt => "Corrupt",
+            ErrorCode::Unauthorized => "Unauthorized",
+            ErrorCode::Malformed => "Malformed",
+            ErrorCode::Other => "Other",
+        }
+    }
 }
 
 /// Custom result type for KvsError
@@ -0,0 +1,208 @@
+//! A minimal HTTP endpoint serving the metrics of a server in the Prometheus text
+//! format, see [KvsServer::with_metrics_addr](crate::KvsServer::with_metrics_addr).
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{error, info};
+
+use crate::{metrics::Metrics, KvsEngine, Result};
+
+/// How often the idle exporter checks whether its server stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a scraper may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest request head read, anything longer is cut off.
+const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+
+/// What the exporter reports: the engine and the counters of its server.
+pub(crate) struct Sources<E: KvsEngine> {
+    pub(crate) engine: E,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) active: Arc<AtomicUsize>,
+}
+
+/// The thread serving the metrics page, stopped and joined when dropped.
+pub(crate) struct Exporter {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// Listen on `addr` and serve the metrics of `sources` on a dedicated thread.
+    ///
+    /// Scrapes are rare, so they are served one at a time.
+    pub(crate) fn spawn<E: KvsEngine>(
+        addr: impl ToSocketAddrs,
+        sources: Sources<E>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        info!("Serving metrics on {:?}", local_addr);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("kvs-metrics".to_owned())
+                .spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        match listener.accept() {
+                            Ok((stream, _)) => {
+                                if let Err(e) = serve_scrape(stream, &sources) {
+                                    error!("Serving metrics failed: {}", e);
+                                }
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                thread::sleep(POLL_INTERVAL)
+                            }
+                            Err(e) => error!("Accepting a metrics connection failed: {}", e),
+                        }
+                    }
+                })?
+        };
+        Ok(Exporter {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer one HTTP request: the metrics page for `GET /metrics` or `GET /`, a 404
+/// otherwise. The connection is closed afterwards.
+fn serve_scrape<E: KvsEngine>(stream: TcpStream, sources: &Sources<E>) -> Result<()> {
+    // accepted sockets may inherit the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed, but are read so that the client sees its request consumed
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => ("200 OK", render(sources)),
+        _ => ("404 Not Found", "Not Found\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// The metrics page in the Prometheus text format.
+///
+/// The key count comes from [KvsEngine::len], which goes through all keys of some
+/// engines. Engine metrics which cannot be read are left out.
+fn render<E: KvsEngine>(sources: &Sources<E>) -> String {
+    let mut page = String::new();
+    let snapshot = sources.metrics.snapshot();
+
+    header(
+        &mut page,
+        "kvs_requests_total",
+        "counter",
+        "Requests served, including failed ones.",
+    );
+    for op in &snapshot.ops {
+        let command = format!("{:?}", op.command).to_lowercase();
+        writeln!(
+            page,
+            "kvs_requests_total{{command=\"{}\"}} {}",
+            command, op.requests
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut page,
+        "kvs_request_errors_total",
+        "counter",
+        "Failed requests, by command and kind of error.",
+    );
+    for count in sources.metrics.errors.info().errors {
+        let command = format!("{:?}", count.command).to_lowercase();
+        writeln!(
+            page,
+            "kvs_request_errors_total{{command=\"{}\",code=\"{}\"}} {}",
+            command,
+            count.code.name(),
+            count.count
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut page,
+        "kvs_active_connections",
+        "gauge",
+        "Connections being served.",
+    );
+    writeln!(
+        page,
+        "kvs_active_connections {}",
+        sources.active.load(Ordering::SeqCst)
+    )
+    .unwrap();
+
+    match sources.engine.len() {
+        Ok(len) => {
+            header(&mut page, "kvs_keys", "gauge", "Keys in the store.");
+            writeln!(page, "kvs_keys {}", len).unwrap();
+        }
+        Err(e) => error!("Counting the keys failed: {}", e),
+    }
+
+    if let Some(uncompacted) = sources.engine.uncompacted_bytes() {
+        header(
+            &mut page,
+            "kvs_uncompacted_bytes",
+            "gauge",
+            "Bytes of stale data which the next compaction reclaims.",
+        );
+        writeln!(page, "kvs_uncompacted_bytes {}", uncompacted).unwrap();
+    }
+
+    page
+}
+
+fn header(page: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(page, "# HELP {} {}", name, help).unwrap();
+    writeln!(page, "# TYPE {} {}", name, kind).unwrap();
+}
@@ -1,31 +1,224 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+#[cfg(feature = "tokio")]
+mod async_server;
 mod client;
 mod engines;
 mod error;
+mod exporter;
+mod metrics;
+pub mod prelude;
+mod redis;
 mod resp;
 mod server;
 pub mod thread_pool;
+mod trace;
+mod transport;
 
-pub use client::KvsClient;
-pub use engines::{Bitcask, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
-pub use server::KvsServer;
+#[cfg(feature = "tokio")]
+pub use async_server::AsyncKvsServer;
+pub use client::{Batch, KvsClient, ReconnectingClient, Response, Scan};
+pub use engines::{
+    inspect_log, inspect_log_with_naming, open_engine, verify, verify_with_naming, AnyEngine,
+    BatchOp, Bitcask, BitcaskOptions, Clock, Cmd, CompactionHook, CompactionReport,
+    CompactionStats, CorruptRecord, Encoding, Inconsistency, IntegrityReport, KeyComparator,
+    KvsEngine, KvsStore, LogFileInfo, LogNaming, LogRecord, SharedEngine, SledKvsEngine, Stats,
+    SyncPolicy, ValueMeta, WriteStall,
+};
+#[cfg(feature = "tokio")]
+pub use engines::{AsyncKvsEngine, SpawnBlocking};
+pub use error::{ErrorCode, KvsError, Result};
+pub use metrics::{Command, ErrorCount, LatencyBucket, OpMetrics, ServerInfo, ServerMetrics};
+pub use resp::{ValueEncoding, PROTOCOL_VERSION};
+pub use server::{Drainer, KvsServer, MalformedRequests, Protocol, ServerHandle, ServerOptions};
 
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use std::path::PathBuf;
+use log::warn;
 
-/// default kvstore data directory
+/// default kvstore data directory, see [kvstore_data_dir]
 pub fn get_kvstore_data_dir() -> PathBuf {
-    let mut dir = std::env::current_dir().unwrap();
-    dir.push("data/kvs");
-    dir
+    kvstore_data_dir(std::env::current_dir().unwrap())
 }
 
-/// default sled engine data directory
+/// default sled engine data directory, see [sled_data_dir]
 pub fn get_sled_data_dir() -> PathBuf {
-    let mut dir = std::env::current_dir().unwrap();
-    dir.push("data/sled");
-    dir
+    sled_data_dir(std::env::current_dir().unwrap())
+}
+
+/// The kvstore data directory of a server whose base directory is `base`.
+///
+/// The [get_kvstore_data_dir] default depends on the working directory, so a server
+/// should rather be given its base directory, e.g. when started by a service manager.
+pub fn kvstore_data_dir(base: impl AsRef<Path>) -> PathBuf {
+    base.as_ref().join("data/kvs")
+}
+
+/// The sled engine data directory of a server whose base directory is `base`, see
+/// [kvstore_data_dir].
+pub fn sled_data_dir(base: impl AsRef<Path>) -> PathBuf {
+    base.as_ref().join("data/sled")
+}
+
+/// The storage engines a server can run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    /// The [Bitcask] engine.
+    Kvs,
+    /// The [SledKvsEngine].
+    Sled,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    /// Parse an engine name, ignoring case, e.g. `kvs` or `Sled`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("kvs") {
+            Ok(EngineKind::Kvs)
+        } else if s.eq_ignore_ascii_case("sled") {
+            Ok(EngineKind::Sled)
+        } else {
+            Err(format!("Unknown engine {:?}, expected kvs or sled", s))
+        }
+    }
+}
+
+impl Display for EngineKind {
+    /// The name recorded in the `engine` marker file, see [detect_engine].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => f.write_str("Kvs"),
+            EngineKind::Sled => f.write_str("Sled"),
+        }
+    }
+}
+
+/// Detect the engine which last ran in `dir`, from the `engine` marker file there.
+///
+/// Returns `None` if no engine is recorded. A server should refuse to start with
+/// another engine than the recorded one, since each engine only reads its own data.
+pub fn detect_engine(dir: &Path) -> Result<Option<EngineKind>> {
+    read_engine_marker(dir.join("engine"))
+}
+
+/// Detect the engine of `dir` like [detect_engine], checking the `engine` marker file
+/// against the data directories of the engines, see [kvstore_data_dir] and
+/// [sled_data_dir], and correcting it.
+///
+/// * Without data of either engine, e.g. after the data directories were deleted to
+///   switch engines, a leftover marker is removed and `None` is returned.
+/// * With data of one engine only, a missing, unreadable or wrong marker is rewritten
+///   to name that engine.
+/// * With data of both engines, the marker decides, and one which is missing or
+///   unreadable is an error naming the marker file.
+///
+/// [Bitcask] data is recognized by log files named after `naming`, which must be the
+/// [BitcaskOptions::log_naming] the store is opened with.
+pub fn reconcile_engine(dir: &Path, naming: &LogNaming) -> Result<Option<EngineKind>> {
+    let marker = dir.join("engine");
+    let recorded = read_engine_marker::<EngineKind>(&marker);
+    let kvs_dir = kvstore_data_dir(dir);
+    let sled_dir = sled_data_dir(dir);
+    let found = match (has_kvs_data(&kvs_dir, naming)?, has_entries(&sled_dir)?) {
+        (false, false) => {
+            match fs::remove_file(&marker) {
+                Ok(()) => warn!("Removed {:?}, as {:?} holds no data", marker, dir),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            return Ok(None);
+        }
+        (true, true) => {
+            return match recorded? {
+                Some(kind) => Ok(Some(kind)),
+                None => Err(KvsError::StringError(format!(
+                    "Both {:?} and {:?} hold data, write the engine to use to {:?}",
+                    kvs_dir, sled_dir, marker
+                ))),
+            };
+        }
+        (true, false) => EngineKind::Kvs,
+        (false, true) => EngineKind::Sled,
+    };
+    match recorded {
+        Ok(Some(kind)) if kind == found => {}
+        recorded => {
+            let recorded = match recorded {
+                Ok(Some(kind)) => kind.to_string(),
+                Ok(None) => "no engine".to_owned(),
+                Err(e) => e.to_string(),
+            };
+            warn!(
+                "Correcting {:?} to {}, as only {} data is in {:?}, it recorded: {}",
+                marker, found, found, dir, recorded
+            );
+            fs::write(&marker, found.to_string())?;
+        }
+    }
+    Ok(Some(found))
+}
+
+/// Whether `dir` holds log files of a [Bitcask] named after `naming`.
+fn has_kvs_data(dir: &Path, naming: &LogNaming) -> Result<bool> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        if name
+            .to_str()
+            .and_then(|name| naming.parse(name, &naming.extension))
+            .is_some()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `dir` exists and is not empty.
+fn has_entries(dir: &Path) -> Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().transpose()?.is_some()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the engine recorded in the marker file at `path`.
+///
+/// A missing file, or one that is empty or only whitespace, e.g. after an interrupted
+/// write, means no engine is recorded and returns `None`. Contents which do not parse
+/// as `T` are an error: guessing could open the data with the wrong engine.
+pub fn read_engine_marker<T>(path: impl AsRef<Path>) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let path = path.as_ref();
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let content = content.trim();
+    if content.is_empty() {
+        return Ok(None);
+    }
+    content.parse().map(Some).map_err(|e| {
+        KvsError::StringError(format!(
+            "Invalid engine file {:?} with content {:?}: {}",
+            path, content, e
+        ))
+    })
 }
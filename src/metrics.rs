@@ -0,0 +1,212 @@
+use std::{
+    iter,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ErrorCode, KvsError, Result};
+
+/// The commands served by [KvsServer](crate::KvsServer).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Get the value of a key.
+    Get,
+    /// Set the value of a key.
+    Set,
+    /// Remove a key.
+    Rm,
+    /// Apply several writes atomically.
+    Transaction,
+    /// Stream the pairs whose keys start with a prefix.
+    Scan,
+}
+
+impl Command {
+    const ALL: [Command; 5] = [
+        Command::Get,
+        Command::Set,
+        Command::Rm,
+        Command::Transaction,
+        Command::Scan,
+    ];
+}
+
+/// Information about a running server, see [KvsClient::info](crate::KvsClient::info).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Failed requests by command and error kind. Pairs that never failed are left out.
+    pub errors: Vec<ErrorCount>,
+    /// Number of connections being served, including the one asking.
+    #[serde(default)]
+    pub active_connections: usize,
+}
+
+impl ServerInfo {
+    /// How many `command` requests failed with a `code` error.
+    pub fn error_count(&self, command: Command, code: ErrorCode) -> u64 {
+        self.errors
+            .iter()
+            .find(|count| count.command == command && count.code == code)
+            .map_or(0, |count| count.count)
+    }
+}
+
+/// Number of requests of one command which failed with one kind of error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorCount {
+    /// The failed command.
+    pub command: Command,
+    /// The kind of error.
+    pub code: ErrorCode,
+    /// Number of failures.
+    pub count: u64,
+}
+
+/// Per `(command, error kind)` failure counters of the server.
+///
+/// Both keys are small fixed enums, so the counters are a plain array of atomics
+/// and recording an error never takes a lock.
+#[derive(Default)]
+pub(crate) struct ErrorCounters {
+    counts: [[AtomicU64; ErrorCode::ALL.len()]; Command::ALL.len()],
+}
+
+impl ErrorCounters {
+    /// Count a failed `command`.
+    pub(crate) fn record(&self, command: Command, err: &KvsError) {
+        self.counts[command as usize][err.code() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Snapshot the counters into a [ServerInfo].
+    pub(crate) fn info(&self) -> ServerInfo {
+        let errors = Command::ALL
+            .iter()
+            .flat_map(|&command| {
+                ErrorCode::ALL.iter().map(move |&code| ErrorCount {
+                    command,
+                    code,
+                    count: self.counts[command as usize][code as usize].load(Ordering::Relaxed),
+                })
+            })
+            .filter(|count| count.count > 0)
+            .collect();
+        ServerInfo {
+            errors,
+            active_connections: 0,
+        }
+    }
+}
+
+/// Upper bounds of the latency buckets of [OpMetrics::latency], the last bucket
+/// counting all slower requests.
+const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Request metrics of a server, see [KvsServer::metrics](crate::KvsServer::metrics).
+#[derive(Debug, Clone)]
+pub struct ServerMetrics {
+    /// The metrics of every command.
+    pub ops: Vec<OpMetrics>,
+}
+
+impl ServerMetrics {
+    /// The metrics of `command`.
+    pub fn op(&self, command: Command) -> &OpMetrics {
+        &self.ops[command as usize]
+    }
+}
+
+/// Request metrics of one command.
+#[derive(Debug, Clone)]
+pub struct OpMetrics {
+    /// The command.
+    pub command: Command,
+    /// Number of requests served, including failed ones.
+    pub requests: u64,
+    /// Number of failed requests.
+    pub errors: u64,
+    /// How long the engine took to serve the requests, as a histogram in increasing
+    /// order of latency.
+    pub latency: Vec<LatencyBucket>,
+}
+
+/// Requests served within a latency bound, see [OpMetrics::latency].
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyBucket {
+    /// Upper bound of the latencies in this bucket, `Duration::MAX` for the last one.
+    pub le: Duration,
+    /// Number of requests served slower than the previous bucket, up to `le`.
+    pub count: u64,
+}
+
+/// Request counters and latency histograms per command, plus the [ErrorCounters].
+///
+/// Like the error counters, they are plain arrays of atomics.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) errors: ErrorCounters,
+    requests: [AtomicU64; Command::ALL.len()],
+    latency: [[AtomicU64; LATENCY_BUCKETS.len() + 1]; Command::ALL.len()],
+}
+
+impl Metrics {
+    /// Count a `command` request which took `elapsed` and failed with `err`, if any.
+    pub(crate) fn record(&self, command: Command, elapsed: Duration, err: Option<&KvsError>) {
+        self.requests[command as usize].fetch_add(1, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS.partition_point(|&le| le < elapsed);
+        self.latency[command as usize][bucket].fetch_add(1, Ordering::Relaxed);
+        if let Some(err) = err {
+            self.errors.record(command, err);
+        }
+    }
+
+    /// Serve a `command` request with `f`, and count it.
+    pub(crate) fn time<T>(&self, command: Command, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let res = f();
+        self.record(command, start.elapsed(), res.as_ref().err());
+        res
+    }
+
+    /// Snapshot the counters into [ServerMetrics].
+    pub(crate) fn snapshot(&self) -> ServerMetrics {
+        let ops = Command::ALL
+            .iter()
+            .map(|&command| {
+                let latency = &self.latency[command as usize];
+                OpMetrics {
+                    command,
+                    requests: self.requests[command as usize].load(Ordering::Relaxed),
+                    errors: self.errors.counts[command as usize]
+                        .iter()
+                        .map(|count| count.load(Ordering::Relaxed))
+                        .sum(),
+                    latency: LATENCY_BUCKETS
+                        .iter()
+                        .chain(iter::once(&Duration::MAX))
+                        .zip(latency)
+                        .map(|(&le, count)| LatencyBucket {
+                            le,
+                            count: count.load(Ordering::Relaxed),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        ServerMetrics { ops }
+    }
+}
@@ -0,0 +1,15 @@
+//! The rskv prelude.
+//!
+//! `use rskv::prelude::*;` brings in what typical usage needs:
+//!
+//! * the [KvsEngine] and [ThreadPool] traits, so their methods resolve,
+//! * the engines [Bitcask] and [SledKvsEngine],
+//! * the [KvsClient] and [KvsServer],
+//! * the thread pools [NaiveThreadPool], [DropJoinThreadPool], [WorkStealingThreadPool]
+//!   and [RayonThreadPool],
+//! * the [Result] and [KvsError] types.
+
+pub use crate::thread_pool::{
+    DropJoinThreadPool, NaiveThreadPool, RayonThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+pub use crate::{Bitcask, KvsClient, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
@@ -0,0 +1,238 @@
+//! The Redis serialization protocol (RESP), so that Redis clients such as `redis-cli`
+//! can talk to the server, see [Protocol::Resp](crate::Protocol::Resp).
+//!
+//! Only the commands mapping onto [KvsEngine] are understood: `GET`, `SET` and `DEL`,
+//! plus `AUTH` if the server requires a token.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+use log::debug;
+
+use crate::{
+    metrics::Metrics,
+    server::{is_token, PeerInfo},
+    trace::Span,
+    transport::{SharedStream, Stream},
+    Command, KvsEngine, KvsError, Result,
+};
+
+/// Longest bulk string accepted, the same limit as Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most arguments accepted in one command.
+const MAX_ARGS: usize = 1024 * 1024;
+/// Longest header line, e.g. `*3` or `$5`, accepted.
+const MAX_LINE_LEN: u64 = 64;
+
+/// A reply to a command.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    /// A bulk string, `None` being the null bulk string of a missing key.
+    Bulk(Option<String>),
+    Integer(i64),
+    Error(String),
+}
+
+impl Reply {
+    /// The kind of an error reply, e.g. `ERR` or `NOAUTH`.
+    fn error(&self) -> Option<&str> {
+        match self {
+            Reply::Error(message) => message.split(' ').next(),
+            _ => None,
+        }
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            // an error is a single line
+            Reply::Error(msg) => write!(writer, "-{}\r\n", msg.replace(['\r', '\n'], " ")),
+        }
+    }
+}
+
+/// Serve the RESP commands sent on `io` until the client disconnects.
+///
+/// A malformed command is answered with a protocol error and closes the
+/// connection, as Redis does.
+pub(crate) fn handle_stream<E: KvsEngine>(
+    engine: E,
+    io: impl Stream,
+    peer: &PeerInfo,
+    metrics: &Metrics,
+    token: Option<&str>,
+) -> Result<()> {
+    let io = SharedStream::new(io);
+    let mut reader = BufReader::new(io.clone());
+    let mut writer = BufWriter::new(io);
+    let mut authenticated = token.is_none();
+
+    loop {
+        let (span, reply) = match read_command(&mut reader) {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => {
+                let op = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+                let span = Span::request(&op, args.get(1).map(Vec::len));
+                debug!(
+                    "Receive command from {}: {:?}",
+                    peer,
+                    String::from_utf8_lossy(&args[0])
+                );
+                let reply = if args[0].eq_ignore_ascii_case(b"AUTH") {
+                    let reply = auth(token, &args[1..]);
+                    authenticated |= matches!(reply, Reply::Simple(_));
+                    reply
+                } else if authenticated {
+                    execute(&engine, args, metrics)
+                } else {
+                    Reply::Error("NOAUTH Authentication required.".to_owned())
+                };
+                (span, reply)
+            }
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                debug!("Protocol error from {}: {}", peer, e);
+                Reply::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        reply.write_to(&mut writer)?;
+        // answer pipelined commands with a single write
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        span.record_status(reply.error());
+    }
+}
+
+/// Check the arguments of an `AUTH` command against the server's `token`.
+fn auth(token: Option<&str>, args: &[Vec<u8>]) -> Reply {
+    match (token, args) {
+        (None, _) => Reply::Error(
+            "ERR AUTH <password> called without any password configured for the default user"
+                .to_owned(),
+        ),
+        (Some(token), [given]) if is_token(token, &String::from_utf8_lossy(given)) => {
+            Reply::Simple("OK")
+        }
+        (Some(_), [_]) => Reply::Error("WRONGPASS invalid username-password pair".to_owned()),
+        (Some(_), _) => Reply::Error("ERR wrong number of arguments for 'auth' command".to_owned()),
+    }
+}
+
+/// Run a command and return its reply.
+fn execute<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>, metrics: &Metrics) -> Reply {
+    let mut args = args.into_iter();
+    let name = args.next().unwrap_or_default().to_ascii_uppercase();
+    let args: Vec<String> = match args.map(String::from_utf8).collect() {
+        Ok(args) => args,
+        Err(_) => return Reply::Error("ERR keys and values must be valid UTF-8".to_owned()),
+    };
+    let failed = |e: KvsError| Reply::Error(format!("ERR {}", e));
+
+    match (name.as_slice(), args.as_slice()) {
+        (b"GET", [key]) => match metrics.time(Command::Get, || engine.get(key.clone())) {
+            Ok(value) => Reply::Bulk(value),
+            Err(e) => failed(e),
+        },
+        (b"SET", [key, value]) => {
+            match metrics.time(Command::Set, || engine.set(key.clone(), value.clone())) {
+                Ok(()) => Reply::Simple("OK"),
+                Err(e) => failed(e),
+            }
+        }
+        (b"DEL", keys) if !keys.is_empty() => {
+            // counted as one request, missing keys not being an error
+            let res = metrics.time(Command::Rm, || {
+                let mut removed = 0;
+                for key in keys {
+                    match engine.rm(key.clone()) {
+                        Ok(()) => removed += 1,
+                        Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(removed)
+            });
+            match res {
+                Ok(removed) => Reply::Integer(removed),
+                Err(e) => failed(e),
+            }
+        }
+        (b"GET" | b"SET" | b"DEL", _) => Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            String::from_utf8_lossy(&name).to_lowercase()
+        )),
+        _ => Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&name)
+        )),
+    }
+}
+
+/// Read a command, an array of bulk strings. Returns `None` if the client
+/// disconnected before sending one.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => parse_len(count, MAX_ARGS)?,
+        None => return Err(protocol_error("expected '*'")),
+    };
+
+    let mut args = Vec::with_capacity(count.min(16));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => parse_len(len, MAX_BULK_LEN)?,
+            None => return Err(protocol_error("expected '$'")),
+        };
+        // read what arrives instead of allocating the announced length up front
+        let mut arg = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected CRLF after bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a header line without its CRLF, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match line.strip_suffix(b"\r\n") {
+        Some(content) => Ok(Some(content.to_vec())),
+        None if line.ends_with(b"\n") => Err(protocol_error("expected CRLF")),
+        None if line.len() as u64 == MAX_LINE_LEN => Err(protocol_error("line too long")),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Parse the length in a header line, at most `max`.
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -1,26 +1,264 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 
+use crate::{ErrorCode, KvsError, Result, ServerInfo};
+
+/// Version of the messages in this module, checked by the [Hello] handshake.
+///
+/// Bump it on any incompatible change, so that mismatched clients and servers
+/// refuse each other instead of misparsing messages.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// First message on a connection, sent by the client and answered by the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Version of the rskv crate of the sender, to make mismatches obvious in logs.
+    pub crate_version: String,
+    /// The encoding of values the client asks for, and the server answers with the one
+    /// it uses. Peers which predate it leave it out, which means [ValueEncoding::Utf8].
+    #[serde(default)]
+    pub value_encoding: ValueEncoding,
+}
+
+impl Hello {
+    /// The greeting of this build.
+    pub fn current() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+            value_encoding: ValueEncoding::Utf8,
+        }
+    }
+}
+
+/// How values are written in the requests and responses of a connection, agreed on
+/// by the handshake, see [KvsClient::connect_with_encoding](crate::KvsClient::connect_with_encoding).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueEncoding {
+    /// Values are sent as JSON strings, so they must be UTF-8.
+    #[default]
+    Utf8,
+    /// Values are sent base64-encoded, so they may hold any bytes.
+    ///
+    /// The server stores values as it receives them, so the store holds the encoded
+    /// text, which connections using [ValueEncoding::Utf8] read as is. The server
+    /// refuses values which are not valid base64.
+    Base64,
+}
+
+impl ValueEncoding {
+    /// Encode the string `value` to send it.
+    pub(crate) fn encode(self, value: String) -> String {
+        match self {
+            ValueEncoding::Utf8 => value,
+            ValueEncoding::Base64 => STANDARD.encode(value),
+        }
+    }
+
+    /// Encode `value` to send it, which fails for non-UTF-8 bytes without base64.
+    pub(crate) fn encode_bytes(self, value: Vec<u8>) -> Result<String> {
+        match self {
+            ValueEncoding::Utf8 => Ok(String::from_utf8(value)?),
+            ValueEncoding::Base64 => Ok(STANDARD.encode(value)),
+        }
+    }
+
+    /// Decode a received value which should be a string.
+    pub(crate) fn decode(self, value: String) -> Result<String> {
+        match self {
+            ValueEncoding::Utf8 => Ok(value),
+            ValueEncoding::Base64 => Ok(String::from_utf8(self.decode_bytes(value)?)?),
+        }
+    }
+
+    /// Decode a received value.
+    pub(crate) fn decode_bytes(self, value: String) -> Result<Vec<u8>> {
+        match self {
+            ValueEncoding::Utf8 => Ok(value.into_bytes()),
+            ValueEncoding::Base64 => decode_base64(&value),
+        }
+    }
+
+    /// Check that a received value is valid in this encoding.
+    pub(crate) fn check(self, value: &str) -> Result<()> {
+        match self {
+            ValueEncoding::Utf8 => Ok(()),
+            ValueEncoding::Base64 => decode_base64(value).map(drop),
+        }
+    }
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| KvsError::StringError(format!("Invalid base64 value: {}", e)))
+}
+
+/// Answer to a [Hello], carrying the server's own greeting.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok(Hello),
+    /// The server does not speak the client's version and closes the connection.
+    VersionMismatch(Hello),
+}
+
+/// A failed request, sent instead of its result.
+///
+/// The [ErrorCode] lets the client tell e.g. a missing key from a disk error
+/// without parsing the message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl From<&KvsError> for ErrorResponse {
+    fn from(err: &KvsError) -> Self {
+        ErrorResponse {
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<ErrorResponse> for KvsError {
+    fn from(err: ErrorResponse) -> Self {
+        match err.code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::Unauthorized => KvsError::Unauthorized,
+            code => KvsError::Remote {
+                code,
+                message: err.message,
+            },
+        }
+    }
+}
+
+/// Answer to a request which could not be parsed, so whose type is unknown.
+///
+/// It is sent as `{"Err": ...}`, which the response types of all requests but a
+/// [Request::Ping] read as their own `Err` variant.
+#[derive(Debug, Serialize)]
+pub enum MalformedResponse {
+    Err(ErrorResponse),
+}
+
+/// A string which is left out of logs, such as an auth token.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    Info,
+    /// A liveness check, answered without touching the engine.
+    Ping,
+    /// Authenticate the connection with the server's token.
+    Auth {
+        token: Secret,
+    },
+    /// Apply `commands` atomically. Only `Set` and `Rm` are allowed.
+    Transaction {
+        commands: Vec<Request>,
+    },
+    /// Get the pairs whose keys start with `prefix`, answered by a stream of
+    /// [ScanResponse]s.
+    Scan {
+        prefix: String,
+    },
+}
+
+impl Request {
+    /// Check that the values this request sets are valid in `encoding`.
+    pub(crate) fn check_values(&self, encoding: ValueEncoding) -> Result<()> {
+        match self {
+            Request::Set { value, .. } => encoding.check(value),
+            Request::Transaction { commands } => commands
+                .iter()
+                .try_for_each(|command| command.check_values(encoding)),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ErrorResponse),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(ErrorResponse),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(ErrorResponse),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InfoResponse {
+    Ok(ServerInfo),
+    Err(ErrorResponse),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(ErrorResponse),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Pong,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransactionResponse {
+    /// One result per command of the transaction, in the same order.
+    Ok(Vec<CommandResult>),
+    Err(ErrorResponse),
+}
+
+/// The result of a command of a [Request::Transaction].
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CommandResult {
+    Set,
+    Rm,
+}
+
+/// Answer to a [Request::Scan], sent as one message per pair, ordered by key, then
+/// [ScanResponse::End].
+///
+/// A scan failing midway ends with [ScanResponse::Err] instead, after the pairs sent
+/// so far.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    /// A key and its value.
+    Item(String, String),
+    /// No pairs are left.
+    End,
+    Err(ErrorResponse),
 }
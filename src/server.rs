@@ -1,79 +1,1147 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    fmt,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use log::{debug, error};
-use serde_json::Deserializer;
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::{Deserializer, Value};
+use socket2::{SockRef, TcpKeepalive};
 
 use crate::{
-    resp::{GetResponse, RemoveResponse, Request, SetResponse},
+    exporter::{Exporter, Sources},
+    metrics::Metrics,
+    redis,
+    resp::{
+        AuthResponse, CommandResult, GetResponse, Hello, HelloResponse, InfoResponse,
+        MalformedResponse, PingResponse, RemoveResponse, Request, ScanResponse, SetResponse,
+        TransactionResponse, PROTOCOL_VERSION,
+    },
     thread_pool::ThreadPool,
-    KvsEngine, Result,
+    trace::Span,
+    transport::{SharedStream, Stream},
+    BatchOp, Command, ErrorCode, KvsEngine, KvsError, Result, ServerInfo, ServerMetrics,
 };
 
+/// How often an idle accept loop checks for the shutdown signal or a free connection slot.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many pairs of a scan are buffered before they are flushed to the client.
+const SCAN_FLUSH_ITEMS: usize = 256;
+
+/// Options for a [KvsServer], see [KvsServer::with_options].
+#[derive(Debug, Clone, Copy)]
+pub struct ServerOptions {
+    /// How long to wait for the next request on a connection before closing it.
+    ///
+    /// `None`, the default, waits forever, so an idle client keeps its thread pool
+    /// worker busy until it disconnects.
+    pub read_timeout: Option<Duration>,
+    /// How long sending a response may block before the connection is closed.
+    ///
+    /// `None`, the default, waits forever.
+    pub write_timeout: Option<Duration>,
+    /// The wire protocol spoken with clients.
+    pub protocol: Protocol,
+    /// Set `TCP_NODELAY` on connections, so that small responses are sent right away
+    /// instead of being held back by Nagle's algorithm. Defaults to `true`.
+    pub nodelay: bool,
+    /// Enable `SO_KEEPALIVE` on connections, probing the peer after it was idle for
+    /// this long, so that connections to vanished clients are eventually closed.
+    ///
+    /// `None`, the default, leaves keep-alive off.
+    pub keepalive: Option<Duration>,
+    /// What to do with a request which cannot be parsed, with [Protocol::Json].
+    pub malformed_requests: MalformedRequests,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            read_timeout: None,
+            write_timeout: None,
+            protocol: Protocol::default(),
+            nodelay: true,
+            keepalive: None,
+            malformed_requests: MalformedRequests::default(),
+        }
+    }
+}
+
+/// How a [KvsServer] handles a request which cannot be parsed, see
+/// [ServerOptions::malformed_requests].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MalformedRequests {
+    /// Close the connection, dropping the requests sent after the malformed one.
+    #[default]
+    Close,
+    /// Answer with an error of [ErrorCode::Malformed] and go on with the next request,
+    /// so that one bad request does not fail the others pipelined with it.
+    ///
+    /// Each request is parsed into a JSON value first, which costs a little, so that a
+    /// well-formed value which is not a request is consumed whole. Input which is not
+    /// even JSON is answered too, but then the connection is closed, as where the next
+    /// request starts is unknown.
+    Reply,
+}
+
+/// Wire protocol of a [KvsServer].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// JSON requests and responses, as sent by [KvsClient](crate::KvsClient).
+    #[default]
+    Json,
+    /// The Redis serialization protocol, so that Redis clients such as `redis-cli`
+    /// can connect. Only the `GET`, `SET` and `DEL` commands are supported.
+    Resp,
+}
+
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    /// Id handed to the next accepted connection.
+    next_conn_id: AtomicU64,
+    metrics: Arc<Metrics>,
+    options: ServerOptions,
+    /// Connections served at once, further connections wait in the listen backlog.
+    max_connections: usize,
+    /// Threads accepting connections, see [KvsServer::acceptors].
+    acceptors: usize,
+    /// Number of connections being served.
+    active: Arc<AtomicUsize>,
+    /// Set to stop accepting while the open connections are finished, see
+    /// [KvsServer::drainer].
+    draining: Arc<AtomicBool>,
+    /// The token clients must authenticate with, if any.
+    auth_token: Option<Arc<str>>,
+    /// Serves the metrics over HTTP until the server stops, see
+    /// [KvsServer::with_metrics_addr].
+    exporter: Option<Exporter>,
+    /// Accept TLS connections only, see [KvsServer::run_tls].
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        Self::with_options(engine, pool, ServerOptions::default())
+    }
+
+    /// Create a `KvsServer` with a given storage engine and [ServerOptions].
+    pub fn with_options(engine: E, pool: P, options: ServerOptions) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            next_conn_id: AtomicU64::new(1),
+            metrics: Arc::new(Metrics::default()),
+            options,
+            max_connections: usize::MAX,
+            acceptors: 1,
+            active: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            auth_token: None,
+            exporter: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Serve at most `max` connections at once, which is unlimited by default.
+    ///
+    /// When the limit is reached the server stops accepting until one of the
+    /// connections is closed, so new clients wait in the listen backlog instead of
+    /// piling up on the thread pool.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Accept connections on `n` threads instead of one, for servers flooded with
+    /// short-lived connections.
+    ///
+    /// The threads share the listener and hand the connections they accept over to
+    /// the thread running the server, which dispatches them to the thread pool. Each
+    /// of them holds on to the connection it accepted until then, so up to `n`
+    /// connections may be accepted beyond [KvsServer::max_connections], though none is
+    /// served beyond it. With `n` of 0 or 1, the default, the thread running the
+    /// server accepts the connections itself.
+    ///
+    /// The connections are still dispatched one at a time through a rendezvous
+    /// channel, so only the `accept` calls overlap. On `cargo bench --bench server`
+    /// 4 acceptors did not beat 1 for concurrent clients; measure before raising it.
+    pub fn acceptors(mut self, n: usize) -> Self {
+        self.acceptors = n;
+        self
+    }
+
+    /// Require clients to authenticate with `token` before any other request, see
+    /// [KvsClient::connect_with_auth](crate::KvsClient::connect_with_auth).
+    ///
+    /// Requests on a connection which did not authenticate fail with
+    /// [KvsError::Unauthorized], except for pings. A connection sending a wrong token
+    /// is closed. Redis clients authenticate with the `AUTH` command.
+    ///
+    /// The token is sent in clear text, so it only keeps out clients on a trusted
+    /// network which do not know it.
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Serve the metrics of the server in the Prometheus text format over HTTP at
+    /// `addr`, e.g. for `curl http://<addr>/metrics`.
+    ///
+    /// The page reports the requests and failed requests per command, the open
+    /// connections, the number of keys, and the stale bytes of engines which track
+    /// them, see [KvsEngine::uncompacted_bytes]. The metrics are served on a dedicated
+    /// thread, from now on until the server stops.
+    ///
+    /// ## Errors
+    ///
+    /// It fails if `addr` cannot be bound.
+    pub fn with_metrics_addr<A: ToSocketAddrs>(mut self, addr: A) -> Result<Self> {
+        let sources = Sources {
+            engine: self.engine.clone(),
+            metrics: Arc::clone(&self.metrics),
+            active: Arc::clone(&self.active),
+        };
+        self.exporter = Some(Exporter::spawn(addr, sources)?);
+        Ok(self)
+    }
+
+    /// The address the metrics are served on, see [KvsServer::with_metrics_addr].
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.exporter.as_ref().map(Exporter::local_addr)
+    }
+
+    /// A handle to drain the server once it runs, e.g. during a rolling deploy.
+    ///
+    /// Draining stops accepting connections, closing the listener so that new clients
+    /// are refused, while the open connections are served until their clients
+    /// disconnect, or time out, see [ServerOptions::read_timeout]. The `run` method
+    /// then returns once the last of them is closed, whatever the thread pool.
+    ///
+    /// A hard shutdown, see [ServerHandle::shutdown] and
+    /// [KvsServer::run_with_shutdown], stops accepting too, but returns as soon as the
+    /// thread pool is dropped, so open connections are only waited for by pools which
+    /// join their workers on drop.
+    pub fn drainer(&self) -> Drainer {
+        Drainer {
+            draining: Arc::clone(&self.draining),
+            active: Arc::clone(&self.active),
+        }
+    }
+
+    /// Request counts, error counts and latencies per command, see [ServerHandle::metrics]
+    /// for a running server.
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.snapshot()
     }
 
     /// Running KvsServer on a certain ip address
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = handle_stream(engine, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
+        self.serve(listener, || false)
+    }
+
+    /// Run the server on a certain ip address, speaking [Protocol::Resp] instead of
+    /// the configured protocol.
+    pub fn run_resp<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+        self.options.protocol = Protocol::Resp;
+        self.run(addr)
+    }
+
+    /// Run the server on a certain ip address, serving TLS connections only, see
+    /// [KvsClient::connect_tls](crate::KvsClient::connect_tls).
+    ///
+    /// The TLS handshake happens on the thread pool along with the requests, so a slow
+    /// client does not hold up the accept loop. Both protocols work over TLS.
+    #[cfg(feature = "tls")]
+    pub fn run_tls<A: ToSocketAddrs>(
+        mut self,
+        addr: A,
+        server_config: Arc<rustls::ServerConfig>,
+    ) -> Result<()> {
+        self.tls = Some(server_config);
+        self.run(addr)
+    }
+
+    /// Serve the requests sent on `io` on the calling thread, until the client disconnects.
+    ///
+    /// This serves a connection accepted by other means than the `run` methods, e.g.
+    /// an in-memory pipe, in the configured protocol and with the same token and
+    /// metrics. `peer` labels the connection in logs, e.g. with the peer address.
+    /// Socket options and TLS are up to the caller.
+    pub fn serve_connection(
+        &self,
+        io: impl Read + Write + Send,
+        peer: Option<String>,
+    ) -> Result<()> {
+        let peer = PeerInfo::new(self.next_conn_id.fetch_add(1, Ordering::Relaxed), peer);
+        let _active = ConnectionGuard::new(&self.active);
+        handle_connection(
+            self.engine.clone(),
+            io,
+            self.options,
+            &peer,
+            &self.metrics,
+            &self.active,
+            self.auth_token.as_deref(),
+        )
+    }
+
+    /// Run the server on a background thread and return a [ServerHandle] to stop it.
+    ///
+    /// This is for embedding the server in a larger application, where [KvsServer::run]
+    /// would block the calling thread forever. Dropping the handle leaves the server
+    /// running.
+    pub fn spawn<A: ToSocketAddrs>(self, addr: A) -> Result<ServerHandle>
+    where
+        P: Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let drainer = self.drainer();
+        let metrics = Arc::clone(&self.metrics);
+        let thread = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || self.serve(listener, || shutdown.load(Ordering::SeqCst)))
+        };
+        Ok(ServerHandle {
+            local_addr,
+            shutdown,
+            drainer,
+            metrics,
+            thread,
+        })
+    }
+
+    /// Run the server on `addr` until a message arrives on `shutdown`, or its sender is dropped.
+    /// The listener is polled every 10 milliseconds, so the signal is noticed
+    /// promptly even when no clients connect. Once signaled, no new connections are
+    /// accepted and the thread pool is dropped before returning, so with a pool which
+    /// joins its workers on drop, such as
+    /// [DropJoinThreadPool](crate::thread_pool::DropJoinThreadPool), the requests in
+    /// flight are finished first.
+    pub fn run_with_shutdown<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        shutdown: Receiver<()>,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.serve(listener, signaled(shutdown))
+    }
+
+    /// Run the server on a Unix domain socket at `path`, see
+    /// [KvsClient::connect_unix](crate::KvsClient::connect_unix).
+    ///
+    /// This avoids the loopback TCP stack for clients on the same host. Binding fails
+    /// with an [io::ErrorKind::AddrInUse] error if another server listens on `path` or
+    /// something other than a socket is there, while a socket file left behind by a
+    /// server which did not stop cleanly is replaced. The socket file is removed when
+    /// the server stops.
+    #[cfg(unix)]
+    pub fn run_unix(self, path: impl AsRef<Path>) -> Result<()> {
+        let socket = UnixSocket::bind(path.as_ref())?;
+        self.serve(socket, || false)
+    }
+
+    /// Run the server on a Unix domain socket at `path` until a message arrives on
+    /// `shutdown`, or its sender is dropped, see [KvsServer::run_unix] and
+    /// [KvsServer::run_with_shutdown].
+    #[cfg(unix)]
+    pub fn run_unix_with_shutdown(
+        self,
+        path: impl AsRef<Path>,
+        shutdown: Receiver<()>,
+    ) -> Result<()> {
+        let socket = UnixSocket::bind(path.as_ref())?;
+        self.serve(socket, signaled(shutdown))
+    }
+
+    /// Accept connections on `listener` until `shutdown` returns true or the server is
+    /// drained.
+    ///
+    /// Accepting gives up every [POLL_INTERVAL] to notice either promptly, while a
+    /// connection is still accepted as soon as it arrives.
+    fn serve<L: Listener>(self, listener: L, shutdown: impl Fn() -> bool) -> Result<()> {
+        listener.set_accept_timeout(POLL_INTERVAL)?;
+        let draining = || self.draining.load(Ordering::SeqCst);
+        let stopped = || shutdown() || draining();
+        if self.acceptors > 1 {
+            self.accept_in_parallel(&listener, stopped);
+        } else {
+            loop {
+                if stopped() || !self.wait_for_slot(stopped) {
+                    break;
+                }
+                match listener.accept() {
+                    Ok(stream) => self.dispatch(Ok(stream)),
+                    Err(e) if is_accept_timeout(&e) => {}
+                    Err(e) => self.dispatch::<L::Conn>(Err(e)),
                 }
-                Err(e) => error!("Connection failed:: {}", e),
-            })
+            }
         }
+        drop(listener);
+        if draining() {
+            info!(
+                "Draining, waiting for {} open connections",
+                self.active.load(Ordering::SeqCst)
+            );
+            self.drainer().wait_idle(None);
+        } else {
+            debug!("Shutting down, waiting for in-flight requests");
+        }
+        drop(self.pool);
         Ok(())
     }
+
+    /// Accept connections on `listener` with [KvsServer::acceptors] threads until
+    /// `stopped` returns true, dispatching them on this thread.
+    fn accept_in_parallel<L: Listener>(&self, listener: &L, stopped: impl Fn() -> bool) {
+        let done = AtomicBool::new(false);
+        // a rendezvous channel, so that a connection is only accepted once the one
+        // before was dispatched
+        let (sender, receiver) = mpsc::sync_channel(0);
+        thread::scope(|s| {
+            for _ in 0..self.acceptors {
+                let sender = sender.clone();
+                let done = &done;
+                s.spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        match listener.accept() {
+                            Err(e) if is_accept_timeout(&e) => {}
+                            stream => {
+                                if sender.send(stream).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            while !stopped() && self.wait_for_slot(&stopped) {
+                match receiver.recv_timeout(POLL_INTERVAL) {
+                    Ok(stream) => self.dispatch(stream),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+            // fail the sends of acceptors holding a connection
+            drop(receiver);
+        });
+    }
+
+    /// Wait until fewer than `max_connections` connections are open.
+    ///
+    /// Returns `false` if `stopped` turned true while waiting.
+    fn wait_for_slot(&self, stopped: impl Fn() -> bool) -> bool {
+        while self.active.load(Ordering::SeqCst) >= self.max_connections {
+            if stopped() {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        true
+    }
+
+    /// Serve an accepted connection on the thread pool.
+    fn dispatch<S: Connection>(&self, stream: io::Result<S>) {
+        let engine = self.engine.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let conn_id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        let options = self.options;
+        let token = self.auth_token.clone();
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let active = ConnectionGuard::new(&self.active);
+        self.pool.spawn(move || match stream {
+            Ok(stream) => {
+                let label = match stream.peer_label() {
+                    Ok(label) => label,
+                    Err(e) => {
+                        debug!("Connection #{} has no peer address: {}", conn_id, e);
+                        None
+                    }
+                };
+                let peer = PeerInfo::new(conn_id, label);
+                debug!("Accepted connection {}", peer);
+                let token = token.as_deref();
+                let res = stream.configure(options).and_then(|()| {
+                    #[cfg(feature = "tls")]
+                    if let Some(config) = tls {
+                        let conn = rustls::ServerConnection::new(config)?;
+                        let stream = rustls::StreamOwned::new(conn, stream);
+                        return handle_connection(
+                            engine, stream, options, &peer, &metrics, &active.0, token,
+                        );
+                    }
+                    handle_connection(engine, stream, options, &peer, &metrics, &active.0, token)
+                });
+                match res {
+                    Ok(()) => {}
+                    Err(e) if is_timeout(&e) => info!("Connection {} timed out", peer),
+                    Err(e) => error!("Error on serving client {}: {}", peer, e),
+                }
+                debug!(
+                    "Connection {} closed after {:?}",
+                    peer,
+                    peer.connected_at.elapsed()
+                );
+            }
+            Err(e) => error!("Connection failed:: {}", e),
+        })
+    }
 }
 
-fn handle_stream<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let peer_addr = stream.peer_addr()?;
-    let reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-    let req_deserialzer = Deserializer::from_reader(reader).into_iter::<Request>();
+/// Whether a message arrived on `shutdown`, or its sender was dropped.
+fn signaled(shutdown: Receiver<()>) -> impl Fn() -> bool {
+    move || {
+        matches!(
+            shutdown.try_recv(),
+            Ok(()) | Err(TryRecvError::Disconnected)
+        )
+    }
+}
 
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-        }};
+/// Drains a server, see [KvsServer::drainer].
+#[derive(Clone)]
+pub struct Drainer {
+    draining: Arc<AtomicBool>,
+    active: Arc<AtomicUsize>,
+}
+
+impl Drainer {
+    /// Stop accepting connections, and let the open ones finish.
+    ///
+    /// The server notices within 10 milliseconds. Draining cannot be undone.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [Drainer::drain] was called on this or another handle of the server.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
     }
 
-    for req in req_deserialzer {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
+    /// Number of connections the server is serving right now.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the server serves no connection, for at most `timeout` if given.
+    ///
+    /// Returns whether the server became idle in time.
+    pub fn wait_idle(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while self.active_connections() > 0 {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        true
+    }
+}
+
+/// Handle of a server started by [KvsServer::spawn].
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    drainer: Drainer,
+    metrics: Arc<Metrics>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// The address the server listens on, e.g. to find the port picked for port `0`.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of connections the server is serving right now.
+    pub fn active_connections(&self) -> usize {
+        self.drainer.active_connections()
+    }
+
+    /// A handle to drain the server, see [KvsServer::drainer].
+    pub fn drainer(&self) -> Drainer {
+        self.drainer.clone()
+    }
+
+    /// Request counts, error counts and latencies per command served so far.
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Stop accepting connections and wait for the accept thread to exit.
+    ///
+    /// The thread pool is dropped on the way out, so with a pool which joins its
+    /// workers on drop, such as [DropJoinThreadPool](crate::thread_pool::DropJoinThreadPool),
+    /// this also waits for the open connections to be closed by their clients.
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.join()
+    }
+
+    /// Drain the server and wait until its open connections are closed by their
+    /// clients, see [KvsServer::drainer] for how this differs from
+    /// [ServerHandle::shutdown].
+    pub fn drain(self) -> Result<()> {
+        self.drainer.drain();
+        self.join()
+    }
+
+    fn join(self) -> Result<()> {
+        self.thread
+            .join()
+            .map_err(|_| KvsError::StringError("The server thread panicked".to_owned()))?
+    }
+}
+
+/// Counts a connection as active from when it is accepted until it is dropped,
+/// which happens when its job on the thread pool ends, however it ends.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(Arc::clone(active))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Per-connection state, created when a connection is accepted and
+/// passed along with every request served on it.
+#[derive(Debug, Clone)]
+pub(crate) struct PeerInfo {
+    /// Server-unique id of the connection, used to correlate logs.
+    pub(crate) id: u64,
+    /// Describes the peer, e.g. its address, if known.
+    pub(crate) label: Option<String>,
+    /// When the connection was accepted.
+    pub(crate) connected_at: Instant,
+}
+
+impl PeerInfo {
+    fn new(id: u64, label: Option<String>) -> Self {
+        PeerInfo {
+            id,
+            label,
+            connected_at: Instant::now(),
+        }
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "#{} ({})", self.id, label),
+            None => write!(f, "#{}", self.id),
+        }
+    }
+}
+
+/// Serve the requests sent on `io` in the given `protocol`.
+fn handle_connection<E: KvsEngine>(
+    engine: E,
+    io: impl Stream,
+    options: ServerOptions,
+    peer: &PeerInfo,
+    metrics: &Metrics,
+    active: &AtomicUsize,
+    token: Option<&str>,
+) -> Result<()> {
+    let _span = Span::connection(peer, options.protocol);
+    match options.protocol {
+        Protocol::Json => handle_stream(
+            engine,
+            io,
+            peer,
+            metrics,
+            active,
+            token,
+            options.malformed_requests,
+        ),
+        Protocol::Resp => redis::handle_stream(engine, io, peer, metrics, token),
+    }
+}
+
+fn handle_stream<E: KvsEngine>(
+    engine: E,
+    io: impl Stream,
+    peer: &PeerInfo,
+    metrics: &Metrics,
+    active: &AtomicUsize,
+    token: Option<&str>,
+    malformed: MalformedRequests,
+) -> Result<()> {
+    let io = SharedStream::new(io);
+    let reader = BufReader::new(io.clone());
+    let mut writer = BufWriter::new(io);
+    let mut deserializer = Deserializer::from_reader(reader);
+
+    let hello = Hello::deserialize(&mut deserializer)?;
+    if hello.version != PROTOCOL_VERSION {
+        warn!(
+            "Connection {} speaks protocol version {} (rskv {}), expected {} (rskv {})",
+            peer,
+            hello.version,
+            hello.crate_version,
+            PROTOCOL_VERSION,
+            env!("CARGO_PKG_VERSION")
+        );
+        serde_json::to_writer(
+            &mut writer,
+            &HelloResponse::VersionMismatch(Hello::current()),
+        )?;
+        writer.flush()?;
+        return Ok(());
+    }
+    // every encoding is supported, so the client's is agreed on
+    let encoding = hello.value_encoding;
+    let hello = Hello {
+        value_encoding: encoding,
+        ..Hello::current()
+    };
+    serde_json::to_writer(&mut writer, &HelloResponse::Ok(hello))?;
+    writer.flush()?;
+    // The outer error fails the connection, the inner one only the request.
+    let requests: Box<dyn Iterator<Item = serde_json::Result<serde_json::Result<Request>>>> =
+        match malformed {
+            MalformedRequests::Close => {
+                Box::new(deserializer.into_iter::<Request>().map(|req| req.map(Ok)))
+            }
+            MalformedRequests::Reply => Box::new(
+                deserializer
+                    .into_iter::<Value>()
+                    .map(|value| value.map(Request::deserialize)),
+            ),
+        };
+
+    let mut authenticated = token.is_none();
+    for req in requests {
+        let req = match req {
+            Ok(Ok(req)) => req,
+            Ok(Err(e)) => {
+                send_malformed(&mut writer, peer, e)?;
+                continue;
+            }
+            Err(e) if malformed == MalformedRequests::Reply && e.is_syntax() => {
+                // where the next request starts is unknown
+                send_malformed(&mut writer, peer, e)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (op, key_len) = describe(&req);
+        let span = Span::request(op, key_len);
+
+        macro_rules! send_resp {
+            ($resp:expr) => {{
+                let resp = $resp;
+                serde_json::to_writer(&mut writer, &resp)?;
+                writer.flush()?;
+                span.record_status(resp.error().map(ErrorCode::name));
+                debug!("Response sent to {}: {:?}", peer, resp);
+            }};
+        }
+
+        debug!("Receive request from {}: {:?}", peer, req);
+        if !authenticated && !matches!(req, Request::Auth { .. } | Request::Ping) {
+            warn!("Unauthenticated request from {}", peer);
+            send_error(&mut writer, &req, &KvsError::Unauthorized)?;
+            span.record_status(Some(ErrorCode::Unauthorized.name()));
+            continue;
+        }
+        if let Err(e) = req.check_values(encoding) {
+            warn!("Invalid request from {}: {}", peer, e);
+            send_error(&mut writer, &req, &e)?;
+            span.record_status(Some(e.code().name()));
+            continue;
+        }
         match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
-                Ok(val) => GetResponse::Ok(val),
-                Err(e) => GetResponse::Err(e.to_string()),
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(()) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(e.to_string()),
-            }),
-            Request::Rm { key } => send_resp!(match engine.rm(key) {
+            Request::Get { key } => {
+                send_resp!(match metrics.time(Command::Get, || engine.get(key)) {
+                    Ok(val) => GetResponse::Ok(val),
+                    Err(e) => GetResponse::Err((&e).into()),
+                })
+            }
+            Request::Set { key, value } => {
+                send_resp!(
+                    match metrics.time(Command::Set, || engine.set(key, value)) {
+                        Ok(()) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err((&e).into()),
+                    }
+                )
+            }
+            Request::Rm { key } => send_resp!(match metrics.time(Command::Rm, || engine.rm(key)) {
                 Ok(()) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(e.to_string()),
+                Err(e) => RemoveResponse::Err((&e).into()),
             }),
+            Request::Info => send_resp!(InfoResponse::Ok(ServerInfo {
+                active_connections: active.load(Ordering::SeqCst),
+                ..metrics.errors.info()
+            })),
+            Request::Ping => send_resp!(PingResponse::Pong),
+            Request::Auth { token: given } => {
+                authenticated = token.is_none_or(|token| is_token(token, &given.0));
+                if !authenticated {
+                    warn!("Connection {} sent a wrong token", peer);
+                    send_resp!(AuthResponse::Err((&KvsError::Unauthorized).into()));
+                    return Ok(());
+                }
+                send_resp!(AuthResponse::Ok(()))
+            }
+            Request::Transaction { commands } => {
+                let res = metrics.time(Command::Transaction, || {
+                    let ops = batch_ops(commands)?;
+                    let results = ops
+                        .iter()
+                        .map(|op| match op {
+                            BatchOp::Set { .. } => CommandResult::Set,
+                            BatchOp::Rm { .. } => CommandResult::Rm,
+                        })
+                        .collect();
+                    engine.write_batch(ops).map(|()| results)
+                });
+                send_resp!(match res {
+                    Ok(results) => TransactionResponse::Ok(results),
+                    Err(e) => TransactionResponse::Err((&e).into()),
+                })
+            }
+            Request::Scan { prefix } => {
+                send_resp!(send_scan(&engine, prefix, &mut writer, metrics)?)
+            }
         }
     }
     Ok(())
 }
+
+/// Send the pairs whose keys start with `prefix` to `writer` as [ScanResponse::Item]s,
+/// flushing every [SCAN_FLUSH_ITEMS] pairs, and return the message ending the stream.
+///
+/// The pairs are written as the engine reads them, so that neither end holds the
+/// whole result. Failing to write fails the connection instead of the scan.
+fn send_scan<E: KvsEngine>(
+    engine: &E,
+    prefix: String,
+    writer: &mut impl Write,
+    metrics: &Metrics,
+) -> Result<ScanResponse> {
+    let mut sent = 0;
+    let mut send = |key, value| -> Result<()> {
+        serde_json::to_writer(&mut *writer, &ScanResponse::Item(key, value))?;
+        sent += 1;
+        if sent % SCAN_FLUSH_ITEMS == 0 {
+            writer.flush()?;
+        }
+        Ok(())
+    };
+    let mut broken = false;
+    let res = metrics.time(Command::Scan, || {
+        engine.for_each_prefix(prefix, |key, value| {
+            let res = send(key, value);
+            broken = res.is_err();
+            res
+        })
+    });
+    match res {
+        Ok(()) => Ok(ScanResponse::End),
+        Err(e) if broken => Err(e),
+        Err(e) => Ok(ScanResponse::Err((&e).into())),
+    }
+}
+
+/// The operation of `req` and the length of its key, if any, for its [Span].
+fn describe(req: &Request) -> (&'static str, Option<usize>) {
+    match req {
+        Request::Get { key } => ("get", Some(key.len())),
+        Request::Set { key, .. } => ("set", Some(key.len())),
+        Request::Rm { key } => ("rm", Some(key.len())),
+        Request::Info => ("info", None),
+        Request::Ping => ("ping", None),
+        Request::Auth { .. } => ("auth", None),
+        Request::Transaction { .. } => ("transaction", None),
+        Request::Scan { prefix } => ("scan", Some(prefix.len())),
+    }
+}
+
+/// A response which may report a failure.
+trait Status {
+    /// The code of the failure reported, if any.
+    fn error(&self) -> Option<ErrorCode>;
+}
+
+macro_rules! impl_status {
+    ($($resp:ident),*) => {
+        $(
+            impl Status for $resp {
+                fn error(&self) -> Option<ErrorCode> {
+                    match self {
+                        $resp::Ok(_) => None,
+                        $resp::Err(err) => Some(err.code),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_status!(
+    GetResponse,
+    SetResponse,
+    RemoveResponse,
+    InfoResponse,
+    AuthResponse,
+    TransactionResponse
+);
+
+impl Status for ScanResponse {
+    fn error(&self) -> Option<ErrorCode> {
+        match self {
+            ScanResponse::Err(err) => Some(err.code),
+            _ => None,
+        }
+    }
+}
+
+impl Status for PingResponse {
+    fn error(&self) -> Option<ErrorCode> {
+        None
+    }
+}
+
+/// Answer a request which could not be parsed with the error `e`.
+fn send_malformed(writer: &mut impl Write, peer: &PeerInfo, e: serde_json::Error) -> Result<()> {
+    let span = Span::request("malformed", None);
+    warn!("Malformed request from {}: {}", peer, e);
+    let err = KvsError::MalformedRequest(e);
+    serde_json::to_writer(&mut *writer, &MalformedResponse::Err((&err).into()))?;
+    writer.flush()?;
+    span.record_status(Some(ErrorCode::Malformed.name()));
+    Ok(())
+}
+
+/// Answer `req` with `err`, in the response type of the request.
+fn send_error(writer: &mut impl Write, req: &Request, err: &KvsError) -> Result<()> {
+    let err = err.into();
+    match req {
+        Request::Get { .. } => serde_json::to_writer(&mut *writer, &GetResponse::Err(err)),
+        Request::Set { .. } => serde_json::to_writer(&mut *writer, &SetResponse::Err(err)),
+        Request::Rm { .. } => serde_json::to_writer(&mut *writer, &RemoveResponse::Err(err)),
+        Request::Info => serde_json::to_writer(&mut *writer, &InfoResponse::Err(err)),
+        Request::Auth { .. } => serde_json::to_writer(&mut *writer, &AuthResponse::Err(err)),
+        Request::Transaction { .. } => {
+            serde_json::to_writer(&mut *writer, &TransactionResponse::Err(err))
+        }
+        Request::Scan { .. } => serde_json::to_writer(&mut *writer, &ScanResponse::Err(err)),
+        Request::Ping => unreachable!("a ping cannot fail"),
+    }?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Whether `given` is the server's `token`.
+///
+/// The comparison takes the same time wherever the first mismatching byte is, so
+/// that response times do not tell how much of a guessed token is right.
+pub(crate) fn is_token(token: &str, given: &str) -> bool {
+    let (token, given) = (token.as_bytes(), given.as_bytes());
+    token.len() == given.len()
+        && token
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// A socket the server accepts connections on.
+trait Listener: Sync {
+    type Conn: Connection;
+
+    fn accept(&self) -> io::Result<Self::Conn>;
+
+    /// Make [Listener::accept] fail once it waited for `timeout`, see [is_accept_timeout].
+    ///
+    /// Accepted connections inherit the timeout as their read timeout, which
+    /// [Connection::configure] replaces.
+    fn set_accept_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+/// Whether `err` comes from [Listener::accept] giving up after its timeout.
+fn is_accept_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// A connection accepted by a [Listener].
+trait Connection: Stream + 'static {
+    /// Describes the peer in logs, e.g. with its address.
+    fn peer_label(&self) -> io::Result<Option<String>>;
+
+    /// Apply the socket options of `options` to an accepted connection.
+    fn configure(&self, options: ServerOptions) -> Result<()>;
+}
+
+impl Listener for TcpListener {
+    type Conn = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn set_accept_timeout(&self, timeout: Duration) -> io::Result<()> {
+        SockRef::from(self).set_read_timeout(Some(timeout))
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer_label(&self) -> io::Result<Option<String>> {
+        Ok(Some(self.peer_addr()?.to_string()))
+    }
+
+    fn configure(&self, options: ServerOptions) -> Result<()> {
+        self.set_read_timeout(options.read_timeout)?;
+        self.set_write_timeout(options.write_timeout)?;
+        self.set_nodelay(options.nodelay)?;
+        if let Some(idle) = options.keepalive {
+            SockRef::from(self).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocket {
+    type Conn = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        self.listener.accept().map(|(stream, _)| stream)
+    }
+
+    fn set_accept_timeout(&self, timeout: Duration) -> io::Result<()> {
+        SockRef::from(&self.listener).set_read_timeout(Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn peer_label(&self) -> io::Result<Option<String>> {
+        // clients rarely bind their end to a path
+        let addr = self.peer_addr()?;
+        Ok(addr.as_pathname().map(|path| path.display().to_string()))
+    }
+
+    /// Only the timeouts apply to a Unix domain socket.
+    fn configure(&self, options: ServerOptions) -> Result<()> {
+        self.set_read_timeout(options.read_timeout)?;
+        self.set_write_timeout(options.write_timeout)?;
+        Ok(())
+    }
+}
+
+/// A listening Unix domain socket, whose file is removed when dropped.
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    fn bind(path: &Path) -> Result<Self> {
+        let in_use = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{:?} is already in use: {}", path, reason),
+            )
+        };
+        match fs::symlink_metadata(path) {
+            Ok(meta) if !meta.file_type().is_socket() => return Err(in_use("not a socket").into()),
+            Ok(_) if UnixStream::connect(path).is_ok() => {
+                return Err(in_use("another server listens on it").into())
+            }
+            // left behind by a server which did not stop cleanly
+            Ok(_) => fs::remove_file(path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(UnixSocket {
+            listener: UnixListener::bind(path)?,
+            path: path.to_owned(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Unable to remove socket file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Whether `err` comes from a read or write timeout set on the connection.
+fn is_timeout(err: &KvsError) -> bool {
+    let kind = match err {
+        KvsError::Io(e) => e.kind(),
+        KvsError::Serde(e) => match e.io_error_kind() {
+            Some(kind) => kind,
+            None => return false,
+        },
+        _ => return false,
+    };
+    // the kind of a timed out socket operation differs between platforms
+    matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Convert the commands of a transaction into writes, rejecting anything but set and rm.
+fn batch_ops(commands: Vec<Request>) -> Result<Vec<BatchOp>> {
+    commands
+        .into_iter()
+        .map(|req| match req {
+            Request::Set { key, value } => Ok(BatchOp::Set { key, value }),
+            Request::Rm { key } => Ok(BatchOp::Rm { key }),
+            Request::Transaction { .. } => Err(KvsError::StringError(
+                "Nested transactions are not allowed".to_owned(),
+            )),
+            req => Err(KvsError::StringError(format!(
+                "Only set and rm are allowed in a transaction, got {:?}",
+                req
+            ))),
+        })
+        .collect()
+}
@@ -1,43 +1,83 @@
 use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{panic, thread};
 
 use log::error;
 
 use crate::KvsError;
 
-use super::ThreadPool;
+use super::{ThreadPool, ThreadPoolBuilder};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-///
+enum Message {
+    Run(Job),
+    /// Makes the worker which receives it exit, see [DropJoinThreadPool::resize].
+    Retire,
+}
+
+/// A shared-queue thread pool which joins all its workers when dropped.
 pub struct DropJoinThreadPool {
+    workers: Mutex<Workers>,
+    sender: Option<mpsc::Sender<Message>>,
+    shared: Arc<Shared>,
+    /// Settings of the threads spawned by [DropJoinThreadPool::resize].
+    builder: ThreadPoolBuilder,
+}
+
+/// State shared by the pool and its workers.
+struct Shared {
+    receiver: Mutex<Receiver<Message>>,
+    /// Set to make the workers exit after their current job, skipping queued ones.
+    stop: AtomicBool,
+    /// Jobs spawned and not yet picked up by a worker.
+    queued: AtomicUsize,
+    /// Jobs being run by a worker.
+    active: AtomicUsize,
+    /// Jobs which panicked.
+    panics: AtomicUsize,
+}
+
+struct Workers {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    /// Id, and index in the thread name, of the next worker.
+    next_id: usize,
+    /// Ids of the workers which took a [Message::Retire].
+    retired: Receiver<usize>,
+    retired_sender: mpsc::Sender<usize>,
 }
 
 impl ThreadPool for DropJoinThreadPool {
     fn new(num_threads: usize) -> crate::Result<Self> {
-        if num_threads <= 0 {
-            return Err(KvsError::StringError(
-                "num_threads must greater than zero".to_owned(),
-            ));
-        }
+        Self::from_builder(&ThreadPoolBuilder::new().num_threads(num_threads))
+    }
 
+    fn from_builder(builder: &ThreadPoolBuilder) -> crate::Result<Self> {
+        let num_threads = builder.checked_num_threads()?;
         let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(num_threads);
-
-        for _ in 0..num_threads {
-            workers.push(Worker::new(Arc::clone(&receiver)));
-        }
-
-        Ok(DropJoinThreadPool {
-            workers,
+        let (retired_sender, retired) = mpsc::channel();
+        let pool = DropJoinThreadPool {
+            workers: Mutex::new(Workers {
+                workers: Vec::with_capacity(num_threads),
+                next_id: 0,
+                retired,
+                retired_sender,
+            }),
             sender: Some(sender),
-        })
+            shared: Arc::new(Shared {
+                receiver: Mutex::new(receiver),
+                stop: AtomicBool::new(false),
+                queued: AtomicUsize::new(0),
+                active: AtomicUsize::new(0),
+                panics: AtomicUsize::new(0),
+            }),
+            builder: builder.clone(),
+        };
+        pool.resize(num_threads)?;
+        Ok(pool)
     }
 
     fn spawn<F>(&self, f: F)
@@ -45,50 +85,193 @@ impl ThreadPool for DropJoinThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
         self.sender
             .as_ref()
             .unwrap()
-            .send(job)
+            .send(Message::Run(job))
             .expect("The thread pool has no thread.");
     }
 }
 
+impl DropJoinThreadPool {
+    /// Number of workers in the pool.
+    pub fn num_threads(&self) -> usize {
+        self.workers.lock().unwrap().workers.len()
+    }
+
+    /// Number of jobs spawned which no worker has picked up yet.
+    pub fn queued_jobs(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs being run right now.
+    pub fn active_jobs(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs which panicked since the pool was created.
+    ///
+    /// The worker catches the panic and goes on with the next job, so this is the
+    /// only trace of it besides the error log.
+    pub fn panics_observed(&self) -> usize {
+        self.shared.panics.load(Ordering::SeqCst)
+    }
+
+    /// Grow or shrink the pool to `num_threads` workers.
+    ///
+    /// Growing spawns the new workers right away. Shrinking queues a stop message
+    /// for each excess worker and waits for them to exit, so it returns after the
+    /// jobs spawned before it have been picked up, and the retired workers have
+    /// finished theirs.
+    pub fn resize(&self, num_threads: usize) -> crate::Result<()> {
+        if num_threads == 0 {
+            return Err(KvsError::StringError(
+                "num_threads must greater than zero".to_owned(),
+            ));
+        }
+        let mut workers = self.workers.lock().unwrap();
+
+        while workers.workers.len() < num_threads {
+            let id = workers.next_id;
+            let worker = Worker::new(
+                id,
+                self.builder.thread(id),
+                Arc::clone(&self.shared),
+                workers.retired_sender.clone(),
+            )?;
+            workers.workers.push(worker);
+            workers.next_id += 1;
+        }
+
+        let excess = workers.workers.len() - num_threads;
+        let sender = self.sender.as_ref().unwrap();
+        for _ in 0..excess {
+            sender
+                .send(Message::Retire)
+                .expect("The thread pool has no thread.");
+        }
+        for _ in 0..excess {
+            let id = workers
+                .retired
+                .recv()
+                .expect("The thread pool has no thread.");
+            let index = workers
+                .workers
+                .iter()
+                .position(|worker| worker.id == id)
+                .expect("retired worker belongs to the pool");
+            if let Some(thread) = workers.workers.swap_remove(index).thread {
+                thread.join().unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop the workers after their current job and wait at most `timeout` for them to exit.
+    ///
+    /// Jobs still queued are dropped without running. Workers busy past the timeout,
+    /// e.g. with a hung job, are detached and an error tells how many there are.
+    /// Dropping the pool instead runs the queued jobs and waits as long as it takes.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> crate::Result<()> {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + timeout;
+        let workers = &mut self.workers.get_mut().unwrap().workers;
+        let mut threads: Vec<_> = workers
+            .iter_mut()
+            .filter_map(|worker| worker.thread.take())
+            .collect();
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) =
+                threads.into_iter().partition(|thread| thread.is_finished());
+            for thread in finished {
+                // jobs run under catch_unwind, so a worker never exits by panicking
+                thread.join().unwrap();
+            }
+            threads = running;
+            if threads.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        if threads.is_empty() {
+            Ok(())
+        } else {
+            Err(KvsError::StringError(format!(
+                "{} of {} workers did not exit within {:?}",
+                threads.len(),
+                workers.len(),
+                timeout
+            )))
+        }
+    }
+}
+
 /// When drop, join all threads in the pool.
 impl Drop for DropJoinThreadPool {
     fn drop(&mut self) {
         drop(self.sender.take());
 
-        self.workers.iter_mut().for_each(|worker| {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        })
+        self.workers
+            .get_mut()
+            .unwrap()
+            .workers
+            .iter_mut()
+            .for_each(|worker| {
+                if let Some(thread) = worker.thread.take() {
+                    thread.join().unwrap();
+                }
+            })
     }
 }
 
 struct Worker {
+    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    fn new(
+        id: usize,
+        thread: thread::Builder,
+        shared: Arc<Shared>,
+        retired: mpsc::Sender<usize>,
+    ) -> crate::Result<Worker> {
+        let thread = thread.spawn(move || loop {
+            if shared.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let message = shared.receiver.lock().unwrap().recv();
 
             match message {
-                Ok(job) => {
+                // a job queued before shutdown_timeout, which skips it
+                Ok(_) if shared.stop.load(Ordering::SeqCst) => break,
+                Ok(Message::Run(job)) => {
+                    shared.active.fetch_add(1, Ordering::SeqCst);
+                    shared.queued.fetch_sub(1, Ordering::SeqCst);
                     if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        shared.panics.fetch_add(1, Ordering::SeqCst);
                         error!("executes a job with error {:?}", e)
                     }
+                    shared.active.fetch_sub(1, Ordering::SeqCst);
+                }
+                Ok(Message::Retire) => {
+                    // the pool may be shutting down and no longer waiting
+                    let _ = retired.send(id);
+                    break;
                 }
                 Err(_) => {
                     break;
                 }
             }
-        });
+        })?;
 
-        Worker {
+        Ok(Worker {
+            id,
             thread: Some(thread),
-        }
+        })
     }
 }
@@ -1,15 +1,19 @@
 //! This module provides various thread pools. All thread pools should implement
 //! the `ThreadPool` trait.
 
-use crate::Result;
+use std::thread;
+
+use crate::{KvsError, Result};
 
-mod naive;
 mod drop_join;
+mod naive;
 mod rayon;
+mod work_stealing;
 
-pub use self::naive::NaiveThreadPool;
 pub use self::drop_join::DropJoinThreadPool;
+pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
 
 /// The trait that all thread pools should implement.
 pub trait ThreadPool {
@@ -21,6 +25,15 @@ pub trait ThreadPool {
     fn new(num_threads: usize) -> Result<Self>
     where
         Self: Sized;
+    /// Creates a new thread pool configured by a [ThreadPoolBuilder].
+    ///
+    /// The default implementation only honors the number of threads.
+    fn from_builder(builder: &ThreadPoolBuilder) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::new(builder.num_threads)
+    }
     /// Spawns a function into the thread pool.
     ///
     /// Spawning always succeeds, but if the function panics the threadpool continues
@@ -30,3 +43,89 @@ pub trait ThreadPool {
     where
         F: FnOnce() + Send + 'static;
 }
+
+/// Configuration of a thread pool's workers.
+///
+/// ```no_run
+/// # use rskv::thread_pool::{DropJoinThreadPool, ThreadPoolBuilder};
+/// let pool = ThreadPoolBuilder::new()
+///     .num_threads(4)
+///     .thread_name("kvs-worker".to_owned())
+///     .build_pool::<DropJoinThreadPool>()?;
+/// # Ok::<(), rskv::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+    num_threads: usize,
+    thread_name: Option<String>,
+    thread_stack_size: Option<usize>,
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        ThreadPoolBuilder {
+            num_threads: num_cpus::get(),
+            thread_name: None,
+            thread_stack_size: None,
+        }
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Creates a builder for a pool with one thread per CPU and unnamed threads.
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// Sets the number of threads in the pool.
+    pub fn num_threads(mut self, num_threads: usize) -> ThreadPoolBuilder {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Names the threads `{name}-{index}`, e.g. `kvs-worker-0`.
+    ///
+    /// Linux only shows the first 15 bytes of a thread name, so keep it short.
+    pub fn thread_name(mut self, name: String) -> ThreadPoolBuilder {
+        self.thread_name = Some(name);
+        self
+    }
+
+    /// Sets the stack size of the threads in bytes, instead of the platform default.
+    pub fn thread_stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.thread_stack_size = Some(size);
+        self
+    }
+
+    /// Builds a [NaiveThreadPool].
+    pub fn build(self) -> Result<NaiveThreadPool> {
+        self.build_pool()
+    }
+
+    /// Builds a thread pool of any type, see [ThreadPool::from_builder].
+    pub fn build_pool<P: ThreadPool>(self) -> Result<P> {
+        P::from_builder(&self)
+    }
+
+    /// Checks the number of threads, which must be greater than zero.
+    fn checked_num_threads(&self) -> Result<usize> {
+        if self.num_threads == 0 {
+            return Err(KvsError::StringError(
+                "num_threads must greater than zero".to_owned(),
+            ));
+        }
+        Ok(self.num_threads)
+    }
+
+    /// A [thread::Builder] for the worker with the given `index`.
+    fn thread(&self, index: usize) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = &self.thread_name {
+            builder = builder.name(format!("{}-{}", name, index));
+        }
+        if let Some(size) = self.thread_stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder
+    }
+}
@@ -6,13 +6,11 @@ use std::{
     thread,
 };
 
-use crate::KvsError;
-
-use super::ThreadPool;
+use super::{ThreadPool, ThreadPoolBuilder};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-///
+/// A thread pool that spawns detached workers sharing one job queue.
 pub struct NaiveThreadPool {
     sender: Sender<Job>,
 }
@@ -22,17 +20,17 @@ impl ThreadPool for NaiveThreadPool {
     where
         Self: Sized,
     {
-        if num_threads <= 0 {
-            return Err(KvsError::StringError(
-                "num_threads must greater than zero".to_owned(),
-            ));
-        }
+        Self::from_builder(&ThreadPoolBuilder::new().num_threads(num_threads))
+    }
+
+    fn from_builder(builder: &ThreadPoolBuilder) -> crate::Result<Self> {
+        let num_threads = builder.checked_num_threads()?;
         let (tx, rx) = channel::<Job>();
         let rx = Arc::new(Mutex::new(rx));
 
-        (0..num_threads).for_each(|_| {
-            spawn_in_pool(rx.clone());
-        });
+        for index in 0..num_threads {
+            spawn_in_pool(builder.thread(index), rx.clone())?;
+        }
 
         Ok(NaiveThreadPool { sender: tx })
     }
@@ -45,49 +43,13 @@ impl ThreadPool for NaiveThreadPool {
     }
 }
 
-fn spawn_in_pool(job: Arc<Mutex<Receiver<Job>>>) {
-    thread::spawn(move || loop {
+fn spawn_in_pool(thread: thread::Builder, job: Arc<Mutex<Receiver<Job>>>) -> crate::Result<()> {
+    thread.spawn(move || loop {
         let msg = job.lock().unwrap().recv();
         match msg {
             Ok(job) => job(),
             Err(_) => break,
         }
-    });
+    })?;
+    Ok(())
 }
-
-// #[derive(Default)]
-// pub struct Builder {
-//     num_threads: Option<usize>,
-//     thread_name: Option<String>,
-//     thread_stack_size: Option<usize>,
-// }
-
-// impl Builder {
-//     pub fn new() -> Builder {
-//         Builder {
-//             num_threads: None,
-//             thread_name: None,
-//             thread_stack_size: None,
-//         }
-//     }
-
-//     pub fn num_threads(mut self, num_threads: usize) -> Builder {
-//         assert!(num_threads > 0);
-//         self.num_threads = Some(num_threads);
-//         self
-//     }
-
-//     pub fn thread_name(mut self, name: String) -> Builder {
-//         self.thread_name = Some(name);
-//         self
-//     }
-
-//     pub fn thread_stack_size(mut self, size: usize) -> Builder {
-//         self.thread_stack_size = Some(size);
-//         self
-//     }
-
-//     pub fn build(self) -> crate::Result<NaiveThreadPool> {
-//         NaiveThreadPool::new(self.num_threads.unwrap())
-//     }
-// }
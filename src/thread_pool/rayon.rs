@@ -1,17 +1,29 @@
 use crate::KvsError;
 
-use super::ThreadPool;
+use super::{ThreadPool, ThreadPoolBuilder};
 
 /// Wrapper of rayon::ThreadPool
 pub struct RayonThreadPool(rayon::ThreadPool);
 
 impl ThreadPool for RayonThreadPool {
-     fn new(num_threads: usize) -> crate::Result<Self>
+    fn new(num_threads: usize) -> crate::Result<Self>
     where
         Self: Sized,
     {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(num_threads)
+        Self::from_builder(&ThreadPoolBuilder::new().num_threads(num_threads))
+    }
+
+    fn from_builder(builder: &ThreadPoolBuilder) -> crate::Result<Self> {
+        // rayon would pick a thread per CPU for zero threads
+        let num_threads = builder.checked_num_threads()?;
+        let mut rayon_builder = rayon::ThreadPoolBuilder::new().num_threads(num_threads);
+        if let Some(name) = builder.thread_name.clone() {
+            rayon_builder = rayon_builder.thread_name(move |index| format!("{}-{}", name, index));
+        }
+        if let Some(size) = builder.thread_stack_size {
+            rayon_builder = rayon_builder.stack_size(size);
+        }
+        let pool = rayon_builder
             .build()
             .map_err(|e| KvsError::StringError(e.to_string()))?;
 
@@ -27,7 +39,7 @@ impl ThreadPool for RayonThreadPool {
 }
 
 impl RayonThreadPool {
-    ///
+    /// Creates a scope in the pool, see `rayon::ThreadPool::scope`.
     pub fn scope<'a, OP, R>(&self, op: OP) -> R
     where
         OP: FnOnce(&rayon::Scope<'a>) -> R + Send,
@@ -0,0 +1,122 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{iter, thread};
+
+use crossbeam_deque::{Injector, Stealer, Worker};
+use log::error;
+
+use super::{ThreadPool, ThreadPoolBuilder};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool whose workers each have their own job queue, and steal jobs from
+/// each other when theirs runs empty.
+///
+/// Spawned jobs go to a shared queue, which idle workers take batches of jobs from.
+/// Unlike the pools sharing one `Mutex<Receiver>`, workers with queued jobs do not
+/// contend on a lock. Dropping the pool runs the queued jobs and joins the workers.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+/// State shared by the pool and its workers.
+struct Shared {
+    injector: Injector<Job>,
+    /// Handles to steal from the queue of each worker.
+    stealers: Vec<Stealer<Job>>,
+    /// Set when the pool is dropped, making the workers exit once no job is left.
+    shutdown: Mutex<bool>,
+    /// Wakes up idle workers when a job is spawned or the pool is dropped.
+    wake: Condvar,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(num_threads: usize) -> crate::Result<Self> {
+        Self::from_builder(&ThreadPoolBuilder::new().num_threads(num_threads))
+    }
+
+    fn from_builder(builder: &ThreadPoolBuilder) -> crate::Result<Self> {
+        let num_threads = builder.checked_num_threads()?;
+        let queues: Vec<_> = (0..num_threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: queues.iter().map(Worker::stealer).collect(),
+            shutdown: Mutex::new(false),
+            wake: Condvar::new(),
+        });
+
+        // dropping the pool on error stops the workers spawned so far
+        let mut pool = WorkStealingThreadPool {
+            shared,
+            threads: Vec::with_capacity(num_threads),
+        };
+        for (index, queue) in queues.into_iter().enumerate() {
+            let shared = Arc::clone(&pool.shared);
+            let thread = builder
+                .thread(index)
+                .spawn(move || run_worker(&queue, &shared))?;
+            pool.threads.push(thread);
+        }
+        Ok(pool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.injector.push(Box::new(job));
+        // an idle worker checks for jobs under the lock before waiting, so it cannot
+        // miss this notification
+        let _guard = self.shared.shutdown.lock().unwrap();
+        self.shared.wake.notify_one();
+    }
+}
+
+/// When drop, run the queued jobs and join all threads in the pool.
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        *self.shared.shutdown.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        for thread in self.threads.drain(..) {
+            // jobs run under catch_unwind, so a worker never exits by panicking
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Run jobs until the pool is dropped and no job is left.
+fn run_worker(queue: &Worker<Job>, shared: &Shared) {
+    loop {
+        if let Some(job) = find_job(queue, shared) {
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                error!("executes a job with error {:?}", e)
+            }
+            continue;
+        }
+
+        let shutdown = shared.shutdown.lock().unwrap();
+        if !shared.injector.is_empty() || shared.stealers.iter().any(|s| !s.is_empty()) {
+            continue;
+        }
+        if *shutdown {
+            break;
+        }
+        drop(shared.wake.wait(shutdown).unwrap());
+    }
+}
+
+/// Take a job from the worker's own queue, or else from the shared queue, or else
+/// steal one from another worker.
+fn find_job(queue: &Worker<Job>, shared: &Shared) -> Option<Job> {
+    queue.pop().or_else(|| {
+        iter::repeat_with(|| {
+            shared
+                .injector
+                .steal_batch_and_pop(queue)
+                .or_else(|| shared.stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(|steal| steal.success())
+    })
+}
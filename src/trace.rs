@@ -0,0 +1,59 @@
+//! `tracing` spans around the connections and requests a server handles.
+//!
+//! Without the `tracing` feature, the spans are no-ops which compile away.
+
+use crate::{server::PeerInfo, Protocol};
+
+/// A span, entered until dropped.
+///
+/// Request spans are entered within the span of their connection, so a subscriber
+/// timing spans, e.g. `tracing_subscriber::fmt().with_span_events(FmtSpan::CLOSE)`,
+/// reports the latency of every request along with its peer.
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+}
+
+#[cfg(feature = "tracing")]
+impl Span {
+    /// The span of a connection, with the id and the label of the `peer`.
+    pub(crate) fn connection(peer: &PeerInfo, protocol: Protocol) -> Self {
+        let span = tracing::info_span!(
+            "connection",
+            peer.id = peer.id,
+            peer.label = peer.label.as_deref(),
+            ?protocol,
+        );
+        Span {
+            span: span.entered(),
+        }
+    }
+
+    /// The span of a request of operation `op`, with the length of its key, if any.
+    ///
+    /// Its status is recorded once answered, see [Span::record_status].
+    pub(crate) fn request(op: &str, key_len: Option<usize>) -> Self {
+        let span = tracing::info_span!("request", op, key_len, status = tracing::field::Empty);
+        Span {
+            span: span.entered(),
+        }
+    }
+
+    /// Record the status of the response: `ok`, or the `error` it reports.
+    pub(crate) fn record_status(&self, error: Option<&str>) {
+        self.span.record("status", error.unwrap_or("ok"));
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn connection(_peer: &PeerInfo, _protocol: Protocol) -> Self {
+        Span {}
+    }
+
+    pub(crate) fn request(_op: &str, _key_len: Option<usize>) -> Self {
+        Span {}
+    }
+
+    pub(crate) fn record_status(&self, _error: Option<&str>) {}
+}
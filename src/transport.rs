@@ -0,0 +1,84 @@
+//! Byte streams the client and the server exchange messages over.
+
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+/// A byte stream in both directions, e.g. a `TcpStream` or a TLS stream over one.
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A stream shared by a buffered reader and a buffered writer.
+///
+/// A `TcpStream` can be read and written through two handles to the same socket,
+/// but a TLS stream is a single object, so both halves take turns locking it.
+pub(crate) struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S> SharedStream<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        SharedStream(Arc::new(Mutex::new(stream)))
+    }
+}
+
+impl<S> Clone for SharedStream<S> {
+    fn clone(&self) -> Self {
+        SharedStream(Arc::clone(&self.0))
+    }
+}
+
+impl<S: Read> Read for SharedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// A TLS connection to a server, which is closed with a `close_notify` alert when
+/// dropped, so that the server can tell it from a truncated stream.
+#[cfg(feature = "tls")]
+pub(crate) struct TlsClientStream(
+    pub(crate) rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream>,
+);
+
+#[cfg(feature = "tls")]
+impl Read for TlsClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Write for TlsClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Drop for TlsClientStream {
+    fn drop(&mut self) {
+        let rustls::StreamOwned { conn, sock } = &mut self.0;
+        conn.send_close_notify();
+        // the server may be gone already
+        while conn.wants_write() {
+            if conn.write_tls(sock).is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use rskv::{Bitcask, BitcaskOptions, Encoding, KvsEngine, Result};
+use tempfile::TempDir;
+
+/// Counts the allocations of each thread, so that tests running in parallel do not
+/// count each other's.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// The allocations made by `f` on this thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+// Reading into a reused buffer should not allocate, unlike `get`
+#[test]
+fn get_into_does_not_allocate() -> Result<()> {
+    for (encoding, checksums) in [
+        (Encoding::Json, false),
+        (Encoding::Json, true),
+        (Encoding::Bincode, false),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            encoding,
+            checksums,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("{:0100}", i))?;
+        }
+        store.set("escaped".to_owned(), "a \"quoted\" value".to_owned())?;
+
+        let mut buf = String::new();
+        assert!(store.get_into("key0", &mut buf)?);
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        let values: Vec<String> = (0..100).map(|i| format!("{:0100}", i)).collect();
+        let allocated = allocations(|| {
+            for (key, value) in keys.iter().zip(&values) {
+                assert!(store.get_into(key, &mut buf).unwrap());
+                assert_eq!(&buf, value);
+            }
+        });
+        assert_eq!(allocated, 0, "{:?}", encoding);
+
+        let allocated = allocations(|| {
+            for key in &keys {
+                store.get(key.clone()).unwrap();
+            }
+        });
+        assert!(allocated >= keys.len(), "{:?}", encoding);
+
+        // values which need unescaping, and missing keys
+        assert!(store.get_into("escaped", &mut buf)?);
+        assert_eq!(buf, "a \"quoted\" value");
+        assert!(!store.get_into("missing", &mut buf)?);
+        assert_eq!(buf, "");
+    }
+    Ok(())
+}
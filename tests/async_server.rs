@@ -0,0 +1,64 @@
+#![cfg(feature = "tokio")]
+
+use rskv::{
+    AsyncKvsEngine, AsyncKvsServer, BatchOp, Bitcask, KvsClient, KvsError, Result, SpawnBlocking,
+};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn spawn_blocking_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SpawnBlocking::new(Bitcask::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    engine.rm("key1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert!(matches!(
+        engine.rm("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_serves_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SpawnBlocking::new(Bitcask::open(temp_dir.path())?);
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(AsyncKvsServer::new(engine).serve(listener));
+
+    // the client blocks, so it must not run on the runtime's worker threads
+    let clients = (0..4).map(|i| {
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut client = KvsClient::connect(addr)?;
+            let key = format!("key{}", i);
+            client.set(key.clone(), format!("value{}", i))?;
+            assert_eq!(client.get(key.clone())?, Some(format!("value{}", i)));
+            client.remove(key.clone())?;
+            assert!(matches!(client.remove(key), Err(KvsError::KeyNotFound)));
+            Ok(())
+        })
+    });
+    for client in clients.collect::<Vec<_>>() {
+        client.await.unwrap()?;
+    }
+
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut client = KvsClient::connect(addr)?;
+        let ops = vec![BatchOp::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+        }];
+        assert!(client.transaction(ops).is_err());
+        assert!(client.info()?.active_connections >= 1);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
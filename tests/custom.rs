@@ -26,9 +26,12 @@ fn test_sorted_gen_list() -> Result<()> {
         Ok(fids)
     }
 
-    let path = get_kvstore_data_dir();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for fid in [10, 2, 1] {
+        fs::File::create(temp_dir.path().join(format!("{}.log", fid)))?;
+    }
 
-    println!("{:?}", sorted_fids(&path)?);
+    assert_eq!(sorted_fids(temp_dir.path())?, vec![1, 2, 10]);
 
     Ok(())
 }
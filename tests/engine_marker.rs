@@ -0,0 +1,230 @@
+use std::{fs, str::FromStr};
+
+use rskv::{
+    detect_engine, kvstore_data_dir, open_engine, read_engine_marker, reconcile_engine,
+    sled_data_dir, Bitcask, BitcaskOptions, EngineKind, KvsEngine, KvsError, LogNaming, Result,
+};
+use tempfile::TempDir;
+
+#[derive(Debug, PartialEq)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "Kvs" => Ok(Engine::Kvs),
+            "Sled" => Ok(Engine::Sled),
+            _ => Err(format!("unknown engine {}", s)),
+        }
+    }
+}
+
+#[test]
+fn missing_or_blank_marker_records_no_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("engine");
+    assert_eq!(read_engine_marker::<Engine>(&path)?, None);
+
+    for content in ["", " \n\t"] {
+        fs::write(&path, content)?;
+        assert_eq!(read_engine_marker::<Engine>(&path)?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn valid_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("engine");
+
+    fs::write(&path, "Sled")?;
+    assert_eq!(read_engine_marker(&path)?, Some(Engine::Sled));
+    fs::write(&path, "Kvs\n")?;
+    assert_eq!(read_engine_marker(&path)?, Some(Engine::Kvs));
+    Ok(())
+}
+
+#[test]
+fn garbage_marker_is_an_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("engine");
+
+    fs::write(&path, "rocksdb")?;
+    assert!(matches!(
+        read_engine_marker::<Engine>(&path),
+        Err(KvsError::StringError(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn detect_engine_without_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(detect_engine(temp_dir.path())?, None);
+    Ok(())
+}
+
+#[test]
+fn detect_engine_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("engine");
+
+    // markers written by the server, or by hand
+    fs::write(&path, EngineKind::Sled.to_string())?;
+    assert_eq!(detect_engine(temp_dir.path())?, Some(EngineKind::Sled));
+    assert_ne!(detect_engine(temp_dir.path())?, Some(EngineKind::Kvs));
+    fs::write(&path, "kvs\n")?;
+    assert_eq!(detect_engine(temp_dir.path())?, Some(EngineKind::Kvs));
+
+    fs::write(&path, "rocksdb")?;
+    assert!(matches!(
+        detect_engine(temp_dir.path()),
+        Err(KvsError::StringError(_))
+    ));
+    Ok(())
+}
+
+// The marker is checked against the data of the engines, and corrected if it can be
+#[test]
+fn reconcile_engine_with_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("engine");
+    let naming = LogNaming::default();
+
+    // a leftover marker, without data
+    fs::write(&path, EngineKind::Sled.to_string())?;
+    assert_eq!(reconcile_engine(temp_dir.path(), &naming)?, None);
+    assert!(!path.exists());
+
+    // a wrong or unreadable marker, with the data of one engine
+    drop(open_engine(
+        EngineKind::Kvs,
+        &kvstore_data_dir(temp_dir.path()),
+    )?);
+    for marker in ["Sled", "rocksdb"] {
+        fs::write(&path, marker)?;
+        assert_eq!(
+            reconcile_engine(temp_dir.path(), &naming)?,
+            Some(EngineKind::Kvs)
+        );
+        assert_eq!(detect_engine(temp_dir.path())?, Some(EngineKind::Kvs));
+    }
+    fs::remove_file(&path)?;
+    assert_eq!(
+        reconcile_engine(temp_dir.path(), &naming)?,
+        Some(EngineKind::Kvs)
+    );
+    assert_eq!(fs::read_to_string(&path)?, "Kvs");
+
+    // the data of both engines
+    drop(open_engine(
+        EngineKind::Sled,
+        &sled_data_dir(temp_dir.path()),
+    )?);
+    fs::write(&path, "sled")?;
+    assert_eq!(
+        reconcile_engine(temp_dir.path(), &naming)?,
+        Some(EngineKind::Sled)
+    );
+    fs::remove_file(&path)?;
+    match reconcile_engine(temp_dir.path(), &naming) {
+        Err(KvsError::StringError(message)) => {
+            assert!(message.contains(&format!("{:?}", path)), "{}", message)
+        }
+        res => panic!("expected an error, got {:?}", res),
+    }
+    Ok(())
+}
+
+// Bitcask data is recognized by the naming of its log files
+#[test]
+fn reconcile_engine_with_log_naming() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("engine");
+    let naming = LogNaming {
+        prefix: "seg-".to_owned(),
+        width: 6,
+        extension: "data".to_owned(),
+    };
+    let options = BitcaskOptions {
+        log_naming: naming.clone(),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(kvstore_data_dir(temp_dir.path()), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::write(&path, EngineKind::Kvs.to_string())?;
+
+    assert_eq!(
+        reconcile_engine(temp_dir.path(), &naming)?,
+        Some(EngineKind::Kvs)
+    );
+    assert_eq!(detect_engine(temp_dir.path())?, Some(EngineKind::Kvs));
+
+    // the default naming finds no data, and would remove the marker
+    assert_eq!(
+        reconcile_engine(temp_dir.path(), &LogNaming::default())?,
+        None
+    );
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[test]
+fn open_engine_by_kind() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = open_engine(kind, temp_dir.path())?;
+        assert_eq!(engine.kind(), kind);
+
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        let clone = engine.clone();
+        assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+        clone.rm("key1".to_owned())?;
+        assert!(matches!(
+            engine.rm("key1".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(engine.len()?, 0);
+    }
+    Ok(())
+}
+
+// Both engines should hand over the pairs of a prefix in key order, and stop on an error
+#[test]
+fn for_each_prefix_by_kind() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = open_engine(kind, temp_dir.path())?;
+        for i in (0..20).rev() {
+            engine.set(format!("user:{:02}", i), format!("value{}", i))?;
+        }
+        engine.set("other".to_owned(), "value".to_owned())?;
+        engine.rm("user:07".to_owned())?;
+
+        let mut pairs = Vec::new();
+        engine.for_each_prefix("user:".to_owned(), |key, value| {
+            pairs.push((key, value));
+            Ok(())
+        })?;
+        let expected: Vec<_> = (0..20)
+            .filter(|&i| i != 7)
+            .map(|i| (format!("user:{:02}", i), format!("value{}", i)))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        let mut seen = 0;
+        let res = engine.for_each_prefix(String::new(), |_, _| {
+            seen += 1;
+            Err(KvsError::StringError("stop".to_owned()))
+        });
+        assert!(matches!(res, Err(KvsError::StringError(message)) if message == "stop"));
+        assert_eq!(seen, 1);
+    }
+    Ok(())
+}
@@ -1,10 +1,22 @@
 use std::{
-    sync::{Arc, Barrier},
+    collections::HashMap,
+    fs,
+    ops::Bound,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Barrier, Mutex,
+    },
     thread,
+    time::{Duration, SystemTime},
 };
 
 use log::LevelFilter;
-use rskv::{Bitcask, KvsEngine, Result};
+use rskv::{
+    inspect_log, inspect_log_with_naming, verify, BatchOp, Bitcask, BitcaskOptions, Clock, Cmd,
+    CompactionHook, CorruptRecord, Encoding, Inconsistency, KeyComparator, KvsEngine, KvsError,
+    LogNaming, Result, SyncPolicy, WriteStall,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -67,6 +79,33 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Values are found behind keys and values which need escaping in the log
+#[test]
+fn escaped_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    let pairs = [
+        ("\"quoted\"", "back\\slash"),
+        ("new\nline", "\u{1f980}"),
+        ("\u{7f}\u{1}", "\"}}"),
+    ];
+
+    for (key, value) in pairs {
+        store.set(key.to_owned(), value.to_owned())?;
+    }
+    for (key, value) in pairs {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+
+    drop(store);
+    let store = Bitcask::open(temp_dir.path())?;
+    for (key, value) in pairs {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -85,133 +124,2346 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Should report live keys only, not removed or expired ones
 #[test]
-fn compaction() -> Result<()> {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::ZERO)?;
+    store.rm("key2".to_owned())?;
+
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert!(!store.contains_key("key3".to_owned())?);
+    assert!(!store.contains_key("key4".to_owned())?);
+    Ok(())
+}
 
+// Binary keys and values should round-trip, and share their keys with strings
+#[test]
+fn binary_keys_and_values() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = Bitcask::open(temp_dir.path())?;
+    let key = vec![0xff, 0x00, 0x80];
+    let value = vec![0xc3, 0x28, 0x00, 0xff];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"text".to_vec(), b"value".to_vec())?;
+    store.set("string".to_owned(), "value".to_owned())?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes(b"string")?, Some(b"value".to_vec()));
+    assert_eq!(store.scan(..)?.len(), 2, "binary keys are not scanned");
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    store.set_bytes(b"binary".to_vec(), value.clone())?;
+    assert!(matches!(
+        store.get("binary".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    store.rm("text".to_owned())?;
+    assert_eq!(store.get_bytes(b"text")?, None);
+    store.rm_bytes(b"string".to_vec())?;
+    assert_eq!(store.get("string".to_owned())?, None);
+    assert!(matches!(
+        store.rm_bytes(b"string".to_vec()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
 
-        drop(store);
-        // reopen and check content
-        let store = Bitcask::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
-    }
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    store.compact()?;
+    drop(store);
 
-    panic!("No compaction detected");
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    assert_eq!(store.get_bytes(b"text")?, None);
+    store.rm_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    Ok(())
 }
 
+// A batch is applied as a whole, or not at all when one of its removals fails
 #[test]
-fn concurrent_set() -> Result<()> {
+fn write_batch() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = Bitcask::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
-    }
-    barrier.wait();
+    store.set("key1".to_owned(), "value1".to_owned())?;
 
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    store.write_batch(vec![
+        BatchOp::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        BatchOp::Rm {
+            key: "key1".to_owned(),
+        },
+        BatchOp::Rm {
+            key: "key2".to_owned(),
+        },
+        BatchOp::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+    ])?;
+    assert!(matches!(
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "key4".to_owned(),
+                value: "value4".to_owned(),
+            },
+            BatchOp::Rm {
+                key: "key1".to_owned(),
+            },
+        ]),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    for store in [store, Bitcask::open_read_only(temp_dir.path())?] {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
     }
 
-    // Open from disk again and check persistent data
+    Ok(())
+}
+
+// Coalesced batches should only write the last operation of each key
+#[test]
+fn coalesce_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        coalesce_batches: true,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    let set = |key: &str, value: &str| BatchOp::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    let rm = |key: &str| BatchOp::Rm {
+        key: key.to_owned(),
+    };
+    store.set("old".to_owned(), "value".to_owned())?;
+
+    store.write_batch(vec![
+        set("a", "1"),
+        set("a", "2"),
+        rm("old"),
+        set("tmp", "value"),
+        rm("tmp"),
+        set("a", "3"),
+    ])?;
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+    assert_eq!(store.get("tmp".to_owned())?, None);
+
+    // removals are still checked in order
+    assert!(matches!(
+        store.write_batch(vec![rm("b"), set("b", "1")]),
+        Err(KvsError::KeyNotFound)
+    ));
+    // a batch which changes nothing writes nothing
+    store.write_batch(vec![set("tmp", "value"), rm("tmp")])?;
     drop(store);
+
+    let records = inspect_log(temp_dir.path())?;
+    assert_eq!(records.len(), 4);
+    assert!(matches!(&records[0].cmd, Cmd::Set { key, .. } if key == "old"));
+    assert!(matches!(records[1].cmd, Cmd::Batch { len: 2 }));
+    assert!(matches!(&records[2].cmd, Cmd::Rm { key } if key == "old"));
+    assert!(matches!(&records[3].cmd, Cmd::Set { key, value } if key == "a" && value == "3"));
+
     let store = Bitcask::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
-    }
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction sets its keys together, or none of them
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    let transfer = |from: &str, to: &str| {
+        vec![
+            BatchOp::Rm {
+                key: from.to_owned(),
+            },
+            BatchOp::Set {
+                key: to.to_owned(),
+                value: "token".to_owned(),
+            },
+        ]
+    };
+    store.set("a".to_owned(), "token".to_owned())?;
+    store.transaction(transfer("a", "b"))?;
+    assert!(matches!(
+        store.transaction(transfer("a", "c")),
+        Err(KvsError::KeyNotFound)
+    ));
 
+    for store in [store, Bitcask::open_read_only(temp_dir.path())?] {
+        assert_eq!(store.get("a".to_owned())?, None);
+        assert_eq!(store.get("b".to_owned())?, Some("token".to_owned()));
+        assert_eq!(store.get("c".to_owned())?, None);
+    }
     Ok(())
 }
 
+// A batch cut short by a crash should be ignored as a whole on open
 #[test]
-fn concurrent_get() -> Result<()> {
+fn incomplete_batch_is_ignored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = Bitcask::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.write_batch(vec![
+        BatchOp::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        BatchOp::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+    ])?;
+    drop(store);
+
+    // Cut the last command of the batch off the log
+    let log = written_log(temp_dir.path())?;
+    let len = log.metadata()?.len();
+    let last = r#"{"Set":{"key":"key3","value":"value3"}}"#.len() as u64;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - last)?;
+
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+/// The only non-empty log file in `dir`.
+fn written_log(dir: &Path) -> Result<std::path::PathBuf> {
+    Ok(fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .find(|path| {
+            path.extension() == Some("log".as_ref())
+                && path.metadata().is_ok_and(|metadata| metadata.len() > 0)
+        })
+        .expect("no log file written"))
+}
+
+// A torn record at the end of a checksummed log is cut off when opening
+#[test]
+fn checksummed_log_is_truncated_after_torn_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let valid_len = written_log(temp_dir.path())?.metadata()?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = written_log(temp_dir.path())?;
+    let len = log.metadata()?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(log.metadata()?.len(), valid_len);
+
+    // the log can be written again after the truncation
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A torn record at the end of a plain log is cut off when opening, in any encoding
+#[test]
+fn log_is_truncated_after_torn_record() -> Result<()> {
+    for encoding in [Encoding::Json, Encoding::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            encoding,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let valid_len = written_log(temp_dir.path())?.metadata()?.len();
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let log = written_log(temp_dir.path())?;
+        let len = log.metadata()?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log)?
+            .set_len(len - 3)?;
+
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(log.metadata()?.len(), valid_len);
     }
+    Ok(())
+}
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+// The inspector lists the records of the log files, and stops at a torn record
+#[test]
+fn inspect_log_lists_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rm("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let records = inspect_log(temp_dir.path())?;
+    assert_eq!(records.len(), 3);
+    assert!(
+        matches!(&records[0].cmd, Cmd::Set { key, value } if key == "key1" && value == "value1")
+    );
+    assert!(matches!(&records[1].cmd, Cmd::Rm { key } if key == "key1"));
+    assert!(matches!(&records[2].cmd, Cmd::Set { key, .. } if key == "key2"));
+    for pair in records.windows(2) {
+        assert!(pair[0].pos + pair[0].len <= pair[1].pos);
     }
-    for handle in handles {
-        handle.join().unwrap();
+
+    let log = written_log(temp_dir.path())?;
+    let len = log.metadata()?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+    let records = inspect_log(temp_dir.path())?;
+    assert_eq!(records.len(), 2);
+    // inspecting leaves the log file as it is
+    assert_eq!(log.metadata()?.len(), len - 3);
+    Ok(())
+}
+
+// A corrupted record in the middle of a plain log fails opening
+#[test]
+fn corrupted_record_fails_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = written_log(temp_dir.path())?;
+    let mut bytes = fs::read(&log)?;
+    // break the quote opening the first value
+    let value = bytes
+        .windows(7)
+        .position(|window| window == b"\"value1")
+        .expect("value not found");
+    bytes[value] = b'x';
+    fs::write(&log, &bytes)?;
+
+    assert!(Bitcask::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// A corrupted record fails reads, and is cut off when opening
+#[test]
+fn checksummed_log_detects_bit_flips() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let log = written_log(temp_dir.path())?;
+    let mut bytes = fs::read(&log)?;
+    let value = bytes
+        .windows(6)
+        .position(|window| window == b"value2")
+        .expect("value not found");
+    bytes[value] ^= 0x01;
+    fs::write(&log, &bytes)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.get("key2".to_owned()),
+        Err(KvsError::ChecksumMismatch { .. })
+    ));
+    drop(store);
+
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Verification should report corrupt records and hints which disagree with the logs
+#[test]
+fn verify_data_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let report = verify(temp_dir.path())?;
+    assert!(report.is_clean(), "{:?}", report);
+    assert_eq!(report.valid_records, 4);
+
+    // a bit flip in the middle of a log only spoils its record
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .find(|path| fs::read(path).is_ok_and(|bytes| bytes.windows(6).any(|w| w == b"value3")))
+        .expect("log not found");
+    let fid: u64 = log.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+    let mut bytes = fs::read(&log)?;
+    let value = bytes
+        .windows(6)
+        .position(|window| window == b"value3")
+        .expect("value not found");
+    bytes[value] ^= 0x01;
+    fs::write(&log, &bytes)?;
+    let record_len = 8 + r#"{"Set":{"key":"key3","value":"value3"}}"#.len() as u64;
+    let pos = (value as u64 + 6 + r#""}}"#.len() as u64) - record_len;
+    let report = verify(temp_dir.path())?;
+    assert_eq!(
+        report.corrupt_records,
+        vec![CorruptRecord {
+            fid,
+            pos,
+            len: record_len
+        }]
+    );
+    assert_eq!(report.corrupt_bytes, record_len);
+    assert_eq!(report.valid_records, 3);
+    bytes[value] ^= 0x01;
+    fs::write(&log, &bytes)?;
+
+    // hints pointing elsewhere than the set of their key, and hint files without logs
+    let hint = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .find(|path| path.extension() == Some("hint".as_ref()))
+        .expect("no hint file written");
+    let hint_fid: u64 = hint.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+    fs::copy(&hint, temp_dir.path().join("999.hint"))?;
+    fs::write(
+        &hint,
+        r#"{"key":[107,101,121,48],"pos":0,"len":5,"value_offset":0,"bytes":false}"#,
+    )?;
+    let report = verify(temp_dir.path())?;
+    assert!(report.corrupt_records.is_empty());
+    assert_eq!(
+        report.inconsistencies,
+        vec![
+            Inconsistency::OrphanedKey {
+                fid: hint_fid,
+                key: b"key0".to_vec(),
+                pos: 0
+            },
+            Inconsistency::OrphanedHintFile { fid: 999 },
+        ]
+    );
+    fs::write(&hint, "garbage")?;
+    let report = verify(temp_dir.path())?;
+    assert_eq!(
+        report.inconsistencies[0],
+        Inconsistency::InvalidHintFile { fid: hint_fid }
+    );
+
+    // the torn end of a plain log is reported as a whole
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log = written_log(temp_dir.path())?;
+    let len = log.metadata()?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+    let report = verify(temp_dir.path())?;
+    let record_len = r#"{"Set":{"key":"key2","value":"value2"}}"#.len() as u64;
+    assert_eq!(report.valid_records, 1);
+    assert_eq!(
+        report.corrupt_records,
+        vec![CorruptRecord {
+            fid: 1,
+            pos: len - record_len,
+            len: record_len - 3
+        }]
+    );
+    Ok(())
+}
+
+// Plain JSON logs stay readable with checksums, and are checksummed by compaction
+#[test]
+fn checksums_read_plain_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    store.set("checked".to_owned(), "value".to_owned())?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter).repeat(1000))?;
     }
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    drop(store);
 
-    // Open from disk again and check persistent data
+    let store = Bitcask::open_checked(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("checked".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("999".repeat(1000)));
     drop(store);
+
     let store = Bitcask::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    assert_eq!(store.get("checked".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Logs of both encodings are readable, and compaction converts them
+#[test]
+fn bincode_encoding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("json".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    for checksums in [false, true] {
+        let options = BitcaskOptions {
+            encoding: Encoding::Bincode,
+            checksums,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("json".to_owned())?, Some("value".to_owned()));
+        store.set("bincode".to_owned(), "value".to_owned())?;
+        store.set_with_ttl(
+            "ttl".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.set_bytes(b"bytes".to_vec(), vec![0xff, 0x00])?;
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "batch".to_owned(),
+                value: "value".to_owned(),
+            },
+            BatchOp::Rm {
+                key: "bincode".to_owned(),
+            },
+        ])?;
+        drop(store);
+
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        let check = || -> Result<()> {
+            assert_eq!(store.get("json".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get("bincode".to_owned())?, None);
+            assert_eq!(store.get("ttl".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get_bytes(b"bytes")?, Some(vec![0xff, 0x00]));
+            assert_eq!(store.get("batch".to_owned())?, Some("value".to_owned()));
+            Ok(())
+        };
+        check()?;
+        store.compact()?;
+        check()?;
+        drop(store);
     }
-    for handle in handles {
-        handle.join().unwrap();
+
+    // back to JSON, reading the compacted bincode log
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("json".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes(b"bytes")?, Some(vec![0xff, 0x00]));
+    store.compact()?;
+    assert_eq!(store.get("batch".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Scans follow the key comparator, lexicographic by default
+#[test]
+fn scan_with_key_comparator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for key in ["2", "10", "1", "33"] {
+        store.set(key.to_owned(), format!("value{}", key))?;
     }
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+
+    assert_eq!(keys(store.scan(..)?), ["1", "10", "2", "33"]);
+    assert_eq!(keys(store.scan("10".."3")?), ["10", "2"]);
+    assert_eq!(store.first_key(), Some("1".to_owned()));
+    assert_eq!(store.last_key(), Some("33".to_owned()));
+    drop(store);
 
+    let options = BitcaskOptions {
+        key_comparator: Some(KeyComparator::new(|a, b| {
+            a.parse::<u64>().unwrap().cmp(&b.parse().unwrap())
+        })),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(keys(store.scan(..)?), ["1", "2", "10", "33"]);
+    assert_eq!(keys(store.scan("2"..="10")?), ["2", "10"]);
+    assert_eq!(
+        store.scan("10"..)?,
+        [
+            ("10".to_owned(), "value10".to_owned()),
+            ("33".to_owned(), "value33".to_owned())
+        ]
+    );
+    assert_eq!(store.first_key(), Some("1".to_owned()));
+    assert_eq!(store.last_key(), Some("33".to_owned()));
+
+    Ok(())
+}
+
+// Range queries see the same keys with either index, ordered by their bytes
+#[test]
+fn get_range_with_ordered_index() -> Result<()> {
+    for ordered_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            ordered_index,
+            compaction_threshold: 2048,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        for key in ["b", "d", "a", "c", "e"] {
+            store.set(key.to_owned(), format!("value{}", key))?;
+        }
+        store.rm("c".to_owned())?;
+        let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+            pairs.into_iter().map(|(key, _)| key).collect()
+        };
+
+        assert_eq!(
+            keys(store.get_range(Bound::Unbounded, Bound::Unbounded)?),
+            ["a", "b", "d", "e"]
+        );
+        // the smallest key >= "c"
+        assert_eq!(
+            store.get_range(Bound::Included("c".to_owned()), Bound::Unbounded)?[0],
+            ("d".to_owned(), "valued".to_owned())
+        );
+        assert_eq!(
+            keys(store.get_range(
+                Bound::Excluded("a".to_owned()),
+                Bound::Included("d".to_owned())
+            )?),
+            ["b", "d"]
+        );
+        assert!(store
+            .get_range(
+                Bound::Excluded("b".to_owned()),
+                Bound::Excluded("b".to_owned())
+            )?
+            .is_empty());
+        assert!(store
+            .get_range(
+                Bound::Included("d".to_owned()),
+                Bound::Included("a".to_owned())
+            )?
+            .is_empty());
+
+        // compactions move the positions of the ordered index too
+        for iter in 0..100 {
+            store.set("b".to_owned(), format!("value{}", iter))?;
+        }
+        drop(store);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(
+            store.get_range(Bound::Unbounded, Bound::Excluded("d".to_owned()))?,
+            [
+                ("a".to_owned(), "valuea".to_owned()),
+                ("b".to_owned(), "value99".to_owned())
+            ]
+        );
+    }
+    Ok(())
+}
+
+// Prefix scans only return matching keys, and stay valid while compactions run
+#[test]
+fn scan_prefix_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("user:{:02}", key_id), "value".to_owned())?;
+    }
+    store.set("usr".to_owned(), "value".to_owned())?;
+    store.set("user".to_owned(), "value".to_owned())?;
+
+    let pairs = store.scan_prefix("user:")?;
+    assert_eq!(pairs.len(), 100);
+    assert_eq!(pairs[0], ("user:00".to_owned(), "value".to_owned()));
+    assert_eq!(pairs[99].0, "user:99");
+
+    // Overwrite other keys to trigger compactions while scanning
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 0..50 {
+                for key_id in 0..1000 {
+                    store.set(format!("other:{}", key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let pairs = store.scan_prefix("user:")?;
+        assert_eq!(pairs.len(), 100);
+        assert!(pairs.iter().all(|(_, value)| value == "value"));
+    }
+    writer.join().unwrap()?;
+
+    assert_eq!(store.scan_prefix("other:")?.len(), 1000);
+    assert!(store.scan_prefix("none")?.is_empty());
+    Ok(())
+}
+
+// Keys set with a TTL read as absent once expired, also after reopening
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(100);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key1".to_owned(), "value2".to_owned(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), ttl)?;
+    // A plain set clears the expiry
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    thread::sleep(ttl * 2);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.rm("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // The expired value also hides the older one of key1 when reloading
+    drop(store);
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.scan(..)?.len(), 2);
+
+    Ok(())
+}
+
+/// A clock which only moves when advanced.
+#[derive(Debug)]
+struct MockClock(Mutex<SystemTime>);
+
+impl MockClock {
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+// Keys should expire by the injected clock, without waiting for them
+#[test]
+fn clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(MockClock(Mutex::new(SystemTime::UNIX_EPOCH)));
+    let options = BitcaskOptions {
+        clock: Some(clock.clone()),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.keys().len(), 2);
+
+    // compaction drops the expired value
+    let live_bytes = store.compaction_preview()?.live_bytes;
+    store.compact()?;
+    assert_eq!(store.stats().num_keys, 2);
+    assert_eq!(store.compaction_preview()?.total_bytes, live_bytes);
+
+    // reopening goes by the clock too
+    drop(store);
+    clock.advance(Duration::from_secs(3600));
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Sweeping should drop expired keys from the index, manually or in the background
+#[test]
+fn sweep_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::ZERO)?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::ZERO)?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.stats().num_keys, 4);
+    assert_eq!(store.sweep_expired()?, 2);
+    assert_eq!(store.sweep_expired()?, 0);
+    assert_eq!(store.stats().num_keys, 2);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    let options = BitcaskOptions {
+        sweep_interval: Some(Duration::from_millis(10)),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().num_keys, 2);
+    store.set_with_ttl("key5".to_owned(), "value5".to_owned(), Duration::ZERO)?;
+    let swept = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(10));
+        store.stats().num_keys == 2
+    });
+    assert!(swept, "the sweeper did not remove the expired key");
+    // the sweeper runs as long as any handle is open, and is joined with the last one
+    let handle = store.clone();
+    drop(store);
+    handle.set_with_ttl("key6".to_owned(), "value6".to_owned(), Duration::ZERO)?;
+    let swept = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(10));
+        handle.stats().num_keys == 2
+    });
+    assert!(
+        swept,
+        "the sweeper stopped before the last handle was dropped"
+    );
+    drop(handle);
+
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.stats().num_keys, 2);
+    Ok(())
+}
+
+// A write between two versioned reads should change the version
+#[test]
+fn get_versioned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+
+    assert_eq!(store.get_versioned("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, v1) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert_eq!(store.get_versioned("key1".to_owned())?, Some((value, v1)));
+
+    // Same value, but a new write: the version must still move forward
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, v2) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert!(v2 > v1);
+
+    Ok(())
+}
+
+// Log and hint files should be named by the configured naming, and only those read
+#[test]
+fn log_naming() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        log_naming: LogNaming {
+            prefix: "seg-".to_owned(),
+            width: 6,
+            extension: "data".to_owned(),
+        },
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rm("key2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<_>>()?;
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "LOCK",
+            "seg-000002.data",
+            "seg-000002.hint",
+            "seg-000003.data"
+        ]
+    );
+
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    let records = inspect_log_with_naming(temp_dir.path(), &options.log_naming)?;
+    assert_eq!(records.len(), 1);
+
+    // the default naming ignores these files
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    // a log file must not be named like a hint file
+    let options = BitcaskOptions {
+        log_naming: LogNaming {
+            extension: "hint".to_owned(),
+            ..LogNaming::default()
+        },
+        ..BitcaskOptions::default()
+    };
+    assert!(Bitcask::open_with_options(temp_dir.path(), options).is_err());
+    Ok(())
+}
+
+// Renaming should move the value in one write, or fail without writing
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    store.rename("key2".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.rename("key1".to_owned(), "key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Overwriting or removing a key should return its previous value
+#[test]
+fn returning_old() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_returning_old("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.set_returning_old("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.rm_returning_old("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.rm_returning_old("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // a binary previous value is left in place
+    store.set_bytes(b"key2".to_vec(), vec![0xff])?;
+    assert!(matches!(
+        store.set_returning_old("key2".to_owned(), "value".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    assert_eq!(store.get_bytes(b"key2")?, Some(vec![0xff]));
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = Bitcask::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Total size of all files under `path`, skipping files deleted while walking
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|res| res.ok()?.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// Sustained overwrites from many threads should not grow the disk usage unboundedly
+// Compaction starts once the configured number of stale bytes is reached
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let written = |compaction_threshold| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            compaction_threshold,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..1000 {
+            store.set("key".to_owned(), format!("{:0100}", iter))?;
+        }
+        assert_eq!(store.get("key".to_owned())?, Some(format!("{:0100}", 999)));
+        // closing waits for the compaction running in the background
+        drop(store);
+        Ok(dir_size(temp_dir.path()))
+    };
+
+    assert!(written(4 * 1024)? < 8 * 1024);
+    assert!(written(1024 * 1024)? > 100 * 1000);
+    Ok(())
+}
+
+// With a ratio, compaction starts once stale bytes make up that fraction of the logs,
+// however large the threshold
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: u64::MAX,
+        compaction_ratio: Some(0.5),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{:0100}", iter))?;
+        }
+    }
+    // about 1 KiB live out of over 100 KiB written
+    let stats = store.stats();
+    assert!(stats.total_bytes < 8 * 1024, "{:?}", stats);
+    assert!(stats.uncompacted_bytes < stats.total_bytes);
+
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.uncompacted_bytes, 0);
+    let log_bytes: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert_eq!(stats.total_bytes, log_bytes);
+    drop(store);
+
+    let options = BitcaskOptions {
+        compaction_ratio: Some(1.0),
+        ..BitcaskOptions::default()
+    };
+    assert!(Bitcask::open_with_options(temp_dir.path(), options).is_err());
+    Ok(())
+}
+
+// Should list the log files with their sizes and the active one last
+#[test]
+fn log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: u64::MAX,
+        max_file_bytes: Some(1024),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    let files = store.log_files()?;
+    assert_eq!(files.len(), 1);
+    assert!(files[0].active);
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{:0100}", iter))?;
+        }
+    }
+    let files = store.log_files()?;
+    assert!(files.len() > 10, "{:?}", files);
+    assert!(files.windows(2).all(|pair| pair[0].fid < pair[1].fid));
+    let (active, sealed) = files.split_last().unwrap();
+    assert!(active.active);
+    assert!(sealed.iter().all(|file| !file.active && file.bytes <= 1024));
+    let bytes: u64 = files.iter().map(|file| file.bytes).sum();
+    assert_eq!(bytes, store.stats().total_bytes);
+
+    store.compact()?;
+    let compacted = store.log_files()?;
+    assert!(compacted.len() < files.len(), "{:?}", compacted);
+    assert!(compacted.last().unwrap().fid > active.fid);
+    drop(store);
+
+    let options = BitcaskOptions {
+        read_only: true,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.log_files()?.len(), compacted.len());
+    assert!(store.log_files()?.iter().all(|file| !file.active));
+    Ok(())
+}
+
+// Should tell where a value is stored, and where compaction moved it
+#[test]
+fn get_with_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    let (value, meta) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert_eq!(meta.expire_at, None);
+    assert_eq!(
+        meta.checksummed,
+        BitcaskOptions::default().checksums,
+        "{:?}",
+        meta
+    );
+    let active = store.log_files()?.pop().unwrap();
+    assert_eq!(meta.fid, active.fid);
+    let log = fs::read(temp_dir.path().join(format!("{}.log", meta.fid)))?;
+    let record = &log[meta.pos as usize..(meta.pos + meta.len) as usize];
+    assert!(String::from_utf8_lossy(record).contains("value2"));
+    let (_, meta2) = store.get_with_meta("key2".to_owned())?.unwrap();
+    assert!(meta2.expire_at.is_some());
+    assert!(meta2.pos > meta.pos);
+
+    store.compact()?;
+    let (value, moved) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert!(moved.fid > meta.fid, "{:?} {:?}", meta, moved);
+    assert_eq!(moved.version, meta.version);
+    Ok(())
+}
+
+// Compaction writes a hint file, from which the index is built when opening
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: 4 * 1024,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let hints: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    assert_eq!(hints.len(), 1, "one hint file for the latest compaction");
+
+    let check = || -> Result<()> {
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in 0..10 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+        }
+        Ok(())
+    };
+    check()?;
+
+    // an unparseable hint file falls back to replaying the log
+    fs::write(&hints[0], "garbage")?;
+    check()?;
+
+    // a hint file pointing past the end of its log is ignored too
+    fs::write(&hints[0], r#"{"key":"key0","pos":0,"len":1000000}"#)?;
+    check()?;
+    Ok(())
+}
+
+#[test]
+fn write_stall_bounds_disk_usage() -> Result<()> {
+    const LIVE_KEYS: usize = 1000;
+    const VALUE_LEN: usize = 4096;
+    const THRESHOLD: u64 = 1024 * 1024;
+    const HIGH_WATER_MARK: u64 = THRESHOLD + 256 * 1024;
+
+    // The most stale bytes seen while 4 threads overwrite a few keys with large
+    // values. Every compaction copies the live keys, 4 times the threshold, so the
+    // writers get past the threshold again long before it is done.
+    let max_uncompacted = |write_stall| -> Result<(u64, Duration)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            compaction_threshold: THRESHOLD,
+            write_stall,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        let value = "x".repeat(VALUE_LEN);
+        for key_id in 0..LIVE_KEYS {
+            store.set(format!("live{}", key_id), value.clone())?;
+        }
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let store = store.clone();
+                let value = value.clone();
+                thread::spawn(move || -> Result<()> {
+                    for iter in 0..500 {
+                        store.set(format!("hot{}-{}", thread_id, iter % 8), value.clone())?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        let mut max_uncompacted = 0;
+        while handles.iter().any(|handle| !handle.is_finished()) {
+            max_uncompacted = max_uncompacted.max(store.stats().uncompacted_bytes);
+            thread::sleep(Duration::from_millis(1));
+        }
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        Ok((max_uncompacted, store.stats().write_stall_time))
+    };
+
+    // each writer may get one record past the mark before it stalls
+    let bound = HIGH_WATER_MARK + 4 * 2 * VALUE_LEN as u64;
+    let (stalled, write_stall_time) = max_uncompacted(Some(WriteStall {
+        high_water_mark: HIGH_WATER_MARK,
+        max_stall: Duration::from_secs(10),
+    }))?;
+    assert!(write_stall_time > Duration::ZERO);
+    assert!(stalled <= bound, "stale bytes grew to {}", stalled);
+
+    // without the stall, writes only wait once a second compaction is due
+    let (unstalled, _) = max_uncompacted(None)?;
+    assert!(unstalled > bound, "stale bytes only grew to {}", unstalled);
+
+    // below the threshold, writes would stall before compaction even starts
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stall = WriteStall {
+        high_water_mark: THRESHOLD / 2,
+        max_stall: Duration::from_millis(10),
+    };
+    let options = BitcaskOptions {
+        compaction_threshold: THRESHOLD,
+        write_stall: Some(stall.clone()),
+        ..BitcaskOptions::default()
+    };
+    assert!(Bitcask::open_with_options(temp_dir.path(), options).is_err());
+    let options = BitcaskOptions {
+        compaction_ratio: Some(0.5),
+        write_stall: Some(stall),
+        ..BitcaskOptions::default()
+    };
+    Bitcask::open_with_options(temp_dir.path(), options)?;
+    Ok(())
+}
+
+// An explicit compaction should reclaim stale bytes below the threshold
+#[test]
+fn explicit_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let size = dir_size(temp_dir.path());
+
+    store.compact()?;
+    assert!(dir_size(temp_dir.path()) < size / 4);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// Stats should count keys, stale bytes and log files
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    let stats = store.stats();
+    assert_eq!(stats.num_keys, 0);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.num_log_files, 1);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats().num_keys, 2);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.rm("key2".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.num_keys, 1);
+    let set_len = r#"{"Set":{"key":"key1","value":"value1"}}"#.len() as u64;
+    let rm_len = r#"{"Rm":{"key":"key2"}}"#.len() as u64;
+    assert_eq!(stats.uncompacted_bytes, 2 * set_len + rm_len);
+    drop(store);
+
+    let store = Bitcask::open(temp_dir.path())?;
+    let stats = store.stats();
+    assert_eq!(stats.num_keys, 1);
+    assert_eq!(stats.uncompacted_bytes, 2 * set_len + rm_len);
+    assert_eq!(stats.num_log_files, 2);
+    Ok(())
+}
+
+// A data directory can only be opened for writing once at a time
+#[test]
+fn directory_is_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        Bitcask::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(path)) if path == temp_dir.path()
+    ));
+    let read_only = Bitcask::open_read_only(temp_dir.path())?;
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the lock is released with the last handle, leaving the file behind
+    let handle = store.clone();
+    drop(store);
+    assert!(Bitcask::open(temp_dir.path()).is_err());
+    drop(handle);
+    assert!(temp_dir.path().join("LOCK").exists());
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A compaction preview should tell what a compaction reclaims, without compacting
+#[test]
+fn compaction_preview() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.rm("key2".to_owned())?;
+
+    let set_len = r#"{"Set":{"key":"key1","value":"value1"}}"#.len() as u64;
+    let rm_len = r#"{"Rm":{"key":"key2"}}"#.len() as u64;
+    let report = store.compaction_preview()?;
+    assert_eq!(report.total_bytes, 3 * set_len + rm_len);
+    assert_eq!(report.live_bytes, set_len);
+    assert_eq!(report.reclaimable_bytes, store.stats().uncompacted_bytes);
+    assert_eq!(store.compaction_preview()?, report);
+    assert_eq!(store.stats().num_log_files, 1);
+
+    store.compact()?;
+    let report = store.compaction_preview()?;
+    assert_eq!(report.live_bytes, set_len);
+    assert_eq!(report.reclaimable_bytes, 0);
+    Ok(())
+}
+
+// The compaction hook should be called with what a compaction reclaimed, and may use the store
+#[test]
+fn on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (done, compacted) = mpsc::channel();
+    let hooked: Arc<Mutex<Option<Bitcask>>> = Arc::default();
+    let options = BitcaskOptions {
+        on_compaction: Some(CompactionHook::new({
+            let hooked = Arc::clone(&hooked);
+            move |stats| {
+                let store = hooked.lock().unwrap().clone().unwrap();
+                store
+                    .set("compacted".to_owned(), "true".to_owned())
+                    .unwrap();
+                done.send(stats).unwrap();
+            }
+        })),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    *hooked.lock().unwrap() = Some(store.clone());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.rm("key2".to_owned())?;
+
+    store.compact()?;
+    let stats = compacted.recv_timeout(Duration::from_secs(5)).unwrap();
+    let set_len = r#"{"Set":{"key":"key1","value":"value1"}}"#.len() as u64;
+    let rm_len = r#"{"Rm":{"key":"key2"}}"#.len() as u64;
+    assert_eq!(stats.files_written, 1);
+    assert_eq!(stats.files_removed, 1);
+    assert_eq!(stats.bytes_reclaimed, 2 * set_len + rm_len);
+    assert_eq!(store.get("compacted".to_owned())?, Some("true".to_owned()));
+    assert!(compacted.try_recv().is_err());
+    Ok(())
+}
+
+// The value cache should serve repeated reads and never a value overwritten since
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        value_cache_bytes: Some(16 * 1024),
+        compaction_threshold: 4096,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    let hits_and_misses = || {
+        let stats = store.stats();
+        (stats.cache_hits, stats.cache_misses)
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(hits_and_misses(), (1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(hits_and_misses(), (1, 2));
+    store.rm("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // compactions move the values without invalidating them
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key2".to_owned())?;
+    for iter in 0..200 {
+        store.set("key3".to_owned(), format!("value{}", iter))?;
+        assert_eq!(
+            store.get("key3".to_owned())?,
+            Some(format!("value{}", iter))
+        );
+    }
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // values beyond the capacity are evicted and read from the log again
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+        store.get(format!("key{}", key_id))?;
+    }
+    let (hits, misses) = hits_and_misses();
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(hits_and_misses(), (hits, misses + 1));
+    assert_eq!(store.get("key999".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(hits_and_misses(), (hits + 1, misses + 1));
+
+    store.clear()?;
+    assert_eq!(store.get("key999".to_owned())?, None);
+    Ok(())
+}
+
+// The value size histogram should follow sets, overwrites and removes
+#[test]
+fn value_size_histogram() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        value_size_histogram: true,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("a".to_owned(), "".to_owned())?;
+    store.set("b".to_owned(), "x".to_owned())?;
+    store.set("c".to_owned(), "xxxx".to_owned())?;
+    store.set("d".to_owned(), "x".repeat(100))?;
+    assert_eq!(
+        store.stats().value_sizes,
+        Some(vec![1, 1, 0, 1, 0, 0, 0, 1])
+    );
+
+    store.set("d".to_owned(), "xx".to_owned())?;
+    store.rm("a".to_owned())?;
+    assert_eq!(store.stats().value_sizes, Some(vec![0, 1, 1, 1]));
+
+    // Rebuilt from the log on open, and only when asked for
+    drop(store);
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().value_sizes, Some(vec![0, 1, 1, 1]));
+    drop(store);
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.stats().value_sizes, None);
+
+    Ok(())
+}
+
+// Values are read back through memory maps, also across compactions
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        mmap_reads: true,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    // Everything written before this open is sealed and mapped
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    // Enough overwrites to compact a few times
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        assert_eq!(store.get("key500".to_owned())?, Some(format!("{}", iter)));
+    }
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Opening should fail early when the disk has less free space than required
+#[test]
+fn open_checks_free_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |min_free_space| BitcaskOptions {
+        min_free_space: Some(min_free_space),
+        ..BitcaskOptions::default()
+    };
+
+    assert!(matches!(
+        Bitcask::open_with_options(temp_dir.path(), options(u64::MAX)),
+        Err(KvsError::StringError(_))
+    ));
+    let store = Bitcask::open_with_options(temp_dir.path(), options(1))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        }));
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data, once no handle is left
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(store);
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Racing writes of the same keys leave the index pointing to the last one in the log
+#[test]
+fn concurrent_set_same_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        checksums: true,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    store.set(
+                        format!("key{}", i % 10),
+                        format!("value{}-{}", thread_id, i),
+                    )?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let values = (0..10)
+        .map(|i| store.get(format!("key{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(values.iter().all(Option::is_some));
+
+    drop(store);
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, value);
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = Bitcask::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    let swap = |expected: Option<&str>, new: Option<&str>| {
+        store.compare_and_swap(
+            "key1".to_owned(),
+            expected.map(str::to_owned),
+            new.map(str::to_owned),
+        )
+    };
+
+    // create only if absent
+    assert!(swap(None, Some("value1"))?);
+    assert!(!swap(None, Some("value2"))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // replace only the expected value
+    assert!(!swap(Some("value2"), Some("value3"))?);
+    assert!(swap(Some("value1"), Some("value2"))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // remove only the expected value
+    assert!(!swap(Some("value1"), None)?);
+    assert!(swap(Some("value2"), None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(swap(None, None)?);
+    Ok(())
+}
+
+// Every sync policy should keep the data readable, including across compactions
+#[test]
+fn sync_policies() -> Result<()> {
+    for sync in [
+        SyncPolicy::None,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::EveryN(3),
+        SyncPolicy::Interval(Duration::from_millis(1)),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            sync,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.write_batch(vec![BatchOp::Rm {
+            key: "key0".to_owned(),
+        }])?;
+        store.compact()?;
+        store.set("key1".to_owned(), "value".to_owned())?;
+        drop(store);
+
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None, "{:?}", sync);
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("value".to_owned()),
+            "{:?}",
+            sync
+        );
+        assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    }
+    Ok(())
+}
+
+// Dropping a store should sync the writes its sync policy left pending
+#[test]
+fn drop_syncs_pending_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        sync: SyncPolicy::EveryN(100),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rm("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A read-only store should read what the writer wrote without touching the directory
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rm("key2".to_owned())?;
+
+    let files = || -> Result<Vec<_>> {
+        let mut files = fs::read_dir(temp_dir.path())?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        files.sort();
+        Ok(files)
+    };
+    let before = files()?;
+    let read_only = Bitcask::open_read_only(temp_dir.path())?;
+    assert_eq!(files()?, before);
+
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(read_only.get("key2".to_owned())?, None);
+    assert!(matches!(
+        read_only.set("key1".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        read_only.rm("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(read_only.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(files()?, before);
+
+    // a missing directory is not created
+    let missing = temp_dir.path().join("missing");
+    assert!(Bitcask::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Should list live keys, and allow writing while going through them
+#[test]
+fn keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.rm("key0".to_owned())?;
+    store.set_with_ttl("key1".to_owned(), "value".to_owned(), Duration::ZERO)?;
+    store.set_bytes(vec![0xff], b"value".to_vec())?;
+
+    let mut keys = store.keys();
+    for key in &keys {
+        store.rm(key.clone())?;
+    }
+    keys.sort();
+    let mut expected: Vec<_> = (2..100).map(|i| format!("key{}", i)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+    assert!(store.keys().is_empty());
+    Ok(())
+}
+
+// Oversized keys and values should be rejected without writing anything
+#[test]
+fn max_key_and_value_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        max_key_bytes: Some(8),
+        max_value_bytes: Some(16),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "v".repeat(16))?;
+    let size = dir_size(temp_dir.path());
+
+    assert!(matches!(
+        store.set("key".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLarge {
+            size: 17,
+            limit: 16
+        })
+    ));
+    assert!(matches!(
+        store.set_bytes(b"key".to_vec(), vec![0xff; 17]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvsError::KeyTooLarge { size: 9, limit: 8 })
+    ));
+    assert!(matches!(
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            BatchOp::Set {
+                key: "key2".to_owned(),
+                value: "v".repeat(17),
+            },
+        ]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+
+    assert_eq!(dir_size(temp_dir.path()), size);
+    assert_eq!(store.get("key".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should count the keys of the index
+#[test]
+fn len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.len()?, 2);
+    assert!(!store.is_empty()?);
+
+    store.rm("key1".to_owned())?;
+    store.rm("key2".to_owned())?;
+    assert!(store.is_empty()?);
+    Ok(())
+}
+
+// Should drop all keys, also when the store is opened again
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    store.clear()?;
+    assert!(store.is_empty()?);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 1);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.keys().iter().all(|key| key == "key1"));
+    Ok(())
+}
+
+// Should only call the default when the key is absent
+#[test]
+fn get_or_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(
+        store.get_or_set("key1".to_owned(), || unreachable!())?,
+        "value1"
+    );
+    assert_eq!(
+        store.get_or_set("key2".to_owned(), || "value2".to_owned())?,
+        "value2"
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // concurrent callers all see the value of the first one
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let calls = Arc::clone(&calls);
+            thread::spawn(move || {
+                store.get_or_set("key3".to_owned(), || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    format!("value{}", i)
+                })
+            })
+        })
+        .collect();
+    let values = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(values.iter().all(|value| *value == values[0]));
+    drop(store);
+
+    let store = Bitcask::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some(values[0].clone()));
+    Ok(())
+}
+
+// Should return the values in the order of the keys, across log files
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in (0..100).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    store.rm("key50".to_owned())?;
+
+    let keys: Vec<_> = (0..120).rev().map(|i| format!("key{}", i)).collect();
+    let values = store.get_many(keys.clone())?;
+    assert_eq!(values.len(), keys.len());
+    for (key, value) in keys.into_iter().zip(values) {
+        assert_eq!(value, store.get(key)?);
+    }
+    assert_eq!(store.get_many(Vec::new())?, Vec::<Option<String>>::new());
+    Ok(())
+}
+
+// Should copy the store as it was, leaving out later writes
+#[test]
+fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.rm("key0".to_owned())?;
+
+    store.backup(backup_dir.path())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.set("key100".to_owned(), "new".to_owned())?;
+    store.compact()?;
+
+    let copy = Bitcask::open(backup_dir.path())?;
+    assert_eq!(copy.len()?, 99);
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    assert_eq!(copy.get("key1".to_owned())?, Some("value901".to_owned()));
+    assert_eq!(copy.get("key100".to_owned())?, None);
+    assert_eq!(copy.get("key99".to_owned())?, Some("value999".to_owned()));
+    drop(copy);
+
+    // a backup is not merged into another one
+    assert!(store.backup(backup_dir.path()).is_err());
+    Ok(())
+}
+
+// Should move all pairs to a store of another format
+#[test]
+fn export_and_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Bitcask::open(temp_dir.path().join("src"))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.rm("key0".to_owned())?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::ZERO)?;
+    store.set_bytes(vec![0xff], vec![0xfe, 0])?;
+
+    let mut dump = Vec::new();
+    store.export(&mut dump)?;
+
+    let options = BitcaskOptions {
+        encoding: Encoding::Bincode,
+        checksums: true,
+        ..BitcaskOptions::default()
+    };
+    let copy = Bitcask::open_with_options(temp_dir.path().join("dest"), options)?;
+    copy.import(&dump[..])?;
+    assert_eq!(copy.len()?, 99);
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    assert_eq!(copy.get("key1".to_owned())?, None);
+    assert_eq!(copy.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(copy.get_bytes(&[0xff])?, Some(vec![0xfe, 0]));
+    let mut keys = copy.keys();
+    keys.sort();
+    let mut expected = store.keys();
+    expected.sort();
+    assert_eq!(keys, expected);
+
+    // a truncated dump fails
+    let copy = Bitcask::open(temp_dir.path().join("truncated"))?;
+    assert!(copy.import(&dump[..dump.len() - 1]).is_err());
+    Ok(())
+}
+
+// Compaction should spread the live data over files of bounded size
+#[test]
+fn compaction_max_file_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        max_file_bytes: Some(4096),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i % 200), format!("{:0100}", i))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+
+    let logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    // 200 records of over 100 bytes, plus the active log file
+    assert!(logs.len() > 6);
+    for log in logs {
+        assert!(log.metadata()?.len() <= 4096);
+    }
+
+    let check = |store: &Bitcask| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+        for i in 1..200 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("{:0100}", 800 + i))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&Bitcask::open_with_options(temp_dir.path(), options)?)
+}
+
+// Writes should roll to a new log file before the active one grows past the limit
+#[test]
+fn active_log_max_file_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: u64::MAX,
+        max_file_bytes: Some(1024),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("{:0100}", i))?;
+    }
+    // a record larger than the limit gets a file of its own
+    store.set("large".to_owned(), "x".repeat(2048))?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+
+    let mut large_logs = 0;
+    let logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    assert!(logs.len() > 10);
+    for log in logs {
+        if log.metadata()?.len() > 1024 {
+            large_logs += 1;
+        }
+    }
+    assert_eq!(large_logs, 1);
+
+    let check = |store: &Bitcask| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0100}", i)));
+        }
+        assert_eq!(store.get("large".to_owned())?, Some("x".repeat(2048)));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&Bitcask::open_with_options(temp_dir.path(), options)?)
+}
+
+// Reads should keep at most `max_open_files` log files open, reopening others on demand
+#[test]
+fn max_open_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: u64::MAX,
+        max_file_bytes: Some(256),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("{:0100}", i))?;
+    }
+    drop(store);
+
+    let options = BitcaskOptions {
+        max_open_files: Some(4),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    assert!(store.stats().num_log_files > 40);
+    for _ in 0..2 {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0100}", i)));
+        }
+    }
+
+    // the readers, the active log file and the LOCK file
+    #[cfg(target_os = "linux")]
+    {
+        let open_files = fs::read_dir("/proc/self/fd")?
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|path| path.starts_with(temp_dir.path()))
+            .count();
+        assert!(open_files <= 6, "{} files open", open_files);
+    }
+    Ok(())
+}
+
+// Writes made while a compaction runs in the background win over its copies
+#[test]
+fn background_compaction_keeps_newer_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: 4 * 1024,
+        max_file_bytes: Some(1024),
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    let mut expected = HashMap::new();
+    let check = |store: &Bitcask, expected: &HashMap<String, String>| -> Result<()> {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key.clone())?.as_ref(), expected.get(&key));
+        }
+        Ok(())
+    };
+
+    for iter in 0..200 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id);
+            if (iter + key_id) % 7 == 0 {
+                if expected.remove(&key).is_some() {
+                    store.rm(key)?;
+                }
+            } else {
+                let value = format!("{:050}", iter);
+                store.set(key.clone(), value.clone())?;
+                expected.insert(key, value);
+            }
+        }
+        check(&store, &expected)?;
+    }
+    drop(store);
+
+    check(
+        &Bitcask::open_with_options(temp_dir.path(), options)?,
+        &expected,
+    )
+}
+
+// Writes racing with compactions on other threads are neither lost nor undone
+#[test]
+fn concurrent_compaction_keeps_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        compaction_threshold: 4 * 1024,
+        ..BitcaskOptions::default()
+    };
+    let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+    let done = Arc::new(AtomicUsize::new(0));
+    let compactor = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> Result<()> {
+            while done.load(Ordering::SeqCst) == 0 {
+                store.compact()?;
+            }
+            Ok(())
+        })
+    };
+
+    let mut expected = HashMap::new();
+    for iter in 0..300 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id);
+            if (iter + key_id) % 5 == 0 {
+                if expected.remove(&key).is_some() {
+                    store.rm(key.clone())?;
+                }
+            } else {
+                let value = format!("{:050}", iter);
+                store.set(key.clone(), value.clone())?;
+                expected.insert(key.clone(), value);
+            }
+            assert_eq!(store.get(key.clone())?.as_ref(), expected.get(&key));
+        }
+    }
+    done.store(1, Ordering::SeqCst);
+    compactor.join().unwrap()?;
+    drop(store);
+
+    let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.clone())?.as_ref(), expected.get(&key));
+    }
     Ok(())
 }
@@ -0,0 +1,1166 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rskv::{
+    thread_pool::*, BatchOp, Bitcask, Command, ErrorCode, KvsClient, KvsEngine, KvsError,
+    KvsServer, KvsStore, MalformedRequests, Protocol, ReconnectingClient, Response, Result,
+    ServerOptions, SharedEngine, SledKvsEngine, ValueEncoding, PROTOCOL_VERSION,
+};
+use tempfile::TempDir;
+
+/// An in-memory engine which cannot be cloned, so it goes on the server in a
+/// `SharedEngine`.
+#[derive(Default)]
+struct MemoryKvsEngine {
+    map: Mutex<HashMap<String, String>>,
+}
+
+impl KvsStore for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.map.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.lock().unwrap().get(&key).cloned())
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.map.lock().unwrap().contains_key(&key))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.map.lock().unwrap().len())
+    }
+
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.map.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut map = self.map.lock().unwrap();
+        if map.get(&key) != expected.as_ref() {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+        Ok(true)
+    }
+
+    fn rm(&self, key: String) -> Result<()> {
+        self.map
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: String,
+        mut f: impl FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        let mut pairs: Vec<_> = self
+            .map
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort();
+        pairs.into_iter().try_for_each(|(key, value)| f(key, value))
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+        let mut new_map = map.clone();
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    new_map.insert(key, value);
+                }
+                BatchOp::Rm { key } => {
+                    new_map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+                }
+            }
+        }
+        *map = new_map;
+        Ok(())
+    }
+}
+
+/// Run a server on a free local port in the background and return its address.
+fn spawn_server<E: KvsEngine>(engine: E) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("unable to find a free port");
+    thread::spawn(move || {
+        let server = KvsServer::new(engine, NaiveThreadPool::new(2)?);
+        server.run(addr)
+    });
+    addr
+}
+
+/// Connect to `addr`, waiting for the server to come up.
+fn connect(addr: SocketAddr) -> KvsClient {
+    for _ in 0..100 {
+        if let Ok(client) = KvsClient::connect(addr) {
+            return client;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("unable to connect to {}", addr);
+}
+
+#[test]
+fn arc_engine_is_shared_between_connections() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = spawn_server(Arc::new(SledKvsEngine::new(sled::open(temp_dir.path())?)));
+
+    connect(addr).set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        connect(addr).get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn shared_engine_is_shared_between_connections() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+
+    connect(addr).set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        connect(addr).get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    connect(addr).remove("key1".to_owned())?;
+    assert_eq!(connect(addr).get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn batch_returns_responses_in_order() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    let mut client = connect(addr);
+
+    let mut batch = client.batch();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .get("key1".to_owned())
+        .remove("key2".to_owned())
+        .set("key1".to_owned(), "value2".to_owned())
+        .get("key1".to_owned());
+    let responses = batch.execute()?;
+
+    assert_eq!(responses.len(), 5);
+    assert!(matches!(responses[0], Response::Set));
+    assert!(matches!(&responses[1], Response::Get(Some(value)) if value == "value1"));
+    assert!(matches!(responses[2], Response::Err(_)));
+    assert!(matches!(responses[3], Response::Set));
+    assert!(matches!(&responses[4], Response::Get(Some(value)) if value == "value2"));
+
+    // The connection is still usable after the batch
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn batch_pipelines_many_requests() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    let mut client = connect(addr);
+
+    let mut pipeline = client.pipeline();
+    for i in 0..100 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    for i in 0..100 {
+        pipeline.get(format!("key{}", i));
+    }
+    let responses = pipeline.execute()?;
+
+    assert_eq!(responses.len(), 200);
+    assert!(responses[..100]
+        .iter()
+        .all(|resp| matches!(resp, Response::Set)));
+    for (i, resp) in responses[100..].iter().enumerate() {
+        assert!(matches!(resp, Response::Get(Some(value)) if *value == format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_streams_pairs_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(Bitcask::open(temp_dir.path())?);
+    let mut client = connect(addr);
+
+    // more pairs than the server buffers between two flushes
+    let mut batch = client.batch();
+    for i in 0..1000 {
+        batch.set(format!("user:{:04}", i), format!("value{}", i));
+    }
+    batch.set("other".to_owned(), "value".to_owned());
+    assert!(batch
+        .execute()?
+        .iter()
+        .all(|resp| matches!(resp, Response::Set)));
+
+    let pairs = client
+        .scan_prefix("user:".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = (0..1000)
+        .map(|i| (format!("user:{:04}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+    assert_eq!(client.scan_prefix("none".to_owned())?.count(), 0);
+
+    // the rest of a scan dropped early is skipped
+    let mut scan = client.scan_prefix("user:".to_owned())?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("user:0000".to_owned(), "value0".to_owned()))
+    );
+    drop(scan);
+    assert_eq!(client.get("other".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn info_counts_errors_per_command() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    let mut client = connect(addr);
+
+    let info = client.info()?;
+    assert_eq!(info.error_count(Command::Rm, ErrorCode::KeyNotFound), 0);
+
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert!(client.remove("key1".to_owned()).is_err());
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key1".to_owned())?;
+
+    let info = client.info()?;
+    assert_eq!(info.error_count(Command::Rm, ErrorCode::KeyNotFound), 2);
+    assert_eq!(info.error_count(Command::Get, ErrorCode::KeyNotFound), 0);
+    assert_eq!(info.errors.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn ping() -> Result<()> {
+    let engine = SharedEngine::new(MemoryKvsEngine::default());
+    let addr = spawn_server(engine.clone());
+    let mut client = connect(addr);
+    client.ping()?;
+    client.ping()?;
+    assert!(engine.store().map.lock().unwrap().is_empty());
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.ping()?;
+    Ok(())
+}
+
+#[test]
+fn server_counts_requests() -> Result<()> {
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(1)?,
+    );
+    let handle = server.spawn("127.0.0.1:0")?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.get("key2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    client.ping()?;
+
+    let metrics = handle.metrics();
+    for (command, requests, errors) in [
+        (Command::Get, 2, 0),
+        (Command::Set, 1, 0),
+        (Command::Rm, 2, 1),
+        (Command::Transaction, 0, 0),
+    ] {
+        let op = metrics.op(command);
+        assert_eq!(op.command, command);
+        assert_eq!(op.requests, requests);
+        assert_eq!(op.errors, errors);
+        assert_eq!(
+            op.latency.iter().map(|bucket| bucket.count).sum::<u64>(),
+            requests
+        );
+    }
+    drop(client);
+    handle.shutdown()
+}
+
+#[test]
+fn client_gets_structured_errors() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    let mut client = connect(addr);
+    assert!(matches!(
+        client.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    let mut batch = client.batch();
+    batch.remove("key2".to_owned());
+    assert!(matches!(
+        batch.execute()?[..],
+        [Response::Err(KvsError::KeyNotFound)]
+    ));
+
+    // other errors keep their code and message
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Bitcask::open(temp_dir.path())?;
+    let addr = spawn_server(Bitcask::open_read_only(temp_dir.path())?);
+    match connect(addr).set("key1".to_owned(), "value2".to_owned()) {
+        Err(e @ KvsError::Remote { .. }) => {
+            assert_eq!(e.code(), ErrorCode::Other);
+            assert_eq!(e.to_string(), KvsError::ReadOnly.to_string());
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+    Ok(())
+}
+
+#[test]
+fn transaction_is_all_or_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(Bitcask::open(temp_dir.path())?);
+    let mut client = connect(addr);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let responses = client.transaction(vec![
+        BatchOp::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        BatchOp::Rm {
+            key: "key1".to_owned(),
+        },
+    ])?;
+    assert!(matches!(responses[..], [Response::Set, Response::Remove]));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The results are sent by the server, one per command
+    let reply = send_raw(
+        addr,
+        r#"{"Transaction":{"commands":[{"Set":{"key":"key4","value":"value4"}},{"Rm":{"key":"key4"}}]}}"#,
+    )?;
+    assert_eq!(reply, r#"{"Ok":["Set","Rm"]}"#);
+
+    // The failing removal rolls back the set before it
+    assert!(client
+        .transaction(vec![
+            BatchOp::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+            },
+            BatchOp::Rm {
+                key: "key1".to_owned(),
+            },
+        ])
+        .is_err());
+    assert_eq!(client.get("key3".to_owned())?, None);
+
+    let info = client.info()?;
+    assert_eq!(
+        info.error_count(Command::Transaction, ErrorCode::KeyNotFound),
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn server_exports_prometheus_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(Bitcask::open(temp_dir.path())?, DropJoinThreadPool::new(1)?)
+        .with_metrics_addr("127.0.0.1:0")?;
+    let metrics_addr = server.metrics_addr().unwrap();
+    let handle = server.spawn("127.0.0.1:0")?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    let scrape = |path: &str| -> Result<String> {
+        let mut stream = TcpStream::connect(metrics_addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    };
+    let page = scrape("/metrics")?;
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "{}", page);
+    for line in [
+        "# TYPE kvs_requests_total counter",
+        "kvs_requests_total{command=\"set\"} 2",
+        "kvs_requests_total{command=\"rm\"} 1",
+        "kvs_request_errors_total{command=\"rm\",code=\"KeyNotFound\"} 1",
+        "kvs_active_connections 1",
+        "kvs_keys 1",
+    ] {
+        assert!(
+            page.lines().any(|l| l == line),
+            "{} missing in {}",
+            line,
+            page
+        );
+    }
+    assert!(page
+        .lines()
+        .any(|l| l.starts_with("kvs_uncompacted_bytes ")));
+    assert!(scrape("/other")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    drop(client);
+    handle.shutdown()?;
+    assert!(TcpStream::connect(metrics_addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn spawned_server_shuts_down() -> Result<()> {
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(2)?,
+    );
+    let handle = server.spawn("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let addr = handle.local_addr();
+    handle.shutdown()?;
+    assert!(KvsClient::connect(addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn server_accepts_on_several_threads() -> Result<()> {
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(4)?,
+    )
+    .acceptors(4);
+    let handle = server.spawn("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    thread::scope(|s| {
+        let clients: Vec<_> = (0..8)
+            .map(|i| {
+                s.spawn(move || -> Result<()> {
+                    for j in 0..10 {
+                        let mut client = KvsClient::connect(addr)?;
+                        let key = format!("key{}-{}", i, j);
+                        client.set(key.clone(), format!("value{}", j))?;
+                        assert_eq!(client.get(key)?, Some(format!("value{}", j)));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        clients
+            .into_iter()
+            .try_for_each(|client| client.join().unwrap())
+    })?;
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key7-9".to_owned())?, Some("value9".to_owned()));
+    drop(client);
+
+    handle.shutdown()?;
+    assert!(KvsClient::connect(addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn server_stops_on_shutdown_signal() -> Result<()> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let (shutdown, signal) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let server = KvsServer::new(
+            SharedEngine::new(MemoryKvsEngine::default()),
+            DropJoinThreadPool::new(2)?,
+        );
+        server.run_with_shutdown(addr, signal)
+    });
+
+    let mut client = connect(addr);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    shutdown.send(()).unwrap();
+    server.join().unwrap()?;
+    assert!(KvsClient::connect(addr).is_err());
+    Ok(())
+}
+
+// Draining refuses new clients, but serves the open connections until they close
+#[test]
+fn drained_server_finishes_open_connections() -> Result<()> {
+    // a pool which does not join its workers, so only draining waits for them
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        NaiveThreadPool::new(2)?,
+    );
+    let handle = server.spawn("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+    let drainer = handle.drainer();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (done, drained) = mpsc::channel();
+    thread::spawn(move || done.send(handle.drain()).unwrap());
+    for _ in 0..100 {
+        if KvsClient::connect(addr).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(KvsClient::connect(addr).is_err());
+    assert!(drainer.is_draining());
+
+    // the open connection is still served, and holds up the drain
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!drainer.wait_idle(Some(Duration::from_millis(50))));
+    assert!(drained.recv_timeout(Duration::from_millis(50)).is_err());
+
+    drop(client);
+    drained.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    assert!(drainer.wait_idle(Some(Duration::ZERO)));
+    assert_eq!(drainer.active_connections(), 0);
+    Ok(())
+}
+
+#[test]
+fn connections_over_the_limit_wait_for_a_free_slot() -> Result<()> {
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(4)?,
+    )
+    .max_connections(1);
+    let handle = server.spawn("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(first.info()?.active_connections, 1);
+
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let mut second = KvsClient::connect(addr)?;
+        let value = second.get("key1".to_owned())?;
+        done.send(value).unwrap();
+        Ok::<_, rskv::KvsError>(())
+    });
+    assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(handle.active_connections(), 1);
+
+    drop(first);
+    assert_eq!(
+        finished.recv_timeout(Duration::from_secs(5)).unwrap(),
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn idle_connections_time_out() -> Result<()> {
+    let options = ServerOptions {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(1)?,
+        options,
+    );
+    let handle = server.spawn("127.0.0.1:0")?;
+
+    // connect and never send a request
+    let mut idle = TcpStream::connect(handle.local_addr())?;
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(idle.read(&mut [0; 16])?, 0);
+
+    // the only worker is free again
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn connect_with_retry_waits_for_the_server() -> Result<()> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    // nobody listens, so every attempt fails and the waits add up
+    let start = Instant::now();
+    let res = KvsClient::connect_with_retry(addr, 3, Duration::from_millis(20));
+    assert!(matches!(res, Err(KvsError::Io(_))), "{:?}", res.err());
+    assert!(start.elapsed() >= Duration::from_millis(30));
+
+    let server = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let server = KvsServer::new(
+            SharedEngine::new(MemoryKvsEngine::default()),
+            NaiveThreadPool::new(1)?,
+        );
+        server.spawn(addr)
+    });
+    let mut client = KvsClient::connect_with_retry(addr, 10, Duration::from_millis(20))?;
+    client.ping()?;
+    server.join().unwrap()?.shutdown()
+}
+
+#[test]
+fn reconnecting_client_survives_dropped_connections() -> Result<()> {
+    let options = ServerOptions {
+        read_timeout: Some(Duration::from_millis(50)),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        NaiveThreadPool::new(2)?,
+        options,
+    );
+    let handle = server.spawn("127.0.0.1:0")?;
+    let mut client =
+        ReconnectingClient::connect(handle.local_addr())?.backoff(Duration::from_millis(10));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // let the server close the idle connection
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // errors from the server are returned as is
+    thread::sleep(Duration::from_millis(200));
+    assert!(matches!(
+        client.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn socket_options_are_applied() -> Result<()> {
+    for (nodelay, keepalive) in [(true, Some(Duration::from_secs(60))), (false, None)] {
+        let options = ServerOptions {
+            nodelay,
+            keepalive,
+            ..ServerOptions::default()
+        };
+        let server = KvsServer::with_options(
+            SharedEngine::new(MemoryKvsEngine::default()),
+            DropJoinThreadPool::new(1)?,
+            options,
+        );
+        let handle = server.spawn("127.0.0.1:0")?;
+        let mut client = KvsClient::connect(handle.local_addr())?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(client);
+        handle.shutdown()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn server_requires_auth_token() -> Result<()> {
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(2)?,
+    )
+    .auth_token("secret".to_owned());
+    let handle = server.spawn("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+    drop(client);
+    assert!(matches!(
+        KvsClient::connect_with_auth(addr, "guess".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+
+    let mut client = KvsClient::connect_with_auth(addr, "secret".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    handle.shutdown()
+}
+
+#[test]
+fn resp_protocol_requires_auth_token() -> Result<()> {
+    let options = ServerOptions {
+        protocol: Protocol::Resp,
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(2)?,
+        options,
+    )
+    .auth_token("secret".to_owned());
+    let handle = server.spawn("127.0.0.1:0")?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    stream.write_all(
+        b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+          *2\r\n$4\r\nAUTH\r\n$5\r\nguess\r\n\
+          *2\r\n$4\r\nauth\r\n$6\r\nsecret\r\n\
+          *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+    )?;
+    let expected: &[u8] = b"-NOAUTH Authentication required.\r\n\
+        -WRONGPASS invalid username-password pair\r\n\
+        +OK\r\n\
+        $-1\r\n";
+    let mut replies = vec![0; expected.len()];
+    stream.read_exact(&mut replies)?;
+    assert_eq!(
+        String::from_utf8_lossy(&replies),
+        String::from_utf8_lossy(expected)
+    );
+    Ok(())
+}
+
+#[test]
+fn resp_protocol_serves_redis_commands() -> Result<()> {
+    let options = ServerOptions {
+        protocol: Protocol::Resp,
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(2)?,
+        options,
+    );
+    let handle = server.spawn("127.0.0.1:0")?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    stream.write_all(
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\
+          *2\r\n$3\r\nget\r\n$4\r\nkey1\r\n\
+          *2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n\
+          *3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n\
+          *1\r\n$3\r\nGET\r\n\
+          *1\r\n$4\r\nPING\r\n",
+    )?;
+    let expected: &[u8] = b"+OK\r\n\
+        $6\r\nvalue1\r\n\
+        $-1\r\n\
+        :1\r\n\
+        -ERR wrong number of arguments for 'get' command\r\n\
+        -ERR unknown command 'PING'\r\n";
+    let mut replies = vec![0; expected.len()];
+    stream.read_exact(&mut replies)?;
+    assert_eq!(
+        String::from_utf8_lossy(&replies),
+        String::from_utf8_lossy(expected)
+    );
+
+    // a malformed command gets an error and the connection is closed
+    stream.write_all(b"GET key1\r\n")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert!(reply.starts_with("-ERR Protocol error"), "{}", reply);
+    Ok(())
+}
+
+#[test]
+fn server_refuses_other_protocol_versions() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    connect(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"version":999,"crate_version":"9.9.9"}"#)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert!(
+        reply.starts_with(&format!(
+            r#"{{"VersionMismatch":{{"version":{}"#,
+            PROTOCOL_VERSION
+        )),
+        "{}",
+        reply
+    );
+    Ok(())
+}
+
+#[test]
+fn client_reports_version_mismatch() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept()?;
+        stream.write_all(br#"{"VersionMismatch":{"version":999,"crate_version":"9.9.9"}}"#)?;
+        Ok::<_, std::io::Error>(())
+    });
+
+    match KvsClient::connect(addr) {
+        Err(KvsError::VersionMismatch {
+            client,
+            server,
+            server_crate_version,
+        }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, 999);
+            assert_eq!(server_crate_version, "9.9.9");
+        }
+        res => panic!("expected a version mismatch, got {:?}", res.err()),
+    }
+    Ok(())
+}
+
+#[test]
+fn base64_values_hold_any_bytes() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    connect(addr);
+    let mut client = KvsClient::connect_with_encoding(addr, ValueEncoding::Base64)?;
+
+    let blob = vec![0, 159, 146, 150, 255, b'\n'];
+    client.set_bytes("blob".to_owned(), blob.clone())?;
+    assert_eq!(client.get_bytes("blob".to_owned())?, Some(blob));
+    assert!(matches!(
+        client.get("blob".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    client.set("text".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("text".to_owned())?, Some("value1".to_owned()));
+    let responses = client.transaction(vec![BatchOp::Set {
+        key: "text".to_owned(),
+        value: "value2".to_owned(),
+    }])?;
+    assert!(matches!(responses[..], [Response::Set]));
+    let mut batch = client.batch();
+    batch.get("text".to_owned()).get("missing".to_owned());
+    let responses = batch.execute()?;
+    assert!(matches!(&responses[0], Response::Get(Some(value)) if value == "value2"));
+    assert!(matches!(responses[1], Response::Get(None)));
+
+    // the store holds the encoded values
+    let mut plain = connect(addr);
+    assert_eq!(plain.get("text".to_owned())?, Some("dmFsdWUy".to_owned()));
+    assert!(matches!(
+        plain.set_bytes("blob".to_owned(), vec![255]),
+        Err(KvsError::Utf8(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn server_agrees_on_base64_values() -> Result<()> {
+    let addr = spawn_server(SharedEngine::new(MemoryKvsEngine::default()));
+    connect(addr);
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        r#"{{"version":{},"crate_version":"0.1.0","value_encoding":"Base64"}}"#,
+        PROTOCOL_VERSION
+    )?;
+    stream.write_all(br#"{"Set":{"key":"key1","value":"not base64!"}}"#)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert!(
+        reply.contains(r#""value_encoding":"Base64"}}"#),
+        "{}",
+        reply
+    );
+    assert!(
+        reply.contains(r#"{"Err":{"code":"Other","message":"Invalid base64 value"#),
+        "{}",
+        reply
+    );
+    assert_eq!(connect(addr).get("key1".to_owned())?, None);
+
+    // a server which predates the encodings only speaks UTF-8
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let old_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept()?;
+        write!(
+            stream,
+            r#"{{"Ok":{{"version":{},"crate_version":"0.0.1"}}}}"#,
+            PROTOCOL_VERSION
+        )?;
+        Ok::<_, std::io::Error>(())
+    });
+    assert!(KvsClient::connect_with_encoding(old_addr, ValueEncoding::Base64).is_err());
+    Ok(())
+}
+
+/// Send `requests` on a raw connection to `addr` after the handshake, and return what
+/// the server answered to them.
+fn send_raw(addr: SocketAddr, requests: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        r#"{{"version":{},"crate_version":"0.1.0"}}{}"#,
+        PROTOCOL_VERSION, requests
+    )?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let (_, responses) = reply.split_once(r#""value_encoding":"Utf8"}}"#).unwrap();
+    Ok(responses.to_owned())
+}
+
+#[test]
+fn malformed_requests_policy() -> Result<()> {
+    let spawn = |malformed_requests| {
+        let options = ServerOptions {
+            malformed_requests,
+            ..ServerOptions::default()
+        };
+        KvsServer::with_options(
+            SharedEngine::new(MemoryKvsEngine::default()),
+            NaiveThreadPool::new(2)?,
+            options,
+        )
+        .spawn("127.0.0.1:0")
+    };
+    let set = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let get = r#"{"Get":{"key":"key1"}}"#;
+    let malformed = r#"{"Err":{"code":"Malformed","message":"Malformed request: "#;
+
+    // a bad request is answered and the next ones are served
+    let handle = spawn(MalformedRequests::Reply)?;
+    let reply = send_raw(
+        handle.local_addr(),
+        &format!(
+            r#"{}{{"Frobnicate":{{"key":"key1"}}}}{{"Get":{{}}}}{}"#,
+            set, get
+        ),
+    )?;
+    assert_eq!(reply.matches(malformed).count(), 2, "{}", reply);
+    assert!(reply.starts_with(r#"{"Ok":null}"#), "{}", reply);
+    assert!(reply.ends_with(r#"{"Ok":"value1"}"#), "{}", reply);
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // but a request which is not JSON ends the connection
+    let reply = send_raw(
+        handle.local_addr(),
+        &format!(r#"{{"Get":{{"key":}}}}{}"#, get),
+    )?;
+    assert!(reply.starts_with(malformed), "{}", reply);
+    assert_eq!(reply.matches("Err").count(), 1, "{}", reply);
+    assert!(!reply.contains("value1"), "{}", reply);
+    handle.shutdown()?;
+
+    // by default, the connection is closed without an answer
+    let handle = spawn(MalformedRequests::Close)?;
+    let reply = send_raw(
+        handle.local_addr(),
+        &format!(r#"{}{{"Frobnicate":{{}}}}{}"#, set, get),
+    )?;
+    assert_eq!(reply, r#"{"Ok":null}"#);
+    handle.shutdown()
+}
+
+/// An in-memory connection: the server reads `input` and writes to `output`.
+struct Pipe {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn server_serves_in_memory_streams() -> Result<()> {
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        NaiveThreadPool::new(1)?,
+    );
+    let mut pipe = Pipe {
+        input: Cursor::new(
+            format!(
+                r#"{{"version":{},"crate_version":"0.0.0"}}
+                {{"Set":{{"key":"key1","value":"value1"}}}}
+                {{"Get":{{"key":"key1"}}}}
+                {{"Rm":{{"key":"key2"}}}}
+                "Ping""#,
+                PROTOCOL_VERSION
+            )
+            .into_bytes(),
+        ),
+        output: Vec::new(),
+    };
+    server.serve_connection(&mut pipe, Some("pipe".to_owned()))?;
+
+    let replies = serde_json::Deserializer::from_slice(&pipe.output)
+        .into_iter::<serde_json::Value>()
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    assert_eq!(replies.len(), 5);
+    assert!(replies[0].get("Ok").is_some(), "{}", replies[0]);
+    assert_eq!(replies[1], serde_json::json!({ "Ok": null }));
+    assert_eq!(replies[2], serde_json::json!({ "Ok": "value1" }));
+    assert_eq!(replies[3]["Err"]["code"], "KeyNotFound");
+    assert_eq!(replies[4], "Pong");
+    assert_eq!(server.metrics().op(Command::Set).requests, 1);
+    Ok(())
+}
+
+/// A writer appending to a shared buffer, to capture what a subscriber prints.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(feature = "tracing")]
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn server_traces_requests() -> Result<()> {
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    let captured = Captured::default();
+    let subscriber = {
+        let captured = captured.clone();
+        tracing_subscriber::fmt()
+            .with_writer(move || captured.clone())
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .finish()
+    };
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        NaiveThreadPool::new(1)?,
+    );
+    let mut pipe = Pipe {
+        input: Cursor::new(
+            format!(
+                r#"{{"version":{},"crate_version":"0.0.0"}}
+                {{"Set":{{"key":"key1","value":"value1"}}}}
+                {{"Rm":{{"key":"key22"}}}}"#,
+                PROTOCOL_VERSION
+            )
+            .into_bytes(),
+        ),
+        output: Vec::new(),
+    };
+    tracing::subscriber::with_default(subscriber, || {
+        server.serve_connection(&mut pipe, Some("pipe".to_owned()))
+    })?;
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", output);
+    for line in &lines {
+        assert!(
+            line.contains(r#"connection{peer.id=1 peer.label="pipe" protocol=Json}"#),
+            "{}",
+            line
+        );
+        assert!(line.contains("close"), "{}", line);
+    }
+    assert!(
+        lines[0].contains(r#"request{op="set" key_len=4 status="ok"}"#),
+        "{}",
+        lines[0]
+    );
+    assert!(
+        lines[1].contains(r#"request{op="rm" key_len=5 status="KeyNotFound"}"#),
+        "{}",
+        lines[1]
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let (tx, rx) = mpsc::channel();
+    let server = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        DropJoinThreadPool::new(2)?,
+    );
+    let thread = {
+        let path = path.clone();
+        thread::spawn(move || server.run_unix_with_shutdown(path, rx))
+    };
+
+    let mut client = (0..100)
+        .find_map(|_| {
+            KvsClient::connect_unix(&path)
+                .map_err(|_| thread::sleep(Duration::from_millis(10)))
+                .ok()
+        })
+        .expect("unable to connect to the server");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the socket is taken by the running server
+    let other = KvsServer::new(
+        SharedEngine::new(MemoryKvsEngine::default()),
+        NaiveThreadPool::new(1)?,
+    );
+    match other.run_unix(&path) {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse),
+        res => panic!("expected the socket to be in use, got {:?}", res),
+    }
+
+    drop(client);
+    tx.send(()).unwrap();
+    thread.join().unwrap()?;
+    assert!(!path.exists());
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use rskv::{BatchOp, KvsEngine, KvsError, Result, SledKvsEngine};
+use sled::transaction::{ConflictableTransactionError, UnabortableTransactionError};
+use tempfile::TempDir;
+
+fn open(temp_dir: &TempDir) -> Result<SledKvsEngine> {
+    Ok(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Move `amount` from one balance to another, aborting on insufficient funds
+fn transfer(store: &SledKvsEngine, from: &str, to: &str, amount: u64) -> Result<()> {
+    store.transaction(|tx| {
+        let balance = |key: &str| -> std::result::Result<u64, UnabortableTransactionError> {
+            let value = tx.get(key)?.unwrap_or_default();
+            Ok(String::from_utf8_lossy(&value).parse().unwrap_or(0))
+        };
+        let (from_balance, to_balance) = (balance(from)?, balance(to)?);
+        if from_balance < amount {
+            return Err(ConflictableTransactionError::Abort(KvsError::StringError(
+                "insufficient balance".to_owned(),
+            )));
+        }
+        tx.insert(from, (from_balance - amount).to_string().as_bytes())?;
+        tx.insert(to, (to_balance + amount).to_string().as_bytes())?;
+        Ok(())
+    })
+}
+
+#[test]
+fn transaction_transfer_balance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    store.set("alice".to_owned(), "100".to_owned())?;
+    store.set("bob".to_owned(), "20".to_owned())?;
+
+    transfer(&store, "alice", "bob", 30)?;
+    assert_eq!(store.get("alice".to_owned())?, Some("70".to_owned()));
+    assert_eq!(store.get("bob".to_owned())?, Some("50".to_owned()));
+
+    // An aborted transaction leaves both balances untouched
+    assert!(matches!(
+        transfer(&store, "bob", "alice", 80),
+        Err(KvsError::StringError(_))
+    ));
+    assert_eq!(store.get("alice".to_owned())?, Some("70".to_owned()));
+    assert_eq!(store.get("bob".to_owned())?, Some("50".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_rolls_back_on_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+
+    assert!(matches!(
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            BatchOp::Rm {
+                key: "key2".to_owned(),
+            },
+        ]),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    store.write_batch(vec![
+        BatchOp::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        BatchOp::Rm {
+            key: "key1".to_owned(),
+        },
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    let swap = |expected: Option<&str>, new: Option<&str>| {
+        store.compare_and_swap(
+            "key1".to_owned(),
+            expected.map(str::to_owned),
+            new.map(str::to_owned),
+        )
+    };
+
+    // create only if absent
+    assert!(swap(None, Some("value1"))?);
+    assert!(!swap(None, Some("value2"))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // replace only the expected value
+    assert!(!swap(Some("value2"), Some("value3"))?);
+    assert!(swap(Some("value1"), Some("value2"))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // remove only the expected value
+    assert!(!swap(Some("value1"), None)?);
+    assert!(swap(Some("value2"), None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(swap(None, None)?);
+    Ok(())
+}
+
+// Renaming should move the value, or fail without writing
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    store.rename("key2".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.rename("key1".to_owned(), "key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Overwriting or removing a key should return its previous value
+#[test]
+fn returning_old() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+
+    assert_eq!(
+        store.set_returning_old("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.set_returning_old("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.rm_returning_old("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.rm_returning_old("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    assert!(store.is_empty()?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.len()?, 2);
+    store.rm("key1".to_owned())?;
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+// Should drop all keys
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.clear()?;
+    assert!(store.is_empty()?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should only call the default when the key is absent
+#[test]
+fn get_or_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(
+        store.get_or_set("key1".to_owned(), || unreachable!())?,
+        "value1"
+    );
+    assert_eq!(
+        store.get_or_set("key2".to_owned(), || "value2".to_owned())?,
+        "value2"
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
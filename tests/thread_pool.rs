@@ -1,8 +1,10 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crossbeam_utils::sync::WaitGroup;
-use rskv::{thread_pool::*, Result};
+use rskv::{thread_pool::*, KvsError, Result};
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
@@ -77,6 +79,20 @@ fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<DropJoinThreadPool>()
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)?;
+
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_mutex_counter(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
 #[test]
 fn rayon_thread_pool_spawn_counter() -> Result<()> {
     let pool = RayonThreadPool::new(4)?;
@@ -106,3 +122,150 @@ fn my_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
     spawn_counter(pool)
 }
+
+fn worker_names<P: ThreadPool>() -> Result<()> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(2)
+        .thread_name("worker".to_owned())
+        .thread_stack_size(4 * 1024 * 1024)
+        .build_pool::<P>()?;
+
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move || {
+        tx.send(thread::current().name().map(str::to_owned))
+            .unwrap()
+    });
+    let name = rx.recv().unwrap().expect("worker thread has no name");
+    assert!(name == "worker-0" || name == "worker-1", "{}", name);
+    Ok(())
+}
+
+#[test]
+fn builder_names_worker_threads() -> Result<()> {
+    worker_names::<NaiveThreadPool>()?;
+    worker_names::<DropJoinThreadPool>()?;
+    worker_names::<WorkStealingThreadPool>()?;
+    worker_names::<RayonThreadPool>()
+}
+
+#[test]
+fn builder_rejects_zero_threads() {
+    assert!(ThreadPoolBuilder::new().num_threads(0).build().is_err());
+}
+
+#[test]
+fn pools_reject_zero_threads() {
+    fn rejects<P: ThreadPool>() -> bool {
+        matches!(P::new(0), Err(KvsError::StringError(_)))
+    }
+    assert!(rejects::<NaiveThreadPool>());
+    assert!(rejects::<DropJoinThreadPool>());
+    assert!(rejects::<WorkStealingThreadPool>());
+    assert!(rejects::<RayonThreadPool>());
+}
+
+#[test]
+fn drop_join_thread_pool_shutdown_timeout() -> Result<()> {
+    let pool = DropJoinThreadPool::new(2)?;
+    pool.shutdown_timeout(Duration::from_secs(1))?;
+
+    let pool = DropJoinThreadPool::new(2)?;
+    let (started, wait_started) = mpsc::channel();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        thread::sleep(Duration::from_secs(2));
+    });
+    wait_started.recv().unwrap();
+
+    let now = Instant::now();
+    let err = pool
+        .shutdown_timeout(Duration::from_millis(100))
+        .unwrap_err();
+    assert!(now.elapsed() < Duration::from_secs(1));
+    assert!(err.to_string().starts_with("1 of 2 workers"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn drop_join_thread_pool_resize() -> Result<()> {
+    let pool = DropJoinThreadPool::new(2)?;
+
+    // four jobs can only meet at the barrier if four workers run them at once
+    pool.resize(4)?;
+    assert_eq!(pool.num_threads(), 4);
+    let barrier = Arc::new(Barrier::new(5));
+    for _ in 0..4 {
+        let barrier = Arc::clone(&barrier);
+        pool.spawn(move || {
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    // jobs spawned before shrinking still run
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    pool.resize(1)?;
+    assert_eq!(pool.num_threads(), 1);
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+
+    assert!(DropJoinThreadPool::new(1)?.resize(0).is_err());
+    Ok(())
+}
+
+/// Wait up to 5 seconds for `cond` to hold.
+fn wait_until(cond: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !cond() {
+        assert!(Instant::now() < deadline, "timed out waiting for condition");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn drop_join_thread_pool_job_counts() -> Result<()> {
+    let pool = DropJoinThreadPool::new(2)?;
+    assert_eq!((pool.queued_jobs(), pool.active_jobs()), (0, 0));
+
+    // five jobs which block until their sender is dropped, the last one then panics
+    let mut releases = Vec::new();
+    for i in 0..5 {
+        let (release, wait) = mpsc::channel::<()>();
+        releases.push(release);
+        pool.spawn(move || {
+            let _ = wait.recv();
+            if i == 4 {
+                panic!("job panics");
+            }
+        });
+    }
+    wait_until(|| pool.active_jobs() == 2);
+    assert_eq!(pool.queued_jobs(), 3);
+
+    drop(releases);
+    wait_until(|| pool.active_jobs() == 0 && pool.queued_jobs() == 0);
+    Ok(())
+}
+
+#[test]
+fn drop_join_thread_pool_counts_panics() -> Result<()> {
+    let pool = DropJoinThreadPool::new(4)?;
+    for _ in 0..10 {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        });
+    }
+    wait_until(|| pool.active_jobs() == 0 && pool.queued_jobs() == 0);
+    assert_eq!(pool.panics_observed(), 10);
+
+    // the workers survived the panics
+    spawn_counter(pool)
+}
@@ -0,0 +1,93 @@
+#![cfg(feature = "tls")]
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use rcgen::CertifiedKey;
+use rskv::{thread_pool::*, Bitcask, KvsClient, KvsError, KvsServer, Result};
+use rustls::{
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tempfile::TempDir;
+
+/// A self-signed certificate for `localhost`.
+fn certificate() -> CertifiedKey {
+    rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+        .expect("unable to generate a certificate")
+}
+
+/// A client configuration trusting only `cert`.
+fn client_config(cert: &CertifiedKey) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Run a TLS server presenting `cert` on a free local port and return its address.
+fn spawn_tls_server(temp_dir: &TempDir, cert: &CertifiedKey) -> Result<SocketAddr> {
+    let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], PrivateKeyDer::Pkcs8(key))?;
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let server = KvsServer::new(Bitcask::open(temp_dir.path())?, NaiveThreadPool::new(2)?);
+    thread::spawn(move || server.run_tls(addr, Arc::new(config)));
+    Ok(addr)
+}
+
+/// Connect to `addr` over TLS, waiting for the server to come up.
+fn connect_tls(addr: SocketAddr, config: Arc<ClientConfig>) -> Result<KvsClient> {
+    for _ in 0..100 {
+        match KvsClient::connect_tls(addr, "localhost", Arc::clone(&config)) {
+            Err(KvsError::Io(_)) => thread::sleep(Duration::from_millis(10)),
+            res => return res,
+        }
+    }
+    panic!("unable to connect to {}", addr);
+}
+
+#[test]
+fn tls_server_serves_tls_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert = certificate();
+    let addr = spawn_tls_server(&temp_dir, &cert)?;
+
+    let mut client = connect_tls(addr, client_config(&cert))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(client.info()?.active_connections >= 1);
+    Ok(())
+}
+
+#[test]
+fn tls_server_refuses_untrusted_and_plain_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert = certificate();
+    let addr = spawn_tls_server(&temp_dir, &cert)?;
+    connect_tls(addr, client_config(&cert))?;
+
+    // the client does not trust the server's certificate
+    let other = certificate();
+    assert!(matches!(
+        KvsClient::connect_tls(addr, "localhost", client_config(&other)),
+        Err(KvsError::Io(_) | KvsError::Serde(_))
+    ));
+    // the certificate is not for this name
+    assert!(KvsClient::connect_tls(addr, "example.com", client_config(&cert)).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+    Ok(())
+}